@@ -0,0 +1,341 @@
+use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::{ConnectInfo, Path, Query, Request};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use log::{info, warn, error};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use subtle::ConstantTimeEq;
+use tracing::Instrument;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use crate::auth::Role;
+use crate::command_handler::{self, db, CommandContext, CommandError};
+use crate::config;
+use crate::device;
+use crate::graphql;
+use crate::json_handler;
+use crate::live;
+use crate::logging;
+use crate::message_schema::{self, Transport};
+use crate::metrics;
+use crate::models::{Aggregate, AggregateParams, CommandResponse, Device, DeviceDetails, DeviceParams, ErrorResponse, GroupBy, IngestAck, IngestBatch, ReadingParams, ReadingsPage};
+use crate::query::{self, ReadingFilter};
+use crate::shutdown;
+use crate::status;
+
+// REST API for dashboards. It offers what TCP and MQTT clients get: ingest, device and
+// reading queries, aggregates, status and every registry command a user may run, plus
+// GraphQL for nested queries over the same data. All routes except /openapi.json need
+// `Authorization: Bearer <http.token>`; /api/live, the WebSocket stream, also takes it as
+// `?token=` since browsers cannot set headers there. The OpenAPI document is generated
+// from the `utoipa::path` annotations and the types in models.rs.
+const DEFAULT_LIMIT: usize = 1000;
+const MAX_LIMIT: usize = 10_000;
+
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn bad_request(message: impl Into<String>) -> Self {
+        ApiError(StatusCode::BAD_REQUEST, message.into())
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        ApiError(StatusCode::NOT_FOUND, message.into())
+    }
+
+    // Neo4j or the WAL is not available
+    fn unavailable(message: impl Into<String>) -> Self {
+        ApiError(StatusCode::SERVICE_UNAVAILABLE, message.into())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(ErrorResponse { error: self.1 })).into_response()
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Datacenter API", description = "Sensor readings ingest and queries", license(name = "Apache-2.0")),
+    paths(get_status, post_readings, get_readings, get_aggregate, get_devices, get_device, post_command, post_graphql, get_graphql_schema, live_stream),
+    components(schemas(GroupBy)),
+    modifiers(&BearerAuth),
+    security(("bearer" = [])),
+)]
+struct ApiDoc;
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("bearer", SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()));
+    }
+}
+
+fn router() -> Router {
+    Router::new()
+        .route("/api/status", get(get_status))
+        .route("/api/readings", get(get_readings).post(post_readings))
+        .route("/api/readings/aggregate", get(get_aggregate))
+        .route("/api/devices", get(get_devices))
+        .route("/api/devices/{id}", get(get_device))
+        .route("/api/commands/{name}", post(post_command))
+        .route("/api/graphql", get(get_graphql_schema).post(post_graphql))
+        .route("/api/live", get(live_stream))
+        .layer(middleware::from_fn(authorize))
+        // Public, so clients can be generated without a token
+        .route("/openapi.json", get(openapi))
+}
+
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+// Serves the REST API until shutdown; without a token it is not started at all
+pub async fn serve() -> Result<(), String> {
+    let http = &config::get().http;
+    if http.token.is_empty() {
+        info!("REST API disabled: http.token (HTTP_TOKEN) is not set");
+        return Ok(());
+    }
+    let listener = tokio::net::TcpListener::bind(&http.bind).await
+        .map_err(|e| format!("Failed to bind REST API on {}: {}", http.bind, e))?;
+    info!("REST API available on http://{}/api", http.bind);
+    axum::serve(listener, router().into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown::token().cancelled())
+        .await
+        .map_err(|e| format!("REST API failed: {}", e))
+}
+
+async fn authorize(ConnectInfo(addr): ConnectInfo<SocketAddr>, request: Request, next: Next) -> Response {
+    let mut token = request.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    if token.is_none() && request.uri().path() == "/api/live" {
+        token = Query::<HashMap<String, String>>::try_from_uri(request.uri()).ok()
+            .and_then(|Query(mut params)| params.remove("token"));
+    }
+    if !token.is_some_and(|token| token_matches(&token, &config::get().http.token)) {
+        warn!("Rejected HTTP request from {} to {}: missing or wrong bearer token", addr, request.uri().path());
+        metrics::AUTH_FAILURES.inc();
+        let mut response = ApiError(StatusCode::UNAUTHORIZED, "Missing or invalid bearer token".to_string()).into_response();
+        response.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+        return response;
+    }
+    next.run(request).await
+}
+
+// Compares digests so the time taken does not depend on how much of the token matched
+fn token_matches(token: &str, expected: &str) -> bool {
+    Sha256::digest(token.as_bytes()).ct_eq(&Sha256::digest(expected.as_bytes())).into()
+}
+
+fn source(addr: &SocketAddr) -> String {
+    format!("http:{}", addr)
+}
+
+async fn graph() -> Result<&'static neo4rs::Graph, ApiError> {
+    db().await.map_err(ApiError::unavailable)
+}
+
+fn split_list(value: Option<String>) -> Vec<String> {
+    value.map(|list| list.split(',').map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+#[utoipa::path(
+    get, path = "/api/status", tag = "status",
+    responses((status = 200, description = "Uptime, version, connections, Neo4j health, ingest counters and queue depths", body = Object)),
+)]
+async fn get_status() -> Json<Value> {
+    Json(status::report().await)
+}
+
+// Accepts `{"data": [...], "message_id": ..}` like the TCP `data` message, or a bare array.
+// Readings go through the WAL, so 202 means stored, not yet written to Neo4j.
+#[utoipa::path(
+    post, path = "/api/readings", tag = "readings",
+    request_body(content = IngestBatch, description = "A batch like the TCP `data` message, or a bare array of readings"),
+    responses(
+        (status = 202, description = "Stored in the write-ahead log", body = IngestAck),
+        (status = 200, description = "Already received within the dedupe window", body = IngestAck),
+        (status = 400, description = "Not a batch of readings; names the failing paths", body = ErrorResponse),
+        (status = 503, description = "The write-ahead log is unavailable", body = IngestAck),
+    ),
+)]
+async fn post_readings(ConnectInfo(addr): ConnectInfo<SocketAddr>, Json(body): Json<Value>) -> Result<(StatusCode, Json<IngestAck>), ApiError> {
+    let mut message = match body {
+        Value::Array(records) => json!({ "data": records }),
+        Value::Object(map) if map.get("data").is_some_and(Value::is_array) => Value::Object(map),
+        _ => return Err(ApiError::bad_request("Expected an array of readings or an object with a 'data' array")),
+    };
+    message["type"] = json!("data");
+    metrics::message_received("http", &message);
+    let errors = message_schema::validate(&message, Transport::Tcp);
+    if !errors.is_empty() {
+        let problems: Vec<String> = errors.iter().map(|e| format!("{}: {}", e["path"].as_str().unwrap_or_default(), e["message"].as_str().unwrap_or_default())).collect();
+        return Err(ApiError::bad_request(problems.join("; ")));
+    }
+
+    let ack = json_handler::handle_data(&message, &source(&addr))
+        .instrument(logging::request_span(&message))
+        .await;
+    let code = match ack.status.as_str() {
+        "accepted" => StatusCode::ACCEPTED,
+        "duplicate" => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    Ok((code, Json(ack)))
+}
+
+fn reading_filter(from: Option<String>, to: Option<String>, device: Option<String>, color: Option<String>, limit: Option<usize>) -> ReadingFilter {
+    ReadingFilter { from, to, devices: split_list(device), colors: split_list(color), limit }
+}
+
+#[utoipa::path(
+    get, path = "/api/readings", tag = "readings",
+    params(ReadingParams),
+    responses(
+        (status = 200, description = "Matching readings, oldest first", body = ReadingsPage),
+        (status = 400, description = "Invalid limit", body = ErrorResponse),
+        (status = 503, description = "Neo4j is unavailable", body = ErrorResponse),
+    ),
+)]
+async fn get_readings(Query(params): Query<ReadingParams>) -> ApiResult<ReadingsPage> {
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    if limit == 0 || limit > MAX_LIMIT {
+        return Err(ApiError::bad_request(format!("'limit' must be between 1 and {}", MAX_LIMIT)));
+    }
+    let filter = reading_filter(params.from, params.to, params.device, params.color, Some(limit));
+    let mut readings = Vec::new();
+    query::for_each_reading(&filter, graph().await?, |reading| {
+        readings.push(reading.to_record());
+        Ok(())
+    }).await.map_err(ApiError::unavailable)?;
+    Ok(Json(ReadingsPage { count: readings.len(), limit, readings }))
+}
+
+#[utoipa::path(
+    get, path = "/api/readings/aggregate", tag = "readings",
+    params(AggregateParams),
+    responses(
+        (status = 200, description = "One aggregate, or one per group", body = Vec<Aggregate>),
+        (status = 503, description = "Neo4j is unavailable", body = ErrorResponse),
+    ),
+)]
+async fn get_aggregate(Query(params): Query<AggregateParams>) -> ApiResult<Vec<Aggregate>> {
+    let filter = reading_filter(params.from, params.to, params.device, params.color, None);
+    query::aggregate_readings(&filter, params.group_by, graph().await?).await
+        .map(Json)
+        .map_err(ApiError::unavailable)
+}
+
+#[utoipa::path(
+    get, path = "/api/devices", tag = "devices",
+    params(DeviceParams),
+    responses(
+        (status = 200, description = "Registered devices ordered by id", body = Vec<Device>),
+        (status = 503, description = "Neo4j is unavailable", body = ErrorResponse),
+    ),
+)]
+async fn get_devices(Query(params): Query<DeviceParams>) -> ApiResult<Vec<Device>> {
+    device::list_devices(&json!(params), None, graph().await?).await
+        .map(Json)
+        .ok_or_else(|| ApiError::unavailable("Failed to list devices"))
+}
+
+#[utoipa::path(
+    get, path = "/api/devices/{id}", tag = "devices",
+    params(("id" = String, Path, description = "Device UUID")),
+    responses(
+        (status = 200, description = "The registered device and its latest reading", body = DeviceDetails),
+        (status = 404, description = "Neither a registered device nor readings", body = ErrorResponse),
+    ),
+)]
+async fn get_device(Path(id): Path<String>) -> ApiResult<DeviceDetails> {
+    let graph = graph().await?;
+    let device = device::get_device(&id, graph).await;
+    let latest_reading = query::get_specific_uuid_node(&id, graph).await;
+    if device.is_none() && latest_reading.is_none() {
+        return Err(ApiError::not_found(format!("No device or readings with id '{}'", id)));
+    }
+    Ok(Json(DeviceDetails { id, device, latest_reading }))
+}
+
+// Runs a registry command with the request body as its arguments. HTTP clients have the
+// user role, so admin-only commands are refused.
+#[utoipa::path(
+    post, path = "/api/commands/{name}", tag = "commands",
+    params(("name" = String, Path, description = "Command name, see the `help` command")),
+    request_body(content = Object, description = "Command arguments"),
+    responses(
+        (status = 200, description = "Command result", body = CommandResponse),
+        (status = 400, description = "Invalid arguments or the command failed", body = ErrorResponse),
+        (status = 403, description = "The command needs the admin role", body = ErrorResponse),
+        (status = 404, description = "Unknown command", body = ErrorResponse),
+    ),
+)]
+async fn post_command(ConnectInfo(addr): ConnectInfo<SocketAddr>, Path(name): Path<String>, body: Option<Json<Value>>) -> ApiResult<CommandResponse> {
+    let args = body.map(|Json(args)| args).unwrap_or(json!({}));
+    let ctx = CommandContext { role: Role::User, source: source(&addr) };
+    let message = json!({ "type": "command", "command": name });
+    metrics::message_received("http", &message);
+
+    command_handler::router(&name, &args, &ctx)
+        .instrument(logging::request_span(&message))
+        .await
+        .map(|result| Json(CommandResponse { command: name.clone(), result }))
+        .map_err(|e| {
+            error!("HTTP command '{}' from {} failed: {}", name, addr, e);
+            match e {
+                CommandError::Unknown(e) => ApiError::not_found(e),
+                CommandError::Forbidden(e) => ApiError(StatusCode::FORBIDDEN, e),
+                CommandError::InvalidArgs(e) | CommandError::Failed(e) => ApiError::bad_request(e),
+            }
+        })
+}
+
+// Depth and complexity limits are enforced by the schema, see graphql.rs
+#[utoipa::path(
+    post, path = "/api/graphql", tag = "graphql",
+    request_body(content = Object, description = "A GraphQL request: `query`, optional `variables` and `operationName`"),
+    responses((status = 200, description = "GraphQL response with `data` and/or `errors`", body = Object)),
+)]
+async fn post_graphql(Json(request): Json<async_graphql::Request>) -> Json<async_graphql::Response> {
+    let message = json!({ "type": "graphql" });
+    metrics::message_received("http", &message);
+    Json(graphql::execute(request).instrument(logging::request_span(&message)).await)
+}
+
+#[utoipa::path(
+    get, path = "/api/graphql", tag = "graphql",
+    responses((status = 200, description = "The GraphQL schema in SDL", body = String, content_type = "text/plain")),
+)]
+async fn get_graphql_schema() -> String {
+    graphql::sdl()
+}
+
+// Upgrades to the live stream. Messages are JSON text frames, see live.rs.
+#[utoipa::path(
+    get, path = "/api/live", tag = "live",
+    params(("token" = Option<String>, Query, description = "Bearer token, for clients that cannot set the Authorization header")),
+    responses(
+        (status = 101, description = "Switched to the WebSocket stream of readings, alerts and query responses"),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorResponse),
+    ),
+)]
+async fn live_stream(ConnectInfo(addr): ConnectInfo<SocketAddr>, upgrade: WebSocketUpgrade) -> Response {
+    upgrade.on_upgrade(move |socket| live::serve_socket(socket, format!("ws:{}", addr)))
+}
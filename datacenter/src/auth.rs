@@ -1,29 +1,48 @@
-use log::info;
-use std::io;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-
-pub async fn authenticate_client(socket: &mut TcpStream, correct_password: &str) -> io::Result<bool> {
-    let mut buffer = [0; 1024];
-    
-    socket.write_all(b"Enter password: ").await?;
-    let n = socket.read(&mut buffer).await?;
-    
-    if n == 0 {
-        info!("Client disconnected during authentication.");
-        return Ok(false);
-    }
-    
-    let received_password = String::from_utf8_lossy(&buffer[..n]).trim().to_string();
-    
-    if received_password == correct_password {
-        socket.write_all(b"Access granted. You can now send JSON messages.\n").await?;
-        info!("Client authenticated successfully.");
-        Ok(true)
-    } else {
-        socket.write_all(b"Access denied.\n").await?;
-        info!("Client provided wrong password.");
-        socket.shutdown().await?;
-        Ok(false)
-    }
-}
\ No newline at end of file
+use log::info;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    User,
+    Admin,
+}
+
+// Returns the role the client authenticated with, or None if the password was wrong.
+// The admin password is optional; without it nobody can run admin-only commands.
+pub async fn authenticate_client(socket: &mut TcpStream, correct_password: &str, admin_password: Option<&str>) -> io::Result<Option<Role>> {
+    let mut buffer = [0; 1024];
+
+    socket.write_all(b"Enter password: ").await?;
+    let n = socket.read(&mut buffer).await?;
+
+    if n == 0 {
+        info!("Client disconnected during authentication.");
+        return Ok(None);
+    }
+
+    let received_password = String::from_utf8_lossy(&buffer[..n]).trim().to_string();
+
+    let role = if admin_password.is_some_and(|admin| !admin.is_empty() && received_password == admin) {
+        Some(Role::Admin)
+    } else if received_password == correct_password {
+        Some(Role::User)
+    } else {
+        None
+    };
+
+    match role {
+        Some(role) => {
+            socket.write_all(b"Access granted. You can now send JSON messages.\n").await?;
+            info!("Client authenticated successfully as {:?}.", role);
+        },
+        None => {
+            socket.write_all(b"Access denied.\n").await?;
+            info!("Client provided wrong password.");
+            crate::metrics::AUTH_FAILURES.inc();
+            socket.shutdown().await?;
+        },
+    }
+    Ok(role)
+}
@@ -0,0 +1,239 @@
+use clap::{Args, Parser, Subcommand};
+use log::info;
+use serde_json::{Value, json};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::config::{self, Config};
+use crate::db::get_db;
+use crate::export::{self, ExportFormat, ExportRequest};
+use crate::import::{self, ImportFormat, ImportOptions};
+use crate::migrations;
+use crate::reset::{self, ResetRequest, ResetScope};
+
+#[derive(Debug, Parser)]
+#[command(name = "datacenter", version, about = "Sensor data ingest server backed by Neo4j")]
+pub struct Cli {
+    /// Config file, default datacenter.toml (or CONFIG_PATH)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    // Kept for scripts written before the `check-config` subcommand
+    #[arg(long, hide = true)]
+    pub check_config: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the TCP, MQTT and metrics servers (the default)
+    Serve {
+        /// Address for the TCP server, overrides server.bind
+        #[arg(long, value_name = "ADDR")]
+        bind: Option<String>,
+    },
+    /// Apply pending graph migrations
+    Migrate {
+        /// Only show the statements that would run
+        #[arg(long, conflicts_with = "status")]
+        dry_run: bool,
+        /// Show the current and latest schema version
+        #[arg(long)]
+        status: bool,
+    },
+    /// Ingest readings from a JSON, JSONL or CSV file, resuming an interrupted import
+    Import {
+        file: PathBuf,
+        /// json, jsonl or csv; detected from the extension by default
+        #[arg(long)]
+        format: Option<ImportFormat>,
+        /// Records per batch, default ingest.batch_size
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+        batch_size: Option<u64>,
+        /// Ignore the checkpoint and import from the first record
+        #[arg(long)]
+        restart: bool,
+    },
+    /// Export readings as CSV, JSONL or Parquet
+    Export {
+        /// csv, jsonl or parquet
+        #[arg(long, default_value = "csv")]
+        format: ExportFormat,
+        /// Earliest timestamp, e.g. "2025-03-10 14:00:00"
+        #[arg(long)]
+        from: Option<String>,
+        /// Latest timestamp
+        #[arg(long)]
+        to: Option<String>,
+        /// Only these device UUIDs (comma-separated or repeated)
+        #[arg(long = "device", value_name = "UUID", value_delimiter = ',')]
+        devices: Vec<String>,
+        /// Only these colors (comma-separated or repeated)
+        #[arg(long = "color", value_name = "COLOR", value_delimiter = ',')]
+        colors: Vec<String>,
+        /// Output file, `-` for stdout; default <export.dir>/readings-<timestamp>.<format>
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Delete graph data
+    Reset {
+        #[command(flatten)]
+        scope: ScopeArgs,
+        /// Export the nodes in scope before deleting them
+        #[arg(long)]
+        export: bool,
+        /// Nodes deleted per transaction
+        #[arg(long, value_name = "N")]
+        batch_size: Option<i64>,
+        /// Skip the confirmation prompt
+        #[arg(long, short)]
+        yes: bool,
+    },
+    /// Show the status report of a running instance
+    Status {
+        /// Address of the running server, default server.bind on localhost
+        #[arg(long, value_name = "ADDR")]
+        addr: Option<String>,
+        /// Password, default server.password
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Validate the configuration and print it with secrets masked
+    CheckConfig,
+}
+
+#[derive(Debug, Args)]
+#[group(required = true, multiple = false)]
+pub struct ScopeArgs {
+    /// Everything except the schema version
+    #[arg(long)]
+    all: bool,
+    /// Only nodes with one of these labels (comma-separated)
+    #[arg(long, value_delimiter = ',')]
+    labels: Option<Vec<String>>,
+    /// Readings with a timestamp in FROM..=TO
+    #[arg(long, num_args = 2, value_names = ["FROM", "TO"])]
+    time_range: Option<Vec<String>>,
+}
+
+impl ScopeArgs {
+    fn to_scope(&self) -> ResetScope {
+        match (&self.labels, &self.time_range) {
+            (Some(labels), _) => ResetScope::Labels(labels.clone()),
+            (_, Some(range)) => ResetScope::TimeRange { from: range[0].clone(), to: range[1].clone() },
+            _ => ResetScope::All,
+        }
+    }
+}
+
+pub fn check_config(config: &Config) {
+    println!("{}", config.masked());
+    println!("# Configuration is valid");
+}
+
+pub async fn migrate(dry_run: bool, status: bool) -> Result<(), String> {
+    let db = get_db().await.map_err(|e| format!("Failed to connect to Neo4j: {}", e))?;
+    let result = if status {
+        migrations::status(db).await?
+    } else if dry_run {
+        migrations::dry_run(db).await?
+    } else {
+        migrations::migrate(db).await?
+    };
+    print_json(&result);
+    Ok(())
+}
+
+pub async fn import(path: &Path, format: Option<ImportFormat>, batch_size: Option<u64>, restart: bool) -> Result<(), String> {
+    let db = get_db().await.map_err(|e| format!("Failed to connect to Neo4j: {}", e))?;
+    migrations::check_compatible(db).await?;
+    let options = ImportOptions { format, batch_size: batch_size.map(|n| n as usize), restart };
+    let report = import::run(path, options, &format!("import:{}", path.display()), db).await?;
+    print_json(&report);
+    Ok(())
+}
+
+pub async fn export(request: ExportRequest, output: Option<PathBuf>) -> Result<(), String> {
+    let db = get_db().await.map_err(|e| format!("Failed to connect to Neo4j: {}", e))?;
+    if output.as_deref() == Some(Path::new("-")) {
+        let rows = export::write_readings(&request, std::io::BufWriter::new(std::io::stdout()), db).await?;
+        info!("Exported {} readings", rows);
+        return Ok(());
+    }
+    print_json(&export::to_file(&request, output, db).await?);
+    Ok(())
+}
+
+// Same two-step flow as the `reset` command, with the confirmation asked on the terminal
+pub async fn reset(scope: &ScopeArgs, export: bool, batch_size: Option<i64>, yes: bool) -> Result<(), String> {
+    let mut request = json!({ "export": export });
+    request["scope"] = match scope.to_scope() {
+        ResetScope::All => json!({ "all": true }),
+        ResetScope::Labels(labels) => json!({ "labels": labels }),
+        ResetScope::TimeRange { from, to } => json!({ "from": from, "to": to }),
+    };
+    if let Some(size) = batch_size {
+        request["batch_size"] = json!(size);
+    }
+
+    let db = get_db().await.map_err(|e| format!("Failed to connect to Neo4j: {}", e))?;
+    let prepared = reset::prepare(ResetRequest::from_json(&request)?, "cli", db).await?;
+    if !yes {
+        eprint!("This deletes {} nodes ({}). Type 'reset' to continue: ", prepared["nodes_in_scope"], prepared["scope"]);
+        let _ = std::io::stderr().flush();
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).map_err(|e| format!("Failed to read confirmation: {}", e))?;
+        if answer.trim() != "reset" {
+            return Err("Reset cancelled".to_string());
+        }
+    }
+    let token = prepared["token"].as_str().unwrap_or_default();
+    print_json(&reset::confirm(token, "cli", db).await?);
+    Ok(())
+}
+
+// Logs in to a running server like any TCP client and asks for its status report
+pub async fn status(addr: Option<String>, password: Option<String>) -> Result<(), String> {
+    let config = config::get();
+    let addr = addr.unwrap_or_else(|| config.server.bind.replace("0.0.0.0", "127.0.0.1"));
+    let password = password.unwrap_or_else(|| config.server.password.clone());
+
+    let request = async {
+        let mut socket = TcpStream::connect(&addr).await.map_err(|e| format!("Failed to connect to {}: {}", addr, e))?;
+        let mut prompt = [0; 64];
+        socket.read(&mut prompt).await.map_err(|e| e.to_string())?;
+        socket.write_all(password.as_bytes()).await.map_err(|e| e.to_string())?;
+
+        let mut reader = BufReader::new(socket);
+        let mut line = String::new();
+        reader.read_line(&mut line).await.map_err(|e| e.to_string())?;
+        if !line.contains("Access granted") {
+            return Err(format!("Login to {} failed: {}", addr, line.trim()));
+        }
+
+        let command = json!({ "type": "command", "command": "status" }).to_string();
+        reader.get_mut().write_all(command.as_bytes()).await.map_err(|e| e.to_string())?;
+        line.clear();
+        reader.read_line(&mut line).await.map_err(|e| e.to_string())?;
+        serde_json::from_str::<Value>(&line).map_err(|e| format!("Unexpected response from {}: {}", addr, e))
+    };
+    let response = tokio::time::timeout(Duration::from_secs(15), request).await
+        .map_err(|_| format!("No answer from {} within 15s", addr))??;
+
+    match response.get("success").and_then(Value::as_bool) {
+        Some(true) => {
+            print_json(&response["result"]);
+            Ok(())
+        },
+        _ => Err(response.get("message").and_then(Value::as_str).unwrap_or("status failed").to_string()),
+    }
+}
+
+fn print_json(value: &Value) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string()));
+}
@@ -0,0 +1,197 @@
+use flate2::Compression as GzipLevel;
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::io::Write;
+use std::str::FromStr;
+
+// Wire encodings for TCP and MQTT messages. Handlers only ever see `serde_json::Value` and
+// the typed models in models.rs; the encoding is applied at the transport edge, so a CBOR
+// or MessagePack gateway gets exactly the fields a JSON one does.
+//
+// TCP connections start in JSON and switch after a `hello` naming another encoding; from
+// then on every message in either direction is a 4-byte big-endian length followed by the
+// encoded message. MQTT 3.1.1 has no content-type property, so the encoding of a request
+// is recognised from its first byte and the response uses the same one unless the request
+// asks for another with `"accept"`. MQTT responses can also be compressed on request, see
+// `Compression`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Cbor,
+    MsgPack,
+}
+
+pub const ENCODINGS: [&str; 3] = ["json", "cbor", "msgpack"];
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Cbor => "cbor",
+            Encoding::MsgPack => "msgpack",
+        }
+    }
+
+    // Recognises the encoding of a message, which is always a map (or a JSON object)
+    pub fn detect(bytes: &[u8]) -> Encoding {
+        match bytes.iter().copied().find(|b| !b.is_ascii_whitespace()) {
+            // CBOR major type 5 (map), definite or indefinite length
+            Some(0xa0..=0xbb | 0xbf) => Encoding::Cbor,
+            // MessagePack fixmap, map 16 and map 32
+            Some(0x80..=0x8f | 0xde | 0xdf) => Encoding::MsgPack,
+            _ => Encoding::Json,
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Encoding::Cbor => ciborium::from_reader(bytes).map_err(|e| e.to_string()),
+            Encoding::MsgPack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).map_err(|e| e.to_string())?;
+                Ok(bytes)
+            },
+            // Maps keep their field names, like JSON
+            Encoding::MsgPack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "json" => Ok(Encoding::Json),
+            "cbor" => Ok(Encoding::Cbor),
+            "msgpack" => Ok(Encoding::MsgPack),
+            other => Err(format!("unknown encoding '{}', expected one of {}", other, ENCODINGS.join(", "))),
+        }
+    }
+}
+
+// Compression of MQTT responses, asked for with `"compression": "gzip" | "zstd"` in the
+// request. The encoded response is compressed before it is split into chunks, and the
+// published messages say so in their `compression` field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+pub const COMPRESSIONS: [&str; 2] = ["gzip", "zstd"];
+
+impl Compression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn compress(self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), GzipLevel::default());
+                encoder.write_all(bytes).and_then(|_| encoder.finish()).map_err(|e| e.to_string())
+            },
+            Compression::Zstd => zstd::encode_all(bytes, zstd::DEFAULT_COMPRESSION_LEVEL).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            other => Err(format!("unknown compression '{}', expected one of {}", other, COMPRESSIONS.join(", "))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+
+    fn message() -> Value {
+        json!({
+            "type": "data",
+            "message_id": "m1",
+            "data": [{ "uuid": "a", "energy_cost": 0.25, "sensor_data": { "temperature": -3, "humidity": 40.5 } }]
+        })
+    }
+
+    #[test]
+    fn every_encoding_round_trips() {
+        for encoding in [Encoding::Json, Encoding::Cbor, Encoding::MsgPack] {
+            let bytes = encoding.encode(&message()).unwrap();
+            assert_eq!(Encoding::detect(&bytes), encoding);
+            assert_eq!(encoding.decode::<Value>(&bytes).unwrap(), message(), "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn detects_maps_by_their_first_byte() {
+        assert_eq!(Encoding::detect(b"  \n{\"type\": \"all\"}"), Encoding::Json);
+        assert_eq!(Encoding::detect(b""), Encoding::Json);
+        // Empty CBOR map, indefinite-length CBOR map, empty MessagePack map, map 16
+        assert_eq!(Encoding::detect(&[0xa0]), Encoding::Cbor);
+        assert_eq!(Encoding::detect(&[0xbf, 0xff]), Encoding::Cbor);
+        assert_eq!(Encoding::detect(&[0x80]), Encoding::MsgPack);
+        assert_eq!(Encoding::detect(&[0xde, 0x00, 0x00]), Encoding::MsgPack);
+    }
+
+    #[test]
+    fn invalid_input_is_an_error() {
+        assert!(Encoding::Cbor.decode::<Value>(&[0xa1]).is_err());
+        assert!(Encoding::MsgPack.decode::<Value>(&[0x81]).is_err());
+        assert!(Encoding::Json.decode::<Value>(b"{").is_err());
+    }
+
+    #[test]
+    fn parses_encoding_names() {
+        for name in ENCODINGS {
+            assert_eq!(name.parse::<Encoding>().unwrap().name(), name);
+        }
+        assert_eq!("xml".parse::<Encoding>(), Err("unknown encoding 'xml', expected one of json, cbor, msgpack".to_string()));
+    }
+
+    #[test]
+    fn compressed_responses_decompress_to_the_original() {
+        use std::io::Read;
+
+        let bytes = Encoding::Json.encode(&json!({ "result": vec!["reading"; 500] })).unwrap();
+        let gzip = Compression::Gzip.compress(&bytes).unwrap();
+        let mut unzipped = Vec::new();
+        flate2::read::GzDecoder::new(gzip.as_slice()).read_to_end(&mut unzipped).unwrap();
+        assert_eq!(unzipped, bytes);
+        assert!(gzip.len() < bytes.len());
+
+        let zstd = Compression::Zstd.compress(&bytes).unwrap();
+        assert_eq!(zstd::decode_all(zstd.as_slice()).unwrap(), bytes);
+        assert!(zstd.len() < bytes.len());
+    }
+
+    #[test]
+    fn parses_compression_names() {
+        for name in COMPRESSIONS {
+            assert_eq!(name.parse::<Compression>().unwrap().name(), name);
+        }
+        assert!("brotli".parse::<Compression>().is_err());
+    }
+}
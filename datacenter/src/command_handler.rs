@@ -1,43 +1,143 @@
-use crate::query::reset_database_and_set_topology;
-use crate::db::{get_read_db, get_db};
-use log::{error, info};
-use std::process::exit;
-
-
-
-pub async fn router(command: &str) -> Result<bool, String> {
-    let db = match get_db().await {
-        Ok(db) => db,
-        Err(e) => {
-            error!("Failed to get database connection: {}", e);
-            return Err(format!("Failed to get database connection: {}", e));
-        },
-    };
-    
-    match command {
-        "exit" => {
-            info!("Exiting application...");
-            exit(0);
-        }
-        "reset" => {
-            info!("Resetting the server...");
-            match reset_database_and_set_topology(db).await {
-                Ok(_) => {
-                    info!("Database reset successfully");
-                    Ok(true)
-                },
-                Err(e) => {
-                    error!("Failed to reset database: {}", e);
-                    Err(format!("Failed to reset database: {}", e))
-                }
-            }
-        }
-        "status" => {
-            Ok(true)
-        }
-        _ => {
-            error!("Invalid command: {}", command);
-            Err(format!("Invalid command: {}", command))
-        },
-    }
-}
\ No newline at end of file
+use crate::auth::Role;
+use crate::commands;
+use crate::db::get_db;
+use async_trait::async_trait;
+use log::{error, info};
+use neo4rs::Graph;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::LazyLock;
+
+
+
+// Who sent a command and over which connection
+pub struct CommandContext {
+    pub role: Role,
+    pub source: String,
+}
+
+// A command that can be run over any transport. `args_schema` is a JSON Schema subset
+// (object with typed `properties` and `required`) checked before `execute` is called.
+#[async_trait]
+pub trait Command: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn help(&self) -> &'static str;
+    fn args_schema(&self) -> Value {
+        json!({ "type": "object", "properties": {} })
+    }
+    fn required_role(&self) -> Role {
+        Role::User
+    }
+    async fn execute(&self, args: &Value, ctx: &CommandContext) -> Result<Value, String>;
+}
+
+// Why `router` refused or failed a command. Transports that have status codes map the
+// kind; the others only send the message.
+#[derive(Debug)]
+pub enum CommandError {
+    Unknown(String),
+    Forbidden(String),
+    InvalidArgs(String),
+    Failed(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown(e)
+            | CommandError::Forbidden(e)
+            | CommandError::InvalidArgs(e)
+            | CommandError::Failed(e) => f.write_str(e),
+        }
+    }
+}
+
+impl From<CommandError> for String {
+    fn from(error: CommandError) -> String {
+        error.to_string()
+    }
+}
+
+static REGISTRY: LazyLock<BTreeMap<&'static str, Box<dyn Command>>> = LazyLock::new(|| {
+    commands::all().into_iter().map(|c| (c.name(), c)).collect()
+});
+
+// Database handle for commands that need one
+pub async fn db() -> Result<&'static Graph, String> {
+    get_db().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        format!("Failed to get database connection: {}", e)
+    })
+}
+
+fn type_matches(expected: &str, value: &Value) -> bool {
+    match expected {
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        _ => true,
+    }
+}
+
+// Checks `args` against the subset of JSON Schema used by `Command::args_schema`
+pub fn validate_args(schema: &Value, args: &Value) -> Result<(), String> {
+    let Some(args) = args.as_object() else {
+        return Err("'args' must be a JSON object".to_string());
+    };
+    if let Some(required) = schema.get("required").and_then(Value::as_array) {
+        for field in required.iter().filter_map(Value::as_str) {
+            if !args.contains_key(field) {
+                return Err(format!("Missing required argument '{}'", field));
+            }
+        }
+    }
+    if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+        for (field, value) in args {
+            if let Some(expected) = properties.get(field).and_then(|p| p.get("type")).and_then(Value::as_str) {
+                if !type_matches(expected, value) {
+                    return Err(format!("Argument '{}' must be of type {}", field, expected));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn describe(command: &dyn Command) -> Value {
+    json!({
+        "name": command.name(),
+        "help": command.help(),
+        "required_role": format!("{:?}", command.required_role()).to_lowercase(),
+        "args": command.args_schema()
+    })
+}
+
+// Lists every command, or describes a single one
+pub fn help(name: Option<&str>) -> Result<Value, String> {
+    match name {
+        Some(name) => REGISTRY.get(name)
+            .map(|c| describe(c.as_ref()))
+            .ok_or_else(|| format!("Invalid command: {}", name)),
+        None => Ok(json!(REGISTRY.values().map(|c| describe(c.as_ref())).collect::<Vec<_>>())),
+    }
+}
+
+pub async fn router(command: &str, args: &Value, ctx: &CommandContext) -> Result<Value, CommandError> {
+    let Some(handler) = REGISTRY.get(command) else {
+        error!("Invalid command: {}", command);
+        return Err(CommandError::Unknown(format!("Invalid command: {}", command)));
+    };
+
+    if ctx.role < handler.required_role() {
+        error!("Command '{}' denied for {}: {:?} role required", command, ctx.source, handler.required_role());
+        return Err(CommandError::Forbidden(format!("The {} command requires the {:?} role", command, handler.required_role()).to_lowercase()));
+    }
+    validate_args(&handler.args_schema(), args).map_err(CommandError::InvalidArgs)?;
+
+    info!("Running command '{}' for {}", command, ctx.source);
+    handler.execute(args, ctx).await.map_err(CommandError::Failed)
+}
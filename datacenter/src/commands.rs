@@ -0,0 +1,376 @@
+use async_trait::async_trait;
+use log::info;
+use serde_json::{Value, json};
+
+use crate::auth::Role;
+use crate::command_handler::{db, help, Command, CommandContext};
+use crate::device;
+use crate::export::{self, ExportRequest};
+use crate::import::{self, ImportOptions};
+use crate::migrations;
+use crate::quarantine;
+use crate::reset::{self, ResetRequest};
+use crate::schema;
+use crate::shutdown;
+use crate::status;
+use crate::wal;
+
+// Every command known to the router. Add new commands here.
+pub fn all() -> Vec<Box<dyn Command>> {
+    vec![
+        Box::new(Help),
+        Box::new(Status),
+        Box::new(WalStatus),
+        Box::new(Exit),
+        Box::new(Reset),
+        Box::new(RegisterDevice),
+        Box::new(UpdateDevice),
+        Box::new(DecommissionDevice),
+        Box::new(SchemaStatus),
+        Box::new(Migrate),
+        Box::new(MigrateStatus),
+        Box::new(MigrateDryRun),
+        Box::new(QuarantineList),
+        Box::new(QuarantineGet),
+        Box::new(QuarantineReplay),
+        Box::new(QuarantinePurge),
+        Box::new(Import),
+        Box::new(ImportStatus),
+        Box::new(Export),
+        Box::new(ExportChunk),
+    ]
+}
+
+struct Help;
+
+#[async_trait]
+impl Command for Help {
+    fn name(&self) -> &'static str { "help" }
+    fn help(&self) -> &'static str { "Lists all commands, or describes the one given in 'command'" }
+    fn args_schema(&self) -> Value {
+        json!({ "type": "object", "properties": { "command": { "type": "string" } } })
+    }
+    async fn execute(&self, args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        help(args.get("command").and_then(Value::as_str))
+    }
+}
+
+struct Status;
+
+#[async_trait]
+impl Command for Status {
+    fn name(&self) -> &'static str { "status" }
+    fn help(&self) -> &'static str { "Uptime, version, connections, Neo4j health, ingest counters and queue depths" }
+    async fn execute(&self, _args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        Ok(status::report().await)
+    }
+}
+
+struct WalStatus;
+
+#[async_trait]
+impl Command for WalStatus {
+    fn name(&self) -> &'static str { "wal_status" }
+    fn help(&self) -> &'static str { "Number of batches and records waiting in the write-ahead log" }
+    async fn execute(&self, _args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        let wal = wal::init().await?;
+        Ok(wal.backlog())
+    }
+}
+
+struct Exit;
+
+#[async_trait]
+impl Command for Exit {
+    fn name(&self) -> &'static str { "exit" }
+    fn help(&self) -> &'static str { "Shuts the server down gracefully" }
+    fn required_role(&self) -> Role { Role::Admin }
+    async fn execute(&self, _args: &Value, ctx: &CommandContext) -> Result<Value, String> {
+        info!("Exiting application...");
+        shutdown::trigger(&format!("exit command from {}", ctx.source));
+        Ok(json!({ "shutting_down": true }))
+    }
+}
+
+struct Reset;
+
+#[async_trait]
+impl Command for Reset {
+    fn name(&self) -> &'static str { "reset" }
+    fn help(&self) -> &'static str {
+        "Deletes graph data in two steps: call with 'scope' to get a confirmation token, then again with 'confirm'"
+    }
+    fn args_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "scope": { "type": "object" },
+                "export": { "type": "boolean" },
+                "batch_size": { "type": "integer" },
+                "confirm": { "type": "string" }
+            }
+        })
+    }
+    fn required_role(&self) -> Role { Role::Admin }
+    async fn execute(&self, args: &Value, ctx: &CommandContext) -> Result<Value, String> {
+        let db = db().await?;
+        match args.get("confirm").and_then(Value::as_str) {
+            Some(token) => {
+                info!("Resetting the server...");
+                reset::confirm(token, &ctx.source, db).await
+            },
+            None => reset::prepare(ResetRequest::from_json(args)?, &ctx.source, db).await,
+        }
+    }
+}
+
+struct RegisterDevice;
+
+#[async_trait]
+impl Command for RegisterDevice {
+    fn name(&self) -> &'static str { "register_device" }
+    fn help(&self) -> &'static str { "Registers a device with id, name, location, type, owner, tags and reporting_interval" }
+    fn args_schema(&self) -> Value {
+        json!({ "type": "object", "properties": { "device": { "type": "object" } }, "required": ["device"] })
+    }
+    fn required_role(&self) -> Role { Role::Admin }
+    async fn execute(&self, args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        device::register_device(&args["device"], db().await?).await.map(|id| json!({ "id": id }))
+    }
+}
+
+struct UpdateDevice;
+
+#[async_trait]
+impl Command for UpdateDevice {
+    fn name(&self) -> &'static str { "update_device" }
+    fn help(&self) -> &'static str { "Updates the given fields of a registered device" }
+    fn args_schema(&self) -> Value {
+        json!({ "type": "object", "properties": { "device": { "type": "object" } }, "required": ["device"] })
+    }
+    fn required_role(&self) -> Role { Role::Admin }
+    async fn execute(&self, args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        device::update_device(&args["device"], db().await?).await.map(|id| json!({ "id": id }))
+    }
+}
+
+struct DecommissionDevice;
+
+#[async_trait]
+impl Command for DecommissionDevice {
+    fn name(&self) -> &'static str { "decommission_device" }
+    fn help(&self) -> &'static str { "Marks a device as decommissioned; its readings are then handled by the unknown device policy" }
+    fn args_schema(&self) -> Value {
+        json!({ "type": "object", "properties": { "id": { "type": "string" } }, "required": ["id"] })
+    }
+    fn required_role(&self) -> Role { Role::Admin }
+    async fn execute(&self, args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        let id = args["id"].as_str().unwrap_or_default();
+        device::decommission_device(id, db().await?).await.map(|id| json!({ "id": id }))
+    }
+}
+
+struct SchemaStatus;
+
+#[async_trait]
+impl Command for SchemaStatus {
+    fn name(&self) -> &'static str { "schema_status" }
+    fn help(&self) -> &'static str { "Shows which of the expected constraints and indexes exist" }
+    async fn execute(&self, _args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        schema::schema_status(db().await?).await
+    }
+}
+
+struct Migrate;
+
+#[async_trait]
+impl Command for Migrate {
+    fn name(&self) -> &'static str { "migrate" }
+    fn help(&self) -> &'static str { "Applies all pending graph migrations" }
+    fn required_role(&self) -> Role { Role::Admin }
+    async fn execute(&self, _args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        migrations::migrate(db().await?).await
+    }
+}
+
+struct MigrateStatus;
+
+#[async_trait]
+impl Command for MigrateStatus {
+    fn name(&self) -> &'static str { "migrate_status" }
+    fn help(&self) -> &'static str { "Shows the current and latest schema version and pending migrations" }
+    async fn execute(&self, _args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        migrations::status(db().await?).await
+    }
+}
+
+struct MigrateDryRun;
+
+#[async_trait]
+impl Command for MigrateDryRun {
+    fn name(&self) -> &'static str { "migrate_dry_run" }
+    fn help(&self) -> &'static str { "Lists the statements 'migrate' would run without applying them" }
+    async fn execute(&self, _args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        migrations::dry_run(db().await?).await
+    }
+}
+
+struct QuarantineList;
+
+#[async_trait]
+impl Command for QuarantineList {
+    fn name(&self) -> &'static str { "quarantine_list" }
+    fn help(&self) -> &'static str { "Lists the newest quarantined records (default limit 100)" }
+    fn args_schema(&self) -> Value {
+        json!({ "type": "object", "properties": { "limit": { "type": "integer" } } })
+    }
+    async fn execute(&self, args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        let limit = args.get("limit").and_then(Value::as_u64).unwrap_or(100) as usize;
+        quarantine::list(limit, db().await?).await.map(|entries| json!(entries))
+    }
+}
+
+struct QuarantineGet;
+
+#[async_trait]
+impl Command for QuarantineGet {
+    fn name(&self) -> &'static str { "quarantine_get" }
+    fn help(&self) -> &'static str { "Shows one quarantined record with its raw payload, reason and source" }
+    fn args_schema(&self) -> Value {
+        json!({ "type": "object", "properties": { "id": { "type": "string" } }, "required": ["id"] })
+    }
+    async fn execute(&self, args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        let id = args["id"].as_str().unwrap_or_default();
+        match quarantine::get(id, db().await?).await? {
+            Some(entry) => Ok(entry),
+            None => Err(format!("No quarantined record with id '{}'", id)),
+        }
+    }
+}
+
+struct QuarantineReplay;
+
+#[async_trait]
+impl Command for QuarantineReplay {
+    fn name(&self) -> &'static str { "quarantine_replay" }
+    fn help(&self) -> &'static str { "Re-validates and ingests one quarantined record, or all of them without 'id'" }
+    fn args_schema(&self) -> Value {
+        json!({ "type": "object", "properties": { "id": { "type": "string" } } })
+    }
+    fn required_role(&self) -> Role { Role::Admin }
+    async fn execute(&self, args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        quarantine::replay(args.get("id").and_then(Value::as_str), db().await?).await
+    }
+}
+
+struct QuarantinePurge;
+
+#[async_trait]
+impl Command for QuarantinePurge {
+    fn name(&self) -> &'static str { "quarantine_purge" }
+    fn help(&self) -> &'static str { "Deletes one quarantined record, or all of them with 'all': true" }
+    fn args_schema(&self) -> Value {
+        json!({ "type": "object", "properties": { "id": { "type": "string" }, "all": { "type": "boolean" } } })
+    }
+    fn required_role(&self) -> Role { Role::Admin }
+    async fn execute(&self, args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        // Without an id the whole quarantine is purged, which has to be asked for explicitly
+        let id = args.get("id").and_then(Value::as_str);
+        if id.is_none() && args.get("all").and_then(Value::as_bool) != Some(true) {
+            return Err("Specify an 'id' or set 'all': true to purge the whole quarantine".to_string());
+        }
+        quarantine::purge(id, db().await?).await.map(|removed| json!({ "removed": removed }))
+    }
+}
+
+struct Import;
+
+#[async_trait]
+impl Command for Import {
+    fn name(&self) -> &'static str { "import" }
+    fn help(&self) -> &'static str {
+        "Imports a JSON, JSONL or CSV file on the server in the background; rerun it to resume an interrupted import"
+    }
+    fn args_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "file": { "type": "string" },
+                "format": { "type": "string", "enum": ["json", "jsonl", "csv"] },
+                "batch_size": { "type": "integer" },
+                "restart": { "type": "boolean" }
+            },
+            "required": ["file"]
+        })
+    }
+    fn required_role(&self) -> Role { Role::Admin }
+    async fn execute(&self, args: &Value, ctx: &CommandContext) -> Result<Value, String> {
+        let file = args["file"].as_str().unwrap_or_default();
+        let options = ImportOptions::from_json(args)?;
+        info!("Import of {} requested by {}", file, ctx.source);
+        import::spawn(file.into(), options, format!("import:{}", file), db().await?)
+    }
+}
+
+struct ImportStatus;
+
+#[async_trait]
+impl Command for ImportStatus {
+    fn name(&self) -> &'static str { "import_status" }
+    fn help(&self) -> &'static str { "Shows the progress of one import, or of all imports since startup" }
+    fn args_schema(&self) -> Value {
+        json!({ "type": "object", "properties": { "id": { "type": "string" } } })
+    }
+    async fn execute(&self, args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        import::progress(args.get("id").and_then(Value::as_str))
+    }
+}
+
+struct Export;
+
+#[async_trait]
+impl Command for Export {
+    fn name(&self) -> &'static str { "export" }
+    fn help(&self) -> &'static str {
+        "Exports readings filtered by 'from', 'to', 'devices' and 'colors' as csv, jsonl or parquet; fetch the file with 'export_chunk'"
+    }
+    fn args_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "format": { "type": "string", "enum": ["csv", "jsonl", "parquet"] },
+                "from": { "type": "string" },
+                "to": { "type": "string" },
+                "devices": { "type": "array" },
+                "colors": { "type": "array" }
+            }
+        })
+    }
+    fn required_role(&self) -> Role { Role::Admin }
+    async fn execute(&self, args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        export::to_export_dir(&ExportRequest::from_json(args)?, db().await?).await
+    }
+}
+
+struct ExportChunk;
+
+#[async_trait]
+impl Command for ExportChunk {
+    fn name(&self) -> &'static str { "export_chunk" }
+    fn help(&self) -> &'static str { "Returns part 'index' (from 0) of an export as base64, 'chunk_size' raw bytes at a time" }
+    fn args_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "id": { "type": "string" },
+                "index": { "type": "integer" },
+                "chunk_size": { "type": "integer" }
+            },
+            "required": ["id", "index"]
+        })
+    }
+    async fn execute(&self, args: &Value, _ctx: &CommandContext) -> Result<Value, String> {
+        let index = args["index"].as_u64().ok_or("'index' must not be negative")?;
+        export::read_chunk(args["id"].as_str().unwrap_or_default(), index, args.get("chunk_size").and_then(Value::as_u64)).await
+    }
+}
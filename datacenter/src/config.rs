@@ -0,0 +1,597 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::device::UnknownDevicePolicy;
+use crate::query::CommitMode;
+
+// All settings in one place. Loaded once at startup from a TOML file (CONFIG_PATH, default
+// datacenter.toml; a missing file means defaults), then overridden by the environment
+// variables in ENV_OVERRIDES, so the existing .env keeps working.
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+pub const DEFAULT_PATH: &str = "datacenter.toml";
+
+// Keys whose values are masked when the config is printed
+const SECRET_KEYS: &[&str] = &["password", "admin_password", "token"];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub neo4j: Neo4jConfig,
+    pub mqtt: MqttConfig,
+    pub ingest: IngestConfig,
+    pub wal: WalConfig,
+    pub quarantine: QuarantineConfig,
+    pub schema: SchemaConfig,
+    pub export: ExportConfig,
+    pub import: ImportConfig,
+    pub shutdown: ShutdownConfig,
+    pub metrics: MetricsConfig,
+    pub http: HttpConfig,
+    pub graphql: GraphqlConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub password: String,
+    // Unset or empty disables admin-only commands
+    pub admin_password: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { bind: "0.0.0.0:12345".into(), password: String::new(), admin_password: None }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Neo4jConfig {
+    pub primary_uris: Vec<String>,
+    pub secondary_uris: Vec<String>,
+    pub user: String,
+    pub password: String,
+}
+
+impl Default for Neo4jConfig {
+    fn default() -> Self {
+        Neo4jConfig {
+            primary_uris: vec!["bolt://server1:7687".into()],
+            secondary_uris: vec!["bolt://server3:9687".into(), "bolt://server2:8687".into()],
+            user: "neo4j".into(),
+            password: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: String,
+    pub connect_timeout_secs: u64,
+    // Requests arrive on `<request_topic>` and `<request_topic>/<client id>`
+    pub request_topic: String,
+    // Responses go to `<response_topic>/<client id>/<type>`
+    pub response_topic: String,
+    // Connect, disconnect and last-will announcements
+    pub clients_topic: String,
+    // Responses larger than this are split into chunks; keep it below the broker's limit
+    pub max_packet_size: usize,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            host: "mosquitto-broker".into(),
+            port: 1883,
+            user: "admin".into(),
+            password: "admin".into(),
+            connect_timeout_secs: 10,
+            request_topic: "rust/topic".into(),
+            response_topic: "rust/response".into(),
+            clients_topic: "rust/clients".into(),
+            max_packet_size: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IngestConfig {
+    pub batch_size: usize,
+    pub commit_mode: CommitMode,
+    pub unknown_device_policy: UnknownDevicePolicy,
+    // 0 disables deduplication
+    pub dedupe_window_secs: u64,
+    // Keys remembered at most; the oldest go first when a busy window would exceed it
+    pub dedupe_max_keys: usize,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        IngestConfig {
+            batch_size: 500,
+            commit_mode: CommitMode::All,
+            unknown_device_policy: UnknownDevicePolicy::Accept,
+            dedupe_window_secs: 3600,
+            dedupe_max_keys: 200_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WalConfig {
+    pub dir: PathBuf,
+    // Failed flushes of one batch while Neo4j is reachable before it goes to the
+    // dead-letter file
+    pub max_attempts: u32,
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        WalConfig { dir: "wal".into(), max_attempts: 5 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuarantineStore {
+    Neo4j,
+    File,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuarantineConfig {
+    pub backend: QuarantineStore,
+    // Only used by the file backend
+    pub file: PathBuf,
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> Self {
+        QuarantineConfig { backend: QuarantineStore::Neo4j, file: "quarantine.jsonl".into() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchemaConfig {
+    // Apply pending graph migrations at startup
+    pub auto_migrate: bool,
+}
+
+impl Default for SchemaConfig {
+    fn default() -> Self {
+        SchemaConfig { auto_migrate: true }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportConfig {
+    pub dir: PathBuf,
+    // Files of the `export` command are removed after this long, and beyond the newest
+    // `max_files`; reset backups are kept
+    pub retention_secs: u64,
+    pub max_files: usize,
+    // Largest `chunk_size` an `export_chunk` request may ask for
+    pub max_chunk_size: u64,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        ExportConfig { dir: "exports".into(), retention_secs: 24 * 60 * 60, max_files: 20, max_chunk_size: 1024 * 1024 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImportConfig {
+    // Progress of unfinished bulk imports, one file per imported file
+    pub checkpoint_dir: PathBuf,
+}
+
+impl Default for ImportConfig {
+    fn default() -> Self {
+        ImportConfig { checkpoint_dir: "imports".into() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    // Grace period for connections, WAL flush and MQTT goodbye
+    pub timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig { timeout_secs: 30 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub bind: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig { bind: "0.0.0.0:9100".into() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub bind: String,
+    // Bearer token for the REST API; the API stays off while it is empty
+    pub token: String,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig { bind: "0.0.0.0:8080".into(), token: String::new() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GraphqlConfig {
+    // Deepest nesting of selections a query may use
+    pub max_depth: usize,
+    // Lists count as their limit times the cost of one item
+    pub max_complexity: usize,
+}
+
+impl Default for GraphqlConfig {
+    fn default() -> Self {
+        GraphqlConfig { max_depth: 8, max_complexity: 5000 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadLogging {
+    Off,
+    Redacted,
+    Full,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    // tracing filter directives, e.g. "info,neo4rs=warn"
+    pub filter: String,
+    pub format: LogFormat,
+    pub payloads: PayloadLogging,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig { filter: "info".into(), format: LogFormat::Text, payloads: PayloadLogging::Redacted }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Str,
+    Int,
+    Bool,
+    // Comma-separated
+    List,
+}
+
+// Environment variable, config key, and how to parse the value
+const ENV_OVERRIDES: &[(&str, &str, Kind)] = &[
+    ("SERVER_BIND", "server.bind", Kind::Str),
+    ("SERVER_PASSWORD", "server.password", Kind::Str),
+    ("ADMIN_PASSWORD", "server.admin_password", Kind::Str),
+    ("NEO4J_PRIMARY_URIS", "neo4j.primary_uris", Kind::List),
+    ("NEO4J_SECONDARY_URIS", "neo4j.secondary_uris", Kind::List),
+    ("DATABASE_USER", "neo4j.user", Kind::Str),
+    ("DATABASE_PASSWORD", "neo4j.password", Kind::Str),
+    ("MQTT_HOST", "mqtt.host", Kind::Str),
+    ("MQTT_PORT", "mqtt.port", Kind::Int),
+    ("MQTT_USER", "mqtt.user", Kind::Str),
+    ("MQTT_PASSWORD", "mqtt.password", Kind::Str),
+    ("MQTT_MAX_PACKET_SIZE", "mqtt.max_packet_size", Kind::Int),
+    ("INGEST_BATCH_SIZE", "ingest.batch_size", Kind::Int),
+    ("INGEST_COMMIT_MODE", "ingest.commit_mode", Kind::Str),
+    ("UNKNOWN_DEVICE_POLICY", "ingest.unknown_device_policy", Kind::Str),
+    ("DEDUPE_WINDOW_SECS", "ingest.dedupe_window_secs", Kind::Int),
+    ("DEDUPE_MAX_KEYS", "ingest.dedupe_max_keys", Kind::Int),
+    ("WAL_DIR", "wal.dir", Kind::Str),
+    ("WAL_MAX_ATTEMPTS", "wal.max_attempts", Kind::Int),
+    ("QUARANTINE_BACKEND", "quarantine.backend", Kind::Str),
+    ("QUARANTINE_FILE", "quarantine.file", Kind::Str),
+    ("AUTO_MIGRATE", "schema.auto_migrate", Kind::Bool),
+    ("EXPORT_DIR", "export.dir", Kind::Str),
+    ("EXPORT_RETENTION_SECS", "export.retention_secs", Kind::Int),
+    ("EXPORT_MAX_FILES", "export.max_files", Kind::Int),
+    ("EXPORT_MAX_CHUNK_SIZE", "export.max_chunk_size", Kind::Int),
+    ("IMPORT_CHECKPOINT_DIR", "import.checkpoint_dir", Kind::Str),
+    ("SHUTDOWN_TIMEOUT_SECS", "shutdown.timeout_secs", Kind::Int),
+    ("METRICS_ADDR", "metrics.bind", Kind::Str),
+    ("HTTP_BIND", "http.bind", Kind::Str),
+    ("HTTP_TOKEN", "http.token", Kind::Str),
+    ("GRAPHQL_MAX_DEPTH", "graphql.max_depth", Kind::Int),
+    ("GRAPHQL_MAX_COMPLEXITY", "graphql.max_complexity", Kind::Int),
+    ("RUST_LOG", "logging.filter", Kind::Str),
+    ("LOG_FORMAT", "logging.format", Kind::Str),
+    ("LOG_PAYLOADS", "logging.payloads", Kind::Str),
+];
+
+// Everything that is wrong with a config, reported together
+#[derive(Debug)]
+pub struct ConfigError {
+    pub source: String,
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration ({}):", self.source)?;
+        for problem in &self.problems {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    // Reads the file (if it exists), applies the environment and validates the result
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        let path = path.map(Path::to_path_buf)
+            .or_else(|| env::var("CONFIG_PATH").ok().map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_PATH));
+        let source = path.display().to_string();
+        let fail = |problems: Vec<String>| ConfigError { source: source.clone(), problems };
+
+        let mut table = match std::fs::read_to_string(&path) {
+            Ok(text) => text.parse::<toml::Table>().map_err(|e| fail(vec![e.to_string()]))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => toml::Table::new(),
+            Err(e) => return Err(fail(vec![format!("cannot read {}: {}", path.display(), e)])),
+        };
+
+        let problems = apply_env(&mut table, |var| env::var(var).ok());
+        if !problems.is_empty() {
+            return Err(fail(problems));
+        }
+        Self::from_table(table).map_err(fail)
+    }
+
+    // Deserialises the merged settings and validates them
+    fn from_table(table: toml::Table) -> Result<Config, Vec<String>> {
+        let config: Config = toml::Value::Table(table).try_into().map_err(|e: toml::de::Error| vec![e.to_string().trim().replace('\n', " ")])?;
+        let problems = config.validate();
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(problems)
+        }
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.server.bind.parse::<SocketAddr>().is_err() {
+            problems.push(format!("server.bind: '{}' is not a socket address like 0.0.0.0:12345", self.server.bind));
+        }
+        if self.server.password.is_empty() {
+            problems.push("server.password must be set (or SERVER_PASSWORD)".into());
+        }
+        if self.neo4j.primary_uris.is_empty() {
+            problems.push("neo4j.primary_uris needs at least one URI".into());
+        }
+        for uri in self.neo4j.primary_uris.iter().chain(&self.neo4j.secondary_uris) {
+            if !["bolt://", "bolt+s://", "neo4j://", "neo4j+s://"].iter().any(|scheme| uri.starts_with(scheme)) {
+                problems.push(format!("neo4j: '{}' is not a bolt:// or neo4j:// URI", uri));
+            }
+        }
+        if self.neo4j.user.is_empty() {
+            problems.push("neo4j.user must be set (or DATABASE_USER)".into());
+        }
+        if self.mqtt.host.is_empty() {
+            problems.push("mqtt.host must not be empty".into());
+        }
+        if self.mqtt.port == 0 {
+            problems.push("mqtt.port must not be 0".into());
+        }
+        if self.mqtt.max_packet_size < 512 {
+            problems.push(format!("mqtt.max_packet_size must be at least 512 bytes, got {}", self.mqtt.max_packet_size));
+        }
+        for (key, topic) in [
+            ("mqtt.request_topic", &self.mqtt.request_topic),
+            ("mqtt.response_topic", &self.mqtt.response_topic),
+            ("mqtt.clients_topic", &self.mqtt.clients_topic),
+        ] {
+            if topic.is_empty() || topic.contains(['#', '+']) || topic.ends_with('/') {
+                problems.push(format!("{}: '{}' must be a plain topic without wildcards or trailing '/'", key, topic));
+            }
+        }
+        if self.ingest.batch_size == 0 {
+            problems.push("ingest.batch_size must be greater than 0".into());
+        }
+        if self.ingest.dedupe_max_keys == 0 {
+            problems.push("ingest.dedupe_max_keys must be greater than 0".into());
+        }
+        if self.wal.max_attempts == 0 {
+            problems.push("wal.max_attempts must be greater than 0".into());
+        }
+        if self.export.retention_secs == 0 || self.export.max_files == 0 || self.export.max_chunk_size == 0 {
+            problems.push("export.retention_secs, export.max_files and export.max_chunk_size must be greater than 0".into());
+        }
+        if self.metrics.bind.parse::<SocketAddr>().is_err() {
+            problems.push(format!("metrics.bind: '{}' is not a socket address like 0.0.0.0:9100", self.metrics.bind));
+        }
+        if self.http.bind.parse::<SocketAddr>().is_err() {
+            problems.push(format!("http.bind: '{}' is not a socket address like 0.0.0.0:8080", self.http.bind));
+        }
+        if self.graphql.max_depth == 0 || self.graphql.max_complexity == 0 {
+            problems.push("graphql.max_depth and graphql.max_complexity must be greater than 0".into());
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.logging.filter) {
+            problems.push(format!("logging.filter: {}", e));
+        }
+        problems
+    }
+
+    // The effective config as TOML, with secrets replaced by "***"
+    pub fn masked(&self) -> String {
+        let mut value = toml::Value::try_from(self).expect("config serialises to TOML");
+        mask(&mut value);
+        toml::to_string_pretty(&value).expect("config serialises to TOML")
+    }
+}
+
+// Writes the set variables of ENV_OVERRIDES into `table`, returning the values that
+// could not be parsed
+fn apply_env(table: &mut toml::Table, lookup: impl Fn(&str) -> Option<String>) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (var, key, kind) in ENV_OVERRIDES {
+        let Some(raw) = lookup(var) else { continue };
+        let value = match kind {
+            Kind::Str => toml::Value::String(raw),
+            Kind::Int => match raw.trim().parse::<i64>() {
+                Ok(n) => toml::Value::Integer(n),
+                Err(_) => {
+                    problems.push(format!("{}: expected an integer, got '{}'", var, raw));
+                    continue;
+                }
+            },
+            Kind::Bool => match raw.trim().to_lowercase().as_str() {
+                "true" | "1" | "yes" => toml::Value::Boolean(true),
+                "false" | "0" | "no" => toml::Value::Boolean(false),
+                _ => {
+                    problems.push(format!("{}: expected true or false, got '{}'", var, raw));
+                    continue;
+                }
+            },
+            Kind::List => toml::Value::Array(
+                raw.split(',').map(str::trim).filter(|s| !s.is_empty()).map(|s| toml::Value::String(s.into())).collect(),
+            ),
+        };
+        let (section, field) = key.split_once('.').expect("override keys are section.field");
+        match table.entry(section).or_insert_with(|| toml::Value::Table(toml::Table::new())) {
+            toml::Value::Table(section) => {
+                section.insert(field.to_string(), value);
+            },
+            _ => problems.push(format!("'{}' must be a table", section)),
+        }
+    }
+    problems
+}
+
+fn mask(value: &mut toml::Value) {
+    if let toml::Value::Table(table) = value {
+        for (key, value) in table.iter_mut() {
+            match value {
+                toml::Value::String(s) if SECRET_KEYS.contains(&key.as_str()) && !s.is_empty() => *s = "***".into(),
+                other => mask(other),
+            }
+        }
+    }
+}
+
+pub fn init(config: Config) -> &'static Config {
+    CONFIG.get_or_init(|| config)
+}
+
+// The loaded config; `init` runs first thing in main
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("config::init must be called before config::get")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn load(text: &str, vars: &[(&str, &str)]) -> Result<Config, Vec<String>> {
+        let mut table = text.parse::<toml::Table>().expect("valid TOML");
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        let problems = apply_env(&mut table, |var| vars.get(var).map(|v| v.to_string()));
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+        Config::from_table(table)
+    }
+
+    #[test]
+    fn defaults_only_need_a_password() {
+        assert_eq!(Config::default().validate(), vec!["server.password must be set (or SERVER_PASSWORD)".to_string()]);
+        assert!(load("[server]\npassword = \"1234\"", &[]).is_ok());
+    }
+
+    #[test]
+    fn env_overrides_the_file() {
+        let config = load(
+            "[server]\npassword = \"file\"\n[mqtt]\nport = 1883",
+            &[
+                ("SERVER_PASSWORD", "env"),
+                ("MQTT_PORT", " 8883 "),
+                ("AUTO_MIGRATE", "yes"),
+                ("NEO4J_PRIMARY_URIS", "bolt://a:7687, ,neo4j://b:7687"),
+            ],
+        ).expect("valid config");
+        assert_eq!(config.server.password, "env");
+        assert_eq!(config.mqtt.port, 8883);
+        assert!(config.schema.auto_migrate);
+        assert_eq!(config.neo4j.primary_uris, vec!["bolt://a:7687", "neo4j://b:7687"]);
+    }
+
+    #[test]
+    fn reports_unparsable_env_values() {
+        let problems = load("", &[("MQTT_PORT", "eighty"), ("AUTO_MIGRATE", "maybe")]).unwrap_err();
+        assert_eq!(problems, vec![
+            "MQTT_PORT: expected an integer, got 'eighty'".to_string(),
+            "AUTO_MIGRATE: expected true or false, got 'maybe'".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let problems = load("[server]\npassword = \"1234\"\npasword = \"typo\"", &[]).unwrap_err();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("pasword"), "{}", problems[0]);
+    }
+
+    #[test]
+    fn reports_every_invalid_value() {
+        let mut config = Config::default();
+        config.server.password = "1234".into();
+        config.server.bind = "nowhere".into();
+        config.neo4j.primary_uris = vec!["http://db:7474".into()];
+        config.mqtt.request_topic = "requests/#".into();
+        config.ingest.batch_size = 0;
+        config.export.max_chunk_size = 0;
+        let problems = config.validate();
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert!(problems[0].starts_with("server.bind: 'nowhere'"));
+        assert!(problems[1].starts_with("neo4j: 'http://db:7474'"));
+        assert!(problems[2].starts_with("mqtt.request_topic: 'requests/#'"));
+        assert_eq!(problems[3], "ingest.batch_size must be greater than 0");
+        assert!(problems[4].starts_with("export.retention_secs"));
+    }
+}
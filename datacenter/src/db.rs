@@ -1,144 +1,177 @@
-use log::{info, error};
-use neo4rs::{Graph, Error as Neo4jError};
-use std::env;
-use tokio::sync::OnceCell;
-use std::fmt;
-
-pub static DB: OnceCell<DatabaseCluster> = OnceCell::const_new();
-
-#[derive(Debug)]
-#[allow(dead_code)]
-pub enum DbError {
-    Neo4jError(Neo4jError),
-    ConnectionError(String),
-    OtherError(String),
-}
-
-impl From<Neo4jError> for DbError {
-    fn from(error: Neo4jError) -> Self {
-        DbError::Neo4jError(error)
-    }
-}
-
-impl From<String> for DbError {
-    fn from(error: String) -> Self {
-        DbError::ConnectionError(error)
-    }
-}
-
-pub async fn initialize_db() -> Result<DatabaseCluster, DbError> {
-    info!("initialize_db called");
-    dotenv::dotenv().ok();
-
-    let uri_server1 = env::var("NEO4J_URI_WRITE_1").map_err(|e| {
-        error!("Failed to read NEO4J_URI_WRITE_1: {}", e);
-        DbError::ConnectionError(e.to_string())
-    })?;
-    let uri_server2 = env::var("NEO4J_URI_WRITE_2").map_err(|e| {
-        error!("Failed to read NEO4J_URI_WRITE_2: {}", e);
-        DbError::ConnectionError(e.to_string())
-    })?;
-    let uri_server3 = env::var("NEO4J_URI_READ_3").map_err(|e| {
-        error!("Failed to read NEO4J_URI_READ_3: {}", e);
-        DbError::ConnectionError(e.to_string())
-    })?;
-
-    let primary_nodes = vec![
-        connect_db(uri_server1).await?,
-    ];
-    let secondary_nodes = vec![
-        connect_db(uri_server3).await?,
-        connect_db(uri_server2).await?,
-    ];
-
-    let cluster = DatabaseCluster {
-        primary_nodes,
-        secondary_nodes,
-    };
-    info!("initialize_db returning: {:?}", cluster);
-    Ok(cluster)
-}
-
-async fn connect_db(uri_env: String) -> Result<Graph, DbError> {
-    info!("Attempting to connect to Neo4j at {}", uri_env);
-    let username = env::var("DATABASE_USER").map_err(|e| {
-        error!("DATABASE_USER env variable is missing: {}", e);
-        DbError::ConnectionError("Missing DATABASE_USER".into())
-    })?;
-    let password = env::var("DATABASE_PASSWORD").map_err(|e| {
-        error!("DATABASE_PASSWORD env variable is missing: {}", e);
-        DbError::ConnectionError("Missing DATABASE_PASSWORD".into())
-    })?;
-
-    let graph = Graph::new(&uri_env, &username, &password).await.map_err(|e| {
-        error!("Connection to Neo4j failed at {}: {:?}", uri_env, e);
-        DbError::Neo4jError(e)
-    })?;
-
-    info!("Successfully connected to Neo4j at {}", uri_env);
-    Ok(graph)
-}
-
-
-pub async fn get_db() -> Result<&'static Graph, DbError> {
-    info!("get_db called");
-    if let Some(cluster) = DB.get() {
-        info!("get_db returning primary node");
-        return Ok(&cluster.primary_nodes[0]);
-    }
-
-    let cluster = initialize_db().await?;
-    DB.set(cluster).map_err(|_| {
-        error!("Failed to initialize DB");
-        DbError::OtherError("Failed to initialize DB".into())
-    })?;
-
-    info!("get_db returning newly initialized primary node");
-    Ok(&DB.get().unwrap().primary_nodes[0])
-}
-
-pub async fn get_read_db(number:usize) -> Result<&'static Graph, DbError> {
-    info!("get_read_db called");
-    if let Some(cluster) = DB.get() {
-        info!("get_read_db returning secondary node");
-
-        return Ok(&cluster.secondary_nodes[0]);
-    }
-
-    let cluster = initialize_db().await?;
-    DB.set(cluster).map_err(|_| {
-        error!("Failed to initialize DB");
-        DbError::OtherError("Failed to initialize DB".into())
-    })?;
-
-    info!("get_read_db returning newly initialized secondary node");
-    Ok(&DB.get().unwrap().secondary_nodes[number])
-}
-
-#[derive(Clone)]
-pub struct DatabaseCluster {
-    primary_nodes: Vec<Graph>,
-    secondary_nodes: Vec<Graph>,
-}
-
-impl std::fmt::Debug for DatabaseCluster {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("DatabaseCluster")
-            .field("primary_nodes_count", &self.primary_nodes.len())
-            .field("secondary_nodes_count", &self.secondary_nodes.len())
-            .finish()
-    }
-}
-
-
-
-
-impl fmt::Display for DbError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            DbError::Neo4jError(e) => write!(f, "Neo4j error: {}", e),
-            DbError::ConnectionError(e) => write!(f, "Connection error: {}", e),
-            DbError::OtherError(e) => write!(f, "Error: {}", e),
-        }
-    }
+use log::{info, error};
+use neo4rs::{Graph, Error as Neo4jError, query};
+use serde_json::{Value, json};
+use tokio::sync::OnceCell;
+
+use crate::config;
+use std::fmt;
+
+pub static DB: OnceCell<DatabaseCluster> = OnceCell::const_new();
+
+#[derive(Debug)]
+#[allow(dead_code, clippy::enum_variant_names)]
+pub enum DbError {
+    Neo4jError(Neo4jError),
+    ConnectionError(String),
+    OtherError(String),
+}
+
+impl From<Neo4jError> for DbError {
+    fn from(error: Neo4jError) -> Self {
+        DbError::Neo4jError(error)
+    }
+}
+
+impl From<String> for DbError {
+    fn from(error: String) -> Self {
+        DbError::ConnectionError(error)
+    }
+}
+
+pub async fn initialize_db() -> Result<DatabaseCluster, DbError> {
+    info!("initialize_db called");
+    let neo4j = &config::get().neo4j;
+
+    let mut primary_nodes = Vec::new();
+    for uri in &neo4j.primary_uris {
+        primary_nodes.push(connect_db(uri).await?);
+    }
+    let mut secondary_nodes = Vec::new();
+    for uri in &neo4j.secondary_uris {
+        secondary_nodes.push(connect_db(uri).await?);
+    }
+
+    let cluster = DatabaseCluster {
+        primary_nodes,
+        secondary_nodes,
+    };
+    info!("initialize_db returning: {:?}", cluster);
+    Ok(cluster)
+}
+
+async fn connect_db(uri_env: &str) -> Result<Graph, DbError> {
+    info!("Attempting to connect to Neo4j at {}", uri_env);
+    let neo4j = &config::get().neo4j;
+
+    let graph = Graph::new(uri_env, &neo4j.user, &neo4j.password).await.map_err(|e| {
+        error!("Connection to Neo4j failed at {}: {:?}", uri_env, e);
+        DbError::Neo4jError(e)
+    })?;
+
+    info!("Successfully connected to Neo4j at {}", uri_env);
+    Ok(graph)
+}
+
+
+pub async fn get_db() -> Result<&'static Graph, DbError> {
+    info!("get_db called");
+    if let Some(cluster) = DB.get() {
+        info!("get_db returning primary node");
+        return Ok(&cluster.primary_nodes[0]);
+    }
+
+    let cluster = initialize_db().await?;
+    DB.set(cluster).map_err(|_| {
+        error!("Failed to initialize DB");
+        DbError::OtherError("Failed to initialize DB".into())
+    })?;
+
+    info!("get_db returning newly initialized primary node");
+    Ok(&DB.get().unwrap().primary_nodes[0])
+}
+
+#[allow(dead_code)]
+pub async fn get_read_db(number:usize) -> Result<&'static Graph, DbError> {
+    info!("get_read_db called");
+    if let Some(cluster) = DB.get() {
+        info!("get_read_db returning secondary node");
+
+        return Ok(&cluster.secondary_nodes[0]);
+    }
+
+    let cluster = initialize_db().await?;
+    DB.set(cluster).map_err(|_| {
+        error!("Failed to initialize DB");
+        DbError::OtherError("Failed to initialize DB".into())
+    })?;
+
+    info!("get_read_db returning newly initialized secondary node");
+    Ok(&DB.get().unwrap().secondary_nodes[number])
+}
+
+// Whether the node answers a trivial query within a few seconds
+pub async fn reachable(graph: &Graph) -> bool {
+    let probe = async {
+        let mut result = graph.execute(query("RETURN 1 AS ok")).await?;
+        result.next().await
+    };
+    matches!(tokio::time::timeout(std::time::Duration::from_secs(5), probe).await, Ok(Ok(Some(_))))
+}
+
+// Runs a trivial query against every node of the cluster and reports reachability and
+// round-trip latency. Each node gets a few seconds before it counts as down.
+pub async fn node_health() -> Value {
+    let Some(cluster) = DB.get() else {
+        return json!({ "initialized": false, "nodes": [] });
+    };
+
+    let nodes = cluster.primary_nodes.iter().enumerate().map(|(i, g)| ("primary", i, g))
+        .chain(cluster.secondary_nodes.iter().enumerate().map(|(i, g)| ("secondary", i, g)));
+
+    // Probe all nodes at once so one dead node doesn't delay the whole report
+    let mut probes = tokio::task::JoinSet::new();
+    for (position, (role, index, graph)) in nodes.enumerate() {
+        probes.spawn(async move {
+            let start = std::time::Instant::now();
+            let probe = async {
+                let mut result = graph.execute(query("RETURN 1 AS ok")).await?;
+                result.next().await
+            };
+            let (healthy, error) = match tokio::time::timeout(std::time::Duration::from_secs(3), probe).await {
+                Ok(Ok(_)) => (true, None),
+                Ok(Err(e)) => (false, Some(e.to_string())),
+                Err(_) => (false, Some("timed out".to_string())),
+            };
+            (position, json!({
+                "role": role,
+                "index": index,
+                "healthy": healthy,
+                "latency_ms": start.elapsed().as_secs_f64() * 1000.0,
+                "error": error
+            }))
+        });
+    }
+
+    let mut health: Vec<(usize, Value)> = probes.join_all().await;
+    health.sort_by_key(|(position, _)| *position);
+    let health: Vec<Value> = health.into_iter().map(|(_, node)| node).collect();
+    json!({ "initialized": true, "nodes": health })
+}
+
+#[derive(Clone)]
+pub struct DatabaseCluster {
+    primary_nodes: Vec<Graph>,
+    secondary_nodes: Vec<Graph>,
+}
+
+impl std::fmt::Debug for DatabaseCluster {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DatabaseCluster")
+            .field("primary_nodes_count", &self.primary_nodes.len())
+            .field("secondary_nodes_count", &self.secondary_nodes.len())
+            .finish()
+    }
+}
+
+
+
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Neo4jError(e) => write!(f, "Neo4j error: {}", e),
+            DbError::ConnectionError(e) => write!(f, "Connection error: {}", e),
+            DbError::OtherError(e) => write!(f, "Error: {}", e),
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,195 @@
+use log::info;
+use serde_json::Value;
+use crate::config;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+// Remembers recently ingested message ids so that retried batches from gateways are
+// acknowledged as duplicates instead of being written twice
+static SEEN: LazyLock<Mutex<Seen>> = LazyLock::new(|| Mutex::new(Seen::default()));
+
+// Keys with the time they were marked, and the same in marking order so expired keys are
+// dropped from the front without scanning the rest
+#[derive(Default)]
+struct Seen {
+    keys: HashMap<String, Instant>,
+    order: VecDeque<(Instant, String)>,
+}
+
+impl Seen {
+    fn expire(&mut self, now: Instant, window: Duration) {
+        while self.order.front().is_some_and(|(at, _)| now.duration_since(*at) >= window) {
+            let Some((at, key)) = self.order.pop_front() else { break };
+            // A forgotten and re-marked key has a newer entry further back
+            if self.keys.get(&key) == Some(&at) {
+                self.keys.remove(&key);
+            }
+        }
+    }
+
+    // Marks `key` as seen at `now` and returns true if it already was. Keeps at most
+    // `capacity` entries by forgetting the oldest ones.
+    fn check_and_mark(&mut self, key: &str, now: Instant, capacity: usize) -> bool {
+        if self.keys.contains_key(key) {
+            return true;
+        }
+        self.keys.insert(key.to_string(), now);
+        self.order.push_back((now, key.to_string()));
+        while self.order.len() > capacity {
+            let Some((at, key)) = self.order.pop_front() else { break };
+            if self.keys.get(&key) == Some(&at) {
+                self.keys.remove(&key);
+            }
+        }
+        false
+    }
+
+    fn forget(&mut self, key: &str) {
+        self.keys.remove(key);
+    }
+}
+
+fn seen() -> std::sync::MutexGuard<'static, Seen> {
+    SEEN.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn window() -> Duration {
+    Duration::from_secs(config::get().ingest.dedupe_window_secs)
+}
+
+fn capacity() -> usize {
+    config::get().ingest.dedupe_max_keys
+}
+
+// Content hash of a JSON value. serde_json keeps object keys sorted, so the same
+// record always serialises to the same string regardless of the sender's key order.
+pub fn content_hash(value: &Value) -> String {
+    format!("{:x}", Sha256::digest(value.to_string().as_bytes()))
+}
+
+// The client supplied `message_id`, or the content hash when there is none
+pub fn record_key(record: &Value) -> String {
+    match record.get("message_id").and_then(Value::as_str) {
+        Some(id) => format!("record:{}", id),
+        None => format!("hash:{}", content_hash(record)),
+    }
+}
+
+// Marks `key` as seen and returns true if it was already seen within the window
+pub fn check_and_mark(key: &str) -> bool {
+    let window = window();
+    if window.is_zero() {
+        return false;
+    }
+    let now = Instant::now();
+    let mut seen = seen();
+    seen.expire(now, window);
+    seen.check_and_mark(key, now, capacity())
+}
+
+// Undoes `check_and_mark` for keys whose batch could not be stored, so a retry is accepted
+pub fn forget(keys: &[String]) {
+    let mut seen = seen();
+    for key in keys {
+        seen.forget(key);
+    }
+}
+
+// Splits records into new ones and the number of duplicates, returning the keys that were marked
+pub fn filter_records(records: &[Value]) -> (Vec<Value>, Vec<String>, usize) {
+    let mut fresh = Vec::new();
+    let mut marked = Vec::new();
+    let mut duplicates = 0;
+    // Hashing happens before the lock, which is then taken once for the whole batch
+    let keys: Vec<String> = records.iter().map(record_key).collect();
+    let (window, capacity) = (window(), capacity());
+    let now = Instant::now();
+    let mut seen = seen();
+    seen.expire(now, window);
+    for (record, key) in records.iter().zip(keys) {
+        if !window.is_zero() && seen.check_and_mark(&key, now, capacity) {
+            duplicates += 1;
+        } else {
+            fresh.push(record.clone());
+            marked.push(key);
+        }
+    }
+    drop(seen);
+    if duplicates > 0 {
+        info!("Dropped {} duplicate records", duplicates);
+    }
+    (fresh, marked, duplicates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+    const CAPACITY: usize = 100;
+
+    #[test]
+    fn marks_keys_once_within_the_window() {
+        let mut seen = Seen::default();
+        let start = Instant::now();
+        assert!(!seen.check_and_mark("a", start, CAPACITY));
+        assert!(seen.check_and_mark("a", start + Duration::from_secs(10), CAPACITY));
+        assert!(!seen.check_and_mark("b", start + Duration::from_secs(10), CAPACITY));
+    }
+
+    #[test]
+    fn expires_keys_after_the_window() {
+        let mut seen = Seen::default();
+        let start = Instant::now();
+        seen.check_and_mark("old", start, CAPACITY);
+        seen.check_and_mark("new", start + Duration::from_secs(30), CAPACITY);
+
+        seen.expire(start + WINDOW, WINDOW);
+        assert!(!seen.keys.contains_key("old"));
+        assert!(seen.keys.contains_key("new"));
+        assert_eq!(seen.order.len(), 1);
+
+        assert!(!seen.check_and_mark("old", start + WINDOW, CAPACITY));
+    }
+
+    #[test]
+    fn a_forgotten_key_keeps_its_new_mark_when_the_old_one_expires() {
+        let mut seen = Seen::default();
+        let start = Instant::now();
+        seen.check_and_mark("a", start, CAPACITY);
+        seen.forget("a");
+        assert!(!seen.check_and_mark("a", start + Duration::from_secs(30), CAPACITY));
+
+        seen.expire(start + WINDOW, WINDOW);
+        assert!(seen.check_and_mark("a", start + WINDOW, CAPACITY));
+
+        seen.expire(start + WINDOW + Duration::from_secs(30), WINDOW);
+        assert!(seen.keys.is_empty());
+        assert!(seen.order.is_empty());
+    }
+
+    #[test]
+    fn evicts_the_oldest_keys_beyond_capacity() {
+        let mut seen = Seen::default();
+        let start = Instant::now();
+        for (i, key) in ["a", "b", "c"].into_iter().enumerate() {
+            seen.check_and_mark(key, start + Duration::from_secs(i as u64), 2);
+        }
+        assert_eq!(seen.keys.len(), 2);
+        assert!(!seen.keys.contains_key("a"));
+        assert!(seen.check_and_mark("c", start, 2));
+        assert!(!seen.check_and_mark("a", start, 2));
+        assert!(!seen.keys.contains_key("b"));
+    }
+
+    #[test]
+    fn record_keys_prefer_the_message_id() {
+        assert_eq!(record_key(&json!({ "message_id": "m1", "uuid": "a" })), "record:m1");
+        let hashed = record_key(&json!({ "uuid": "a", "color": "red" }));
+        assert!(hashed.starts_with("hash:"));
+        assert_eq!(hashed, record_key(&json!({ "color": "red", "uuid": "a" })));
+    }
+}
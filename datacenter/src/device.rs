@@ -0,0 +1,328 @@
+use log::{info, error, warn};
+use crate::config;
+use crate::metrics;
+use crate::models::Device;
+use serde::{Deserialize, Serialize};
+use neo4rs::{Graph, BoltType, query};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+// What happens to readings from UUIDs that are not registered as an active device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownDevicePolicy {
+    Accept,
+    Reject,
+    Quarantine,
+}
+
+impl UnknownDevicePolicy {
+    pub fn from_config() -> Self {
+        config::get().ingest.unknown_device_policy
+    }
+}
+
+// Extracts the writable device properties from a JSON object. `require_name` is set
+// for registrations, updates may only carry the fields that change.
+fn device_properties(device: &Value, require_name: bool) -> Result<HashMap<String, BoltType>, String> {
+    let obj = device.as_object().ok_or("'device' must be a JSON object")?;
+    let mut props = HashMap::<String, BoltType>::new();
+
+    for (key, prop) in [("name", "name"), ("location", "location"), ("type", "device_type"), ("owner", "owner")] {
+        if let Some(value) = obj.get(key) {
+            let value = value.as_str().ok_or(format!("'{}' must be a string", key))?;
+            props.insert(prop.to_string(), value.to_string().into());
+        }
+    }
+
+    if let Some(tags) = obj.get("tags") {
+        let tags = tags.as_array().ok_or("'tags' must be an array of strings")?;
+        let mut tag_list = Vec::new();
+        for tag in tags {
+            tag_list.push(tag.as_str().ok_or("'tags' must be an array of strings")?.to_string());
+        }
+        props.insert("tags".to_string(), tag_list.into());
+    }
+
+    if let Some(interval) = obj.get("reporting_interval") {
+        let interval = interval.as_i64().filter(|i| *i > 0)
+            .ok_or("'reporting_interval' must be a positive number of seconds")?;
+        props.insert("reporting_interval".to_string(), interval.into());
+    }
+
+    if require_name && !props.contains_key("name") {
+        return Err("'name' is required to register a device".to_string());
+    }
+    Ok(props)
+}
+
+fn device_id(device: &Value) -> Result<String, String> {
+    device.get("id")
+        .and_then(Value::as_str)
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| "'id' is required and must be a non-empty string".to_string())
+}
+
+pub async fn register_device(device: &Value, graph: &Graph) -> Result<String, String> {
+    let id = device_id(device)?;
+    let props = device_properties(device, true)?;
+
+    let register_query = query(r#"
+        OPTIONAL MATCH (existing:Device {id: $id})
+        WITH existing
+        WHERE existing IS NULL
+        CREATE (d:Device {id: $id})
+        SET d += $props,
+            d.status = 'active',
+            d.registered_at = toString(datetime()),
+            d.updated_at = toString(datetime())
+        RETURN d.id AS id
+    "#)
+    .param("id", id.clone())
+    .param("props", props);
+
+    match graph.execute(register_query).await {
+        Ok(mut result) => match result.next().await {
+            Ok(Some(_)) => {
+                info!("Registered device {}", id);
+                Ok(id)
+            },
+            Ok(None) => Err(format!("Device '{}' is already registered", id)),
+            Err(e) => Err(format!("Failed to register device '{}': {}", id, e)),
+        },
+        Err(e) => {
+            error!("Failed to execute Neo4j query: {}", e);
+            Err(format!("Failed to register device '{}': {}", id, e))
+        }
+    }
+}
+
+pub async fn update_device(device: &Value, graph: &Graph) -> Result<String, String> {
+    let id = device_id(device)?;
+    let props = device_properties(device, false)?;
+    if props.is_empty() {
+        return Err(format!("No fields to update for device '{}'", id));
+    }
+
+    let update_query = query(r#"
+        MATCH (d:Device {id: $id})
+        SET d += $props,
+            d.updated_at = toString(datetime())
+        RETURN d.id AS id
+    "#)
+    .param("id", id.clone())
+    .param("props", props);
+
+    match graph.execute(update_query).await {
+        Ok(mut result) => match result.next().await {
+            Ok(Some(_)) => {
+                info!("Updated device {}", id);
+                Ok(id)
+            },
+            Ok(None) => Err(format!("Device '{}' is not registered", id)),
+            Err(e) => Err(format!("Failed to update device '{}': {}", id, e)),
+        },
+        Err(e) => {
+            error!("Failed to execute Neo4j query: {}", e);
+            Err(format!("Failed to update device '{}': {}", id, e))
+        }
+    }
+}
+
+pub async fn decommission_device(id: &str, graph: &Graph) -> Result<String, String> {
+    let decommission_query = query(r#"
+        MATCH (d:Device {id: $id})
+        WHERE d.status = 'active'
+        SET d.status = 'decommissioned',
+            d.decommissioned_at = toString(datetime()),
+            d.updated_at = toString(datetime())
+        RETURN d.id AS id
+    "#)
+    .param("id", id);
+
+    match graph.execute(decommission_query).await {
+        Ok(mut result) => match result.next().await {
+            Ok(Some(_)) => {
+                info!("Decommissioned device {}", id);
+                Ok(id.to_string())
+            },
+            Ok(None) => Err(format!("Device '{}' is not registered or already decommissioned", id)),
+            Err(e) => Err(format!("Failed to decommission device '{}': {}", id, e)),
+        },
+        Err(e) => {
+            error!("Failed to execute Neo4j query: {}", e);
+            Err(format!("Failed to decommission device '{}': {}", id, e))
+        }
+    }
+}
+
+fn device_from_row(row: &neo4rs::Row) -> Device {
+    Device {
+        id: row.get("id").unwrap_or_default(),
+        name: row.get("name").ok(),
+        location: row.get("location").ok(),
+        device_type: row.get("device_type").ok(),
+        owner: row.get("owner").ok(),
+        tags: row.get("tags").unwrap_or_default(),
+        reporting_interval: row.get("reporting_interval").ok(),
+        status: row.get("status").unwrap_or_default(),
+        registered_at: row.get("registered_at").ok(),
+        updated_at: row.get("updated_at").ok(),
+        decommissioned_at: row.get("decommissioned_at").ok(),
+    }
+}
+
+const DEVICE_RETURN: &str = r#"
+        RETURN d.id AS id,
+               d.name AS name,
+               d.location AS location,
+               d.device_type AS device_type,
+               d.owner AS owner,
+               d.tags AS tags,
+               d.reporting_interval AS reporting_interval,
+               d.status AS status,
+               d.registered_at AS registered_at,
+               d.updated_at AS updated_at,
+               d.decommissioned_at AS decommissioned_at
+"#;
+
+pub async fn get_device(id: &str, graph: &Graph) -> Option<Device> {
+    let _timer = metrics::query_timer("get_device");
+    let get_query = query(&format!("MATCH (d:Device {{id: $id}}) {}", DEVICE_RETURN))
+        .param("id", id);
+
+    match graph.execute(get_query).await {
+        Ok(mut result) => match result.next().await {
+            Ok(Some(row)) => Some(device_from_row(&row)),
+            _ => None,
+        },
+        Err(e) => {
+            error!("Failed to execute Neo4j query: {}", e);
+            None
+        }
+    }
+}
+
+// The registered devices among `ids`, in one query
+pub async fn get_devices(ids: &[String], graph: &Graph) -> Result<HashMap<String, Device>, String> {
+    let _timer = metrics::query_timer("get_devices");
+    let get_query = query(&format!("MATCH (d:Device) WHERE d.id IN $ids {}", DEVICE_RETURN))
+        .param("ids", ids.to_vec());
+
+    match graph.execute(get_query).await {
+        Ok(mut result) => {
+            let mut devices = HashMap::new();
+            while let Ok(Some(row)) = result.next().await {
+                let device = device_from_row(&row);
+                devices.insert(device.id.clone(), device);
+            }
+            Ok(devices)
+        },
+        Err(e) => {
+            error!("Failed to execute Neo4j query: {}", e);
+            Err(format!("Failed to look up devices: {}", e))
+        }
+    }
+}
+
+// Lists devices, optionally filtered by status, location, type, owner and tag
+pub async fn list_devices(filters: &Value, limit: Option<usize>, graph: &Graph) -> Option<Vec<Device>> {
+    let _timer = metrics::query_timer("list_devices");
+    let filter = |key: &str| filters.get(key).and_then(Value::as_str).unwrap_or("").to_string();
+
+    let list_query = query(&format!(r#"
+        MATCH (d:Device)
+        WHERE ($status = '' OR d.status = $status)
+          AND ($location = '' OR d.location = $location)
+          AND ($device_type = '' OR d.device_type = $device_type)
+          AND ($owner = '' OR d.owner = $owner)
+          AND ($tag = '' OR $tag IN coalesce(d.tags, []))
+        WITH d
+        ORDER BY d.id
+        {}
+        {}
+    "#, if limit.is_some() { "LIMIT $limit" } else { "" }, DEVICE_RETURN))
+    .param("status", filter("status"))
+    .param("location", filter("location"))
+    .param("device_type", filter("type"))
+    .param("owner", filter("owner"))
+    .param("tag", filter("tag"))
+    .param("limit", limit.unwrap_or(0) as i64);
+
+    match graph.execute(list_query).await {
+        Ok(mut result) => {
+            let mut devices = Vec::new();
+            while let Ok(Some(row)) = result.next().await {
+                devices.push(device_from_row(&row));
+            }
+            Some(devices)
+        },
+        Err(e) => {
+            error!("Failed to execute Neo4j query: {}", e);
+            None
+        }
+    }
+}
+
+// Returns the subset of `ids` that belong to active, registered devices
+pub async fn active_device_ids(ids: &[String], graph: &Graph) -> Result<HashSet<String>, String> {
+    let _timer = metrics::query_timer("active_device_ids");
+    let active_query = query(r#"
+        MATCH (d:Device)
+        WHERE d.id IN $ids AND d.status = 'active'
+        RETURN d.id AS id
+    "#)
+    .param("ids", ids.to_vec());
+
+    match graph.execute(active_query).await {
+        Ok(mut result) => {
+            let mut known = HashSet::new();
+            while let Ok(Some(row)) = result.next().await {
+                if let Ok(id) = row.get::<String>("id") {
+                    known.insert(id);
+                }
+            }
+            Ok(known)
+        },
+        Err(e) => {
+            error!("Failed to execute Neo4j query: {}", e);
+            Err(format!("Failed to look up registered devices: {}", e))
+        }
+    }
+}
+
+// Applies the unknown device policy to an ingest batch. Returns the records that may be
+// written; rejected records are logged and, for the quarantine policy, handed to the
+// quarantine store.
+pub async fn apply_device_policy(records: Vec<(String, Value)>, policy: UnknownDevicePolicy, source: &str, graph: &Graph) -> Result<Vec<Value>, String> {
+    if policy == UnknownDevicePolicy::Accept {
+        return Ok(records.into_iter().map(|(_, record)| record).collect());
+    }
+
+    let ids: Vec<String> = records.iter()
+        .filter_map(|(_, r)| r.get("uuid").and_then(Value::as_str))
+        .map(str::to_string)
+        .collect();
+    let known = active_device_ids(&ids, graph).await?;
+
+    let (accepted, unknown): (Vec<_>, Vec<_>) = records.into_iter().partition(|(_, r)| {
+        r.get("uuid").and_then(Value::as_str).is_some_and(|id| known.contains(id))
+    });
+
+    if !unknown.is_empty() {
+        match policy {
+            UnknownDevicePolicy::Reject => {
+                for (_, record) in &unknown {
+                    warn!("Rejected record from unregistered device: {}", crate::logging::payload(record));
+                }
+            },
+            UnknownDevicePolicy::Quarantine => {
+                crate::quarantine::quarantine(&unknown, "unregistered device", source, graph).await?;
+            },
+            UnknownDevicePolicy::Accept => {}
+        }
+    }
+
+    Ok(accepted.into_iter().map(|(_, record)| record).collect())
+}
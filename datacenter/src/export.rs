@@ -0,0 +1,397 @@
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::{info, warn};
+use neo4rs::Graph;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use serde_json::{Value, json};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::config;
+use crate::query::{send_readings, Reading, ReadingFilter};
+
+// Export of readings for analysis. Every format uses the column layout of the ingest
+// payload, so an exported CSV or JSONL file can be imported again as it is.
+const COLUMNS: [&str; 7] = ["uuid", "color", "sensor_data.temperature", "sensor_data.humidity", "timestamp", "energy_consume", "energy_cost"];
+
+// Rows per Parquet row group
+const PARQUET_BATCH: usize = 8192;
+// Raw bytes per `export_chunk` response, before base64
+const DEFAULT_CHUNK_SIZE: u64 = 32 * 1024;
+// Files written by the `export` command start with this; only they are pruned, never the
+// backups taken before a reset
+const EXPORT_PREFIX: &str = "readings-";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            "parquet" => Ok(ExportFormat::Parquet),
+            other => Err(format!("unknown export format '{}', expected csv, jsonl or parquet", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportRequest {
+    pub format: ExportFormat,
+    pub filter: ReadingFilter,
+}
+
+impl ExportRequest {
+    // Parses `{"format": "csv" | "jsonl" | "parquet", "from", "to", "devices", "colors"}`
+    pub fn from_json(json: &Value) -> Result<ExportRequest, String> {
+        let format = json.get("format").and_then(Value::as_str).unwrap_or("csv").parse()?;
+        Ok(ExportRequest { format, filter: ReadingFilter::from_json(json)? })
+    }
+}
+
+enum RowWriter<W: Write + Send> {
+    Csv(csv::Writer<W>),
+    Jsonl(W),
+    Parquet { writer: ArrowWriter<W>, pending: Vec<Reading> },
+}
+
+impl<W: Write + Send> RowWriter<W> {
+    fn new(format: ExportFormat, out: W) -> Result<Self, String> {
+        match format {
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(out);
+                writer.write_record(COLUMNS).map_err(|e| e.to_string())?;
+                Ok(RowWriter::Csv(writer))
+            },
+            ExportFormat::Jsonl => Ok(RowWriter::Jsonl(out)),
+            ExportFormat::Parquet => {
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                    .build();
+                let writer = ArrowWriter::try_new(out, parquet_schema(), Some(properties)).map_err(|e| e.to_string())?;
+                Ok(RowWriter::Parquet { writer, pending: Vec::with_capacity(PARQUET_BATCH) })
+            },
+        }
+    }
+
+    fn write(&mut self, reading: Reading) -> Result<(), String> {
+        match self {
+            RowWriter::Csv(writer) => {
+                let number = |n: Option<f64>| n.map(|n| n.to_string()).unwrap_or_default();
+                writer.write_record([
+                    reading.uuid,
+                    reading.color.unwrap_or_default(),
+                    number(reading.temperature),
+                    number(reading.humidity),
+                    reading.timestamp,
+                    number(reading.energy_consume),
+                    number(reading.energy_cost),
+                ]).map_err(|e| e.to_string())
+            },
+            RowWriter::Jsonl(out) => writeln!(out, "{}", json!(reading.to_record())).map_err(|e| e.to_string()),
+            RowWriter::Parquet { writer, pending } => {
+                pending.push(reading);
+                if pending.len() >= PARQUET_BATCH {
+                    writer.write(&record_batch(pending)?).map_err(|e| e.to_string())?;
+                    pending.clear();
+                }
+                Ok(())
+            },
+        }
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self {
+            RowWriter::Csv(mut writer) => writer.flush().map_err(|e| e.to_string()),
+            RowWriter::Jsonl(mut out) => out.flush().map_err(|e| e.to_string()),
+            RowWriter::Parquet { mut writer, pending } => {
+                if !pending.is_empty() {
+                    writer.write(&record_batch(&pending)?).map_err(|e| e.to_string())?;
+                }
+                writer.close().map(|_| ()).map_err(|e| e.to_string())
+            },
+        }
+    }
+}
+
+fn parquet_schema() -> Arc<Schema> {
+    Arc::new(Schema::new(COLUMNS.map(|name| {
+        let data_type = match name {
+            "uuid" | "color" | "timestamp" => DataType::Utf8,
+            _ => DataType::Float64,
+        };
+        Field::new(name, data_type, name != "uuid" && name != "timestamp")
+    }).to_vec()))
+}
+
+fn record_batch(readings: &[Reading]) -> Result<RecordBatch, String> {
+    let strings = |get: fn(&Reading) -> Option<&str>| -> ArrayRef {
+        Arc::new(readings.iter().map(get).collect::<StringArray>())
+    };
+    let numbers = |get: fn(&Reading) -> Option<f64>| -> ArrayRef {
+        Arc::new(readings.iter().map(get).collect::<Float64Array>())
+    };
+    RecordBatch::try_new(parquet_schema(), vec![
+        strings(|r| Some(r.uuid.as_str())),
+        strings(|r| r.color.as_deref()),
+        numbers(|r| r.temperature),
+        numbers(|r| r.humidity),
+        strings(|r| Some(r.timestamp.as_str())),
+        numbers(|r| r.energy_consume),
+        numbers(|r| r.energy_cost),
+    ]).map_err(|e| format!("Failed to build Parquet batch: {}", e))
+}
+
+// Writes the matching readings to `out` and returns the number of rows. The rows are
+// written on a blocking thread while the query streams them in.
+pub async fn write_readings<W: Write + Send + 'static>(request: &ExportRequest, out: W, graph: &Graph) -> Result<usize, String> {
+    let (tx, mut rx) = mpsc::channel(PARQUET_BATCH);
+    let format = request.format;
+    let writer = tokio::task::spawn_blocking(move || {
+        let mut writer = RowWriter::new(format, out)?;
+        while let Some(reading) = rx.blocking_recv() {
+            writer.write(reading)?;
+        }
+        writer.finish()
+    });
+    let sent = send_readings(&request.filter, graph, &tx).await;
+    drop(tx);
+    // A failed writer drops the receiver, which is what stopped the query; report its error
+    writer.await.map_err(|e| format!("Export writer failed: {}", e))??;
+    sent
+}
+
+// Exports to `path`, or to a new file in export.dir, and describes the result
+pub async fn to_file(request: &ExportRequest, path: Option<PathBuf>, graph: &Graph) -> Result<Value, String> {
+    let in_export_dir = path.is_none();
+    let path = match path {
+        Some(path) => path,
+        None => {
+            let dir = &config::get().export.dir;
+            tokio::fs::create_dir_all(dir).await
+                .map_err(|e| format!("Failed to create export directory {}: {}", dir.display(), e))?;
+            // The file name is the id `export_chunk` hands the file out under, so it must not be guessable
+            let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%3fZ");
+            dir.join(format!("{}{}-{}.{}", EXPORT_PREFIX, timestamp, Uuid::new_v4().simple(), request.format.extension()))
+        },
+    };
+    let file = tokio::fs::File::create(&path).await
+        .map_err(|e| format!("Failed to create export file {}: {}", path.display(), e))?
+        .into_std().await;
+    let rows = match write_readings(request, BufWriter::new(file), graph).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            // A half-written export in export.dir would only be handed out by `export_chunk`
+            if in_export_dir {
+                let _ = tokio::fs::remove_file(&path).await;
+            }
+            return Err(format!("Failed to export readings to {}: {}", path.display(), e));
+        },
+    };
+    let bytes = tokio::fs::metadata(&path).await.map(|m| m.len()).unwrap_or(0);
+    info!("Exported {} readings to {} ({} bytes)", rows, path.display(), bytes);
+    if in_export_dir {
+        prune_exports().await;
+    }
+
+    Ok(json!({
+        "file": path.display().to_string(),
+        "format": request.format.extension(),
+        "rows": rows,
+        "bytes": bytes
+    }))
+}
+
+// Exports into export.dir for the `export` command. The file name is the id under which
+// `export_chunk` hands the file out.
+pub async fn to_export_dir(request: &ExportRequest, graph: &Graph) -> Result<Value, String> {
+    let mut result = to_file(request, None, graph).await?;
+    let id = Path::new(result["file"].as_str().unwrap_or_default())
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let bytes = result["bytes"].as_u64().unwrap_or(0);
+    result["id"] = json!(id);
+    result["chunk_size"] = json!(DEFAULT_CHUNK_SIZE);
+    result["chunks"] = json!(bytes.div_ceil(DEFAULT_CHUNK_SIZE));
+    Ok(result)
+}
+
+// Applies export.retention_secs and export.max_files to the files of the `export` command
+async fn prune_exports() {
+    let settings = &config::get().export;
+    let (dir, retention, max_files) = (settings.dir.clone(), Duration::from_secs(settings.retention_secs), settings.max_files);
+    match tokio::task::spawn_blocking(move || prune(&dir, retention, max_files)).await {
+        Ok(0) => {},
+        Ok(removed) => info!("Removed {} expired exports", removed),
+        Err(e) => warn!("Pruning exports failed: {}", e),
+    }
+}
+
+// Removes exports older than `retention` and all but the newest `max_files`; returns how
+// many files were removed
+fn prune(dir: &Path, retention: Duration, max_files: usize) -> usize {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    let mut exports: Vec<(PathBuf, Duration)> = entries.flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(EXPORT_PREFIX))
+        .filter_map(|entry| {
+            let age = entry.metadata().and_then(|m| m.modified()).ok()?.elapsed().unwrap_or_default();
+            Some((entry.path(), age))
+        })
+        .collect();
+    exports.sort_by_key(|(_, age)| *age);
+    exports.into_iter().enumerate()
+        .filter(|(position, (_, age))| *position >= max_files || *age > retention)
+        .filter(|(_, (path, _))| match std::fs::remove_file(path) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Failed to remove expired export {}: {}", path.display(), e);
+                false
+            },
+        })
+        .count()
+}
+
+// Reads part `index` (0-based) of an export file as base64
+pub async fn read_chunk(id: &str, index: u64, chunk_size: Option<u64>) -> Result<Value, String> {
+    let settings = &config::get().export;
+    let chunk_size = chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+    if chunk_size == 0 || chunk_size > settings.max_chunk_size {
+        return Err(format!("'chunk_size' must be between 1 and {}", settings.max_chunk_size));
+    }
+    let (dir, id) = (settings.dir.clone(), id.to_string());
+    tokio::task::spawn_blocking(move || read_part(&dir, &id, index, chunk_size)).await
+        .map_err(|e| format!("Failed to read export: {}", e))?
+}
+
+fn read_part(dir: &Path, id: &str, index: u64, chunk_size: u64) -> Result<Value, String> {
+    // Only plain file names, nothing outside export.dir
+    if id.is_empty() || Path::new(id).file_name().and_then(|name| name.to_str()) != Some(id) {
+        return Err(format!("Invalid export id '{}'", id));
+    }
+    let path = dir.join(id);
+    let mut file = File::open(&path).map_err(|_| format!("No export with id '{}'", id))?;
+    let size = file.metadata().map_err(|e| e.to_string())?.len();
+    let chunks = size.div_ceil(chunk_size);
+    if index >= chunks.max(1) {
+        return Err(format!("Chunk {} is out of range, export '{}' has {} chunks", index, id, chunks));
+    }
+
+    let mut data = Vec::with_capacity(chunk_size as usize);
+    file.seek(SeekFrom::Start(index * chunk_size))
+        .and_then(|_| file.take(chunk_size).read_to_end(&mut data))
+        .map_err(|e| format!("Failed to read export {}: {}", id, e))?;
+    Ok(json!({
+        "id": id,
+        "index": index,
+        "chunks": chunks,
+        "last": index + 1 >= chunks,
+        "data": BASE64.encode(&data)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("datacenter-export-{}", Uuid::new_v4().simple()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(dir: &Path, name: &str, data: &[u8], age: Duration) {
+        let file = File::create(dir.join(name)).unwrap();
+        (&file).write_all(data).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    fn decode(part: &Value) -> Vec<u8> {
+        BASE64.decode(part["data"].as_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn reads_every_chunk_including_a_short_last_one() {
+        let dir = temp_dir();
+        write(&dir, "readings-1.csv", b"0123456789", Duration::ZERO);
+
+        let first = read_part(&dir, "readings-1.csv", 0, 4).unwrap();
+        assert_eq!((decode(&first), first["chunks"].clone(), first["last"].clone()), (b"0123".to_vec(), json!(3), json!(false)));
+        let last = read_part(&dir, "readings-1.csv", 2, 4).unwrap();
+        assert_eq!((decode(&last), last["last"].clone()), (b"89".to_vec(), json!(true)));
+        assert_eq!(
+            read_part(&dir, "readings-1.csv", 3, 4),
+            Err("Chunk 3 is out of range, export 'readings-1.csv' has 3 chunks".to_string()),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn an_empty_export_has_one_empty_chunk() {
+        let dir = temp_dir();
+        write(&dir, "readings-2.csv", b"", Duration::ZERO);
+        let part = read_part(&dir, "readings-2.csv", 0, 4).unwrap();
+        assert_eq!((decode(&part), part["last"].clone()), (Vec::new(), json!(true)));
+        assert!(read_part(&dir, "readings-2.csv", 1, 4).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_ids_outside_the_export_dir() {
+        let dir = temp_dir();
+        for id in ["", "../secret", "sub/readings-1.csv", "/etc/passwd", ".."] {
+            assert_eq!(read_part(&dir, id, 0, 4), Err(format!("Invalid export id '{}'", id)));
+        }
+        assert_eq!(read_part(&dir, "readings-3.csv", 0, 4), Err("No export with id 'readings-3.csv'".to_string()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prunes_old_and_surplus_exports_but_keeps_reset_backups() {
+        let dir = temp_dir();
+        let hour = Duration::from_secs(3600);
+        write(&dir, "readings-new.csv", b"", Duration::ZERO);
+        write(&dir, "readings-older.csv", b"", hour);
+        write(&dir, "readings-oldest.csv", b"", 2 * hour);
+        write(&dir, "readings-expired.csv", b"", 48 * hour);
+        write(&dir, "reset-backup.jsonl", b"", 48 * hour);
+
+        assert_eq!(prune(&dir, 24 * hour, 2), 2);
+        let mut left: Vec<String> = std::fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, vec!["readings-new.csv", "readings-older.csv", "reset-backup.jsonl"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,289 @@
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::{ComplexObject, Context, EmptyMutation, EmptySubscription, InputObject, Object, Result, Schema};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use crate::command_handler::db;
+use crate::config;
+use crate::device;
+use crate::models::{Aggregate, Device, DeviceParams, GroupBy, ReadingRecord};
+use crate::query::{self, ReadingFilter};
+
+// GraphQL view of the sensor graph: devices, readings, colors and aggregates, with nested
+// selections between them. Everything resolves through query.rs and device.rs. Lists take
+// a `limit` and cost that many times their selection, so together with the depth limit a
+// single query cannot walk the whole graph. Aggregates scan every matching reading and cost
+// accordingly; per-device and per-color lookups are batched into one query per request.
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+// A scan over all readings
+const AGGREGATE_COST: usize = 100;
+// A share in one batched scan
+const BATCHED_COST: usize = 10;
+
+pub type SensorSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+static SCHEMA: LazyLock<SensorSchema> = LazyLock::new(|| {
+    let limits = &config::get().graphql;
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(limits.max_depth)
+        .limit_complexity(limits.max_complexity)
+        .finish()
+});
+
+pub async fn execute(request: async_graphql::Request) -> async_graphql::Response {
+    // A loader per request, so nothing is cached between requests
+    SCHEMA.execute(request.data(DataLoader::new(GraphLoader, tokio::spawn))).await
+}
+
+// The schema in SDL, for client code generators
+pub fn sdl() -> String {
+    SCHEMA.sdl()
+}
+
+/// Reading filters; omitted fields match everything
+#[derive(Debug, Clone, Default, InputObject)]
+struct ReadingFilterInput {
+    /// Earliest timestamp, compared as a string
+    from: Option<String>,
+    /// Latest timestamp, compared as a string
+    to: Option<String>,
+    /// Device UUIDs
+    devices: Option<Vec<String>>,
+    colors: Option<Vec<String>>,
+}
+
+impl ReadingFilterInput {
+    fn into_filter(self, limit: Option<usize>) -> ReadingFilter {
+        ReadingFilter {
+            from: self.from,
+            to: self.to,
+            devices: self.devices.unwrap_or_default(),
+            colors: self.colors.unwrap_or_default(),
+            limit,
+        }
+    }
+}
+
+fn check_limit(limit: usize) -> Result<usize> {
+    if limit == 0 || limit > MAX_LIMIT {
+        return Err(format!("'limit' must be between 1 and {}", MAX_LIMIT).into());
+    }
+    Ok(limit)
+}
+
+async fn readings(filter: &ReadingFilter) -> Result<Vec<ReadingRecord>> {
+    let mut readings = Vec::new();
+    query::for_each_reading(filter, db().await?, |reading| {
+        readings.push(reading.to_record());
+        Ok(())
+    }).await?;
+    Ok(readings)
+}
+
+// Batches the lookups below that every device or color of a list would otherwise run on
+// its own
+struct GraphLoader;
+
+// The aggregate of one device or color in a time range
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct AggregateKey {
+    group_by: GroupBy,
+    value: String,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+// The devices that reported a color
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ColorDevices(String);
+
+// The registered device with an id
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct DeviceKey(String);
+
+// The newest reading of a UUID
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct LatestReading(String);
+
+impl Loader<AggregateKey> for GraphLoader {
+    type Value = Aggregate;
+    type Error = String;
+
+    async fn load(&self, keys: &[AggregateKey]) -> Result<HashMap<AggregateKey, Aggregate>, String> {
+        // One grouped query per kind and time range
+        let mut batches: HashMap<(GroupBy, Option<String>, Option<String>), Vec<String>> = HashMap::new();
+        for key in keys {
+            batches.entry((key.group_by, key.from.clone(), key.to.clone())).or_default().push(key.value.clone());
+        }
+        let graph = db().await?;
+        let mut aggregates = HashMap::new();
+        for ((group_by, from, to), values) in batches {
+            let mut filter = ReadingFilter { from: from.clone(), to: to.clone(), ..ReadingFilter::default() };
+            match group_by {
+                GroupBy::Device => filter.devices = values,
+                GroupBy::Color => filter.colors = values,
+            }
+            for aggregate in query::aggregate_readings(&filter, Some(group_by), graph).await? {
+                if let Some(value) = aggregate.group.clone() {
+                    aggregates.insert(AggregateKey { group_by, value, from: from.clone(), to: to.clone() }, aggregate);
+                }
+            }
+        }
+        Ok(aggregates)
+    }
+}
+
+impl Loader<ColorDevices> for GraphLoader {
+    type Value = Vec<String>;
+    type Error = String;
+
+    async fn load(&self, keys: &[ColorDevices]) -> Result<HashMap<ColorDevices, Vec<String>>, String> {
+        let colors: Vec<String> = keys.iter().map(|ColorDevices(color)| color.clone()).collect();
+        let devices = query::devices_with_colors(&colors, db().await?).await?;
+        Ok(devices.into_iter().map(|(color, devices)| (ColorDevices(color), devices)).collect())
+    }
+}
+
+impl Loader<DeviceKey> for GraphLoader {
+    type Value = Device;
+    type Error = String;
+
+    async fn load(&self, keys: &[DeviceKey]) -> Result<HashMap<DeviceKey, Device>, String> {
+        let ids: Vec<String> = keys.iter().map(|DeviceKey(id)| id.clone()).collect();
+        let devices = device::get_devices(&ids, db().await?).await?;
+        Ok(devices.into_iter().map(|(id, device)| (DeviceKey(id), device)).collect())
+    }
+}
+
+impl Loader<LatestReading> for GraphLoader {
+    type Value = ReadingRecord;
+    type Error = String;
+
+    async fn load(&self, keys: &[LatestReading]) -> Result<HashMap<LatestReading, ReadingRecord>, String> {
+        let uuids: Vec<String> = keys.iter().map(|LatestReading(uuid)| uuid.clone()).collect();
+        let readings = query::latest_readings(&uuids, db().await?).await?;
+        Ok(readings.into_iter().map(|(uuid, reading)| (LatestReading(uuid), reading.to_record())).collect())
+    }
+}
+
+fn loader<'a>(ctx: &'a Context<'_>) -> &'a DataLoader<GraphLoader> {
+    ctx.data_unchecked::<DataLoader<GraphLoader>>()
+}
+
+async fn aggregate_of(ctx: &Context<'_>, group_by: GroupBy, value: &str, from: Option<String>, to: Option<String>) -> Result<Option<Aggregate>> {
+    let key = AggregateKey { group_by, value: value.to_string(), from, to };
+    Ok(loader(ctx).load_one(key).await?)
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Registered devices ordered by id; all given filters must match
+    #[graphql(complexity = "limit.saturating_mul(child_complexity)")]
+    async fn devices(&self, filter: Option<DeviceParams>, #[graphql(default_with = "DEFAULT_LIMIT")] limit: usize) -> Result<Vec<Device>> {
+        let limit = check_limit(limit)?;
+        Ok(device::list_devices(&serde_json::json!(filter.unwrap_or_default()), Some(limit), db().await?).await
+            .ok_or("Failed to list devices")?)
+    }
+
+    /// A registered device
+    async fn device(&self, id: String) -> Result<Option<Device>> {
+        Ok(device::get_device(&id, db().await?).await)
+    }
+
+    /// Readings matching the filter, oldest first
+    #[graphql(complexity = "limit.saturating_mul(child_complexity)")]
+    async fn readings(&self, filter: Option<ReadingFilterInput>, #[graphql(default_with = "DEFAULT_LIMIT")] limit: usize) -> Result<Vec<ReadingRecord>> {
+        let limit = check_limit(limit)?;
+        readings(&filter.unwrap_or_default().into_filter(Some(limit))).await
+    }
+
+    /// The latest reading of a device UUID, registered or not
+    async fn latest_reading(&self, ctx: &Context<'_>, uuid: String) -> Result<Option<ReadingRecord>> {
+        Ok(loader(ctx).load_one(LatestReading(uuid)).await?)
+    }
+
+    /// Colors that readings were reported with, by name
+    #[graphql(complexity = "AGGREGATE_COST + limit.saturating_mul(child_complexity)")]
+    async fn colors(&self, #[graphql(default_with = "DEFAULT_LIMIT")] limit: usize) -> Result<Vec<Color>> {
+        let limit = check_limit(limit)?;
+        let filter = ReadingFilter { limit: Some(limit), ..ReadingFilter::default() };
+        let groups = query::aggregate_readings(&filter, Some(GroupBy::Color), db().await?).await?;
+        Ok(groups.into_iter()
+            .filter_map(|group| Some(Color { name: group.group?, reading_count: group.readings }))
+            .collect())
+    }
+
+    /// Statistics over the matching readings, overall or per device or color
+    #[graphql(complexity = "AGGREGATE_COST + child_complexity")]
+    async fn aggregate(&self, filter: Option<ReadingFilterInput>, group_by: Option<GroupBy>) -> Result<Vec<Aggregate>> {
+        let filter = filter.unwrap_or_default().into_filter(None);
+        Ok(query::aggregate_readings(&filter, group_by, db().await?).await?)
+    }
+}
+
+#[ComplexObject]
+impl Device {
+    /// Readings of this device, oldest first
+    #[graphql(complexity = "limit.saturating_mul(child_complexity)")]
+    async fn readings(&self, from: Option<String>, to: Option<String>, #[graphql(default_with = "DEFAULT_LIMIT")] limit: usize) -> Result<Vec<ReadingRecord>> {
+        let limit = check_limit(limit)?;
+        readings(&ReadingFilter { from, to, devices: vec![self.id.clone()], colors: Vec::new(), limit: Some(limit) }).await
+    }
+
+    #[graphql(complexity = "BATCHED_COST + child_complexity")]
+    async fn latest_reading(&self, ctx: &Context<'_>) -> Result<Option<ReadingRecord>> {
+        Ok(loader(ctx).load_one(LatestReading(self.id.clone())).await?)
+    }
+
+    #[graphql(complexity = "BATCHED_COST + child_complexity")]
+    async fn aggregate(&self, ctx: &Context<'_>, from: Option<String>, to: Option<String>) -> Result<Option<Aggregate>> {
+        aggregate_of(ctx, GroupBy::Device, &self.id, from, to).await
+    }
+}
+
+#[ComplexObject]
+impl ReadingRecord {
+    /// The registered device that sent the reading, if it is registered
+    #[graphql(complexity = "BATCHED_COST + child_complexity")]
+    async fn device(&self, ctx: &Context<'_>) -> Result<Option<Device>> {
+        Ok(loader(ctx).load_one(DeviceKey(self.uuid.clone())).await?)
+    }
+}
+
+/// A color readings were reported with
+pub struct Color {
+    name: String,
+    reading_count: i64,
+}
+
+#[Object]
+impl Color {
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn reading_count(&self) -> i64 {
+        self.reading_count
+    }
+
+    /// Readings with this color, oldest first
+    #[graphql(complexity = "limit.saturating_mul(child_complexity)")]
+    async fn readings(&self, from: Option<String>, to: Option<String>, #[graphql(default_with = "DEFAULT_LIMIT")] limit: usize) -> Result<Vec<ReadingRecord>> {
+        let limit = check_limit(limit)?;
+        readings(&ReadingFilter { from, to, devices: Vec::new(), colors: vec![self.name.clone()], limit: Some(limit) }).await
+    }
+
+    /// UUIDs of the devices that reported this color
+    #[graphql(complexity = "BATCHED_COST")]
+    async fn devices(&self, ctx: &Context<'_>) -> Result<Vec<String>> {
+        Ok(loader(ctx).load_one(ColorDevices(self.name.clone())).await?.unwrap_or_default())
+    }
+
+    #[graphql(complexity = "BATCHED_COST + child_complexity")]
+    async fn aggregate(&self, ctx: &Context<'_>, from: Option<String>, to: Option<String>) -> Result<Option<Aggregate>> {
+        aggregate_of(ctx, GroupBy::Color, &self.name, from, to).await
+    }
+}
@@ -0,0 +1,675 @@
+use log::{info, warn, error};
+use neo4rs::Graph;
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Unexpected, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+use crate::config;
+use crate::ingest;
+use crate::migrations;
+use crate::quarantine;
+use crate::shutdown;
+use crate::status;
+
+// Bulk import of historical readings. The file is parsed on a blocking thread and streamed
+// in batches through validation, the device policy and the Neo4j write, like live ingest
+// minus the WAL and the dedupe window. After every batch a checkpoint records how far the
+// import got, so an interrupted import continues there when it is started again; that
+// already keeps records from being sent twice, and a large file would only push everything
+// else out of the dedupe window.
+
+// Imports started in this process, by id, for `import_status`
+static IMPORTS: LazyLock<Mutex<BTreeMap<String, ImportProgress>>> = LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+// Columns that hold numbers; everything else in a CSV file stays a string
+const NUMERIC_COLUMNS: &[&str] = &["energy_consume", "energy_cost", "temperature", "humidity"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    // An array of records, or a batch like test.json with the records under "data"
+    Json,
+    // One record per line
+    Jsonl,
+    // A header row naming the fields; temperature and humidity go into sensor_data
+    Csv,
+}
+
+impl ImportFormat {
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        extension.to_lowercase().parse()
+            .map_err(|_| format!("Cannot tell the format of {} from its extension, pass it explicitly (json, jsonl or csv)", path.display()))
+    }
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "json" => Ok(ImportFormat::Json),
+            "jsonl" | "ndjson" => Ok(ImportFormat::Jsonl),
+            "csv" => Ok(ImportFormat::Csv),
+            other => Err(format!("unknown import format '{}', expected json, jsonl or csv", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ImportOptions {
+    // Detected from the file extension when not given
+    pub format: Option<ImportFormat>,
+    // Records per batch, default ingest.batch_size
+    pub batch_size: Option<usize>,
+    // Ignore an existing checkpoint and start from the first record
+    pub restart: bool,
+}
+
+impl ImportOptions {
+    pub fn from_json(args: &Value) -> Result<Self, String> {
+        let format = match args.get("format").and_then(Value::as_str) {
+            Some(format) => Some(format.parse()?),
+            None => None,
+        };
+        let batch_size = match args.get("batch_size").and_then(Value::as_u64) {
+            Some(0) => return Err("'batch_size' must be greater than 0".to_string()),
+            Some(size) => Some(size as usize),
+            None => None,
+        };
+        let restart = args.get("restart").and_then(Value::as_bool).unwrap_or(false);
+        Ok(ImportOptions { format, batch_size, restart })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct Counts {
+    // Records and unparseable lines consumed from the file, the resume position
+    records: usize,
+    batches: usize,
+    written: usize,
+    quarantined: usize,
+    rejected: usize,
+}
+
+// Written next to the other checkpoints after every batch. Size and modification time
+// identify the file, so a checkpoint is never applied to a file that changed since.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Checkpoint {
+    file: String,
+    format: ImportFormat,
+    size: u64,
+    modified: u64,
+    counts: Counts,
+    updated_at: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ImportState {
+    Running,
+    Completed,
+    // Stopped by a shutdown; the checkpoint is kept
+    Interrupted,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ImportProgress {
+    id: String,
+    file: String,
+    format: ImportFormat,
+    state: ImportState,
+    resumed_at: usize,
+    #[serde(flatten)]
+    counts: Counts,
+    bytes_read: u64,
+    bytes_total: u64,
+    started_at: String,
+    finished_at: Option<String>,
+    error: Option<String>,
+}
+
+impl ImportProgress {
+    fn to_json(&self) -> Value {
+        let mut value = json!(self);
+        let percent = match self.bytes_total {
+            0 => 100.0,
+            total => (self.bytes_read as f64 / total as f64 * 1000.0).round() / 10.0,
+        };
+        value["percent"] = json!(percent.min(100.0));
+        value
+    }
+}
+
+fn update(id: &str, change: impl FnOnce(&mut ImportProgress)) {
+    let mut imports = IMPORTS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(progress) = imports.get_mut(id) {
+        change(progress);
+    }
+}
+
+// Progress of one import, or of every import started since the server came up
+pub fn progress(id: Option<&str>) -> Result<Value, String> {
+    let imports = IMPORTS.lock().unwrap_or_else(|e| e.into_inner());
+    match id {
+        Some(id) => imports.get(id).map(ImportProgress::to_json).ok_or_else(|| format!("No import with id '{}'", id)),
+        None => Ok(json!({ "imports": imports.values().map(ImportProgress::to_json).collect::<Vec<_>>() })),
+    }
+}
+
+// The same file always gets the same id, which also names its checkpoint
+fn import_id(path: &Path) -> String {
+    let digest = format!("{:x}", Sha256::digest(path.to_string_lossy().as_bytes()));
+    digest[..16].to_string()
+}
+
+fn checkpoint_path(id: &str) -> PathBuf {
+    config::get().import.checkpoint_dir.join(format!("{}.json", id))
+}
+
+fn read_checkpoint(path: &Path) -> Result<Option<Checkpoint>, String> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|e| format!("Corrupt import checkpoint {}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Failed to read import checkpoint {}: {}", path.display(), e)),
+    }
+}
+
+// Written to a temporary file first so a crash never leaves half a checkpoint behind
+fn write_checkpoint(path: &Path, checkpoint: &Checkpoint) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create checkpoint directory {}: {}", dir.display(), e))?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    let text = serde_json::to_string_pretty(checkpoint).map_err(|e| e.to_string())?;
+    std::fs::write(&tmp, text)
+        .and_then(|_| std::fs::rename(&tmp, path))
+        .map_err(|e| format!("Failed to write import checkpoint {}: {}", path.display(), e))
+}
+
+enum Item {
+    Record(Value),
+    // A line or row that could not be parsed; it is quarantined like an invalid record
+    Invalid { raw: Value, reason: String },
+}
+
+type ItemSender = mpsc::Sender<Result<Item, String>>;
+
+fn send(tx: &ItemSender, item: Item) -> Result<(), String> {
+    tx.blocking_send(Ok(item)).map_err(|_| "import stopped".to_string())
+}
+
+// Counts the bytes the parser has consumed, for the progress percentage
+struct CountingReader<R> {
+    inner: R,
+    bytes: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+// Runs on a blocking thread and feeds the records into `tx` until the file ends, the
+// receiver goes away or the file turns out to be unreadable
+fn parse_file(path: &Path, format: ImportFormat, bytes: Arc<AtomicU64>, tx: &ItemSender) -> Result<(), String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let reader = BufReader::new(CountingReader { inner: file, bytes });
+    match format {
+        ImportFormat::Json => parse_json(reader, tx),
+        ImportFormat::Jsonl => parse_jsonl(reader, tx),
+        ImportFormat::Csv => parse_csv(reader, tx),
+    }
+}
+
+// A syntax error ends a JSON document, so unlike JSONL and CSV it fails the import
+fn parse_json(reader: impl Read, tx: &ItemSender) -> Result<(), String> {
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    RecordStream { tx, top_level: true }.deserialize(&mut deserializer)
+        .and_then(|_| deserializer.end())
+        .map_err(|e| format!("Invalid JSON: {}", e))
+}
+
+// Hands the elements of the record array to the importer one at a time instead of
+// building the whole array in memory
+struct RecordStream<'a> {
+    tx: &'a ItemSender,
+    top_level: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for RecordStream<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for RecordStream<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of records or an object with a 'data' array")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(record) = seq.next_element::<Value>()? {
+            send(self.tx, Item::Record(record)).map_err(de::Error::custom)?;
+        }
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        if !self.top_level {
+            return Err(de::Error::invalid_type(Unexpected::Map, &"an array of records"));
+        }
+        let mut found = false;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "data" {
+                map.next_value_seed(RecordStream { tx: self.tx, top_level: false })?;
+                found = true;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        if found { Ok(()) } else { Err(de::Error::missing_field("data")) }
+    }
+}
+
+fn parse_jsonl(reader: impl BufRead, tx: &ItemSender) -> Result<(), String> {
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| format!("Failed to read line {}: {}", i + 1, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let item = match serde_json::from_str(&line) {
+            Ok(record) => Item::Record(record),
+            Err(e) => Item::Invalid { raw: json!(line), reason: format!("line {}: invalid JSON: {}", i + 1, e) },
+        };
+        send(tx, item)?;
+    }
+    Ok(())
+}
+
+fn parse_csv(reader: impl Read, tx: &ItemSender) -> Result<(), String> {
+    // Flexible, so rows with missing columns reach validation and get a precise reason
+    let mut rows = csv::ReaderBuilder::new().flexible(true).trim(csv::Trim::All).from_reader(reader);
+    let headers = rows.headers().map_err(|e| format!("Failed to read the CSV header: {}", e))?.clone();
+    for row in rows.byte_records() {
+        let row = row.map_err(|e| format!("Failed to read CSV: {}", e))?;
+        let line = row.position().map_or(0, |p| p.line());
+        let item = match csv::StringRecord::from_byte_record(row) {
+            Ok(row) => Item::Record(csv_record(&headers, &row)),
+            Err(e) => Item::Invalid {
+                raw: json!(e.into_byte_record().iter().map(String::from_utf8_lossy).collect::<Vec<_>>().join(",")),
+                reason: format!("line {}: invalid UTF-8", line),
+            },
+        };
+        send(tx, item)?;
+    }
+    Ok(())
+}
+
+// Turns a CSV row into a record shaped like the live ingest payload. Numbers that do not
+// parse stay strings, so validation names the offending field.
+fn csv_record(headers: &csv::StringRecord, row: &csv::StringRecord) -> Value {
+    let mut record = Map::new();
+    let mut sensor_data = Map::new();
+    for (header, field) in headers.iter().zip(row.iter()) {
+        if field.is_empty() {
+            continue;
+        }
+        let (name, in_sensor_data) = match header.strip_prefix("sensor_data.") {
+            Some(name) => (name, true),
+            None => (header, header == "temperature" || header == "humidity"),
+        };
+        let value = match field.parse::<f64>() {
+            Ok(number) if NUMERIC_COLUMNS.contains(&name) => json!(number),
+            _ => json!(field),
+        };
+        if in_sensor_data {
+            sensor_data.insert(name.to_string(), value);
+        } else {
+            record.insert(name.to_string(), value);
+        }
+    }
+    if !sensor_data.is_empty() {
+        record.insert("sensor_data".to_string(), Value::Object(sensor_data));
+    }
+    Value::Object(record)
+}
+
+// The counts to continue from: those of a checkpoint taken of this exact file, or zero
+// without one
+fn resume_counts(checkpoint: Option<Checkpoint>, path: &Path, format: ImportFormat, size: u64, modified: u64) -> Result<Counts, String> {
+    match checkpoint {
+        Some(checkpoint) if checkpoint.size == size && checkpoint.modified == modified && checkpoint.format == format => {
+            info!("Resuming import of {} after {} records", path.display(), checkpoint.counts.records);
+            Ok(checkpoint.counts)
+        },
+        Some(checkpoint) => Err(format!(
+            "{} changed since the checkpoint from {}; import it with restart to start over",
+            path.display(), checkpoint.updated_at,
+        )),
+        None => Ok(Counts::default()),
+    }
+}
+
+// Quarantines the unparseable items and ingests the records of one batch. Quarantine ids
+// are `prefix` and the position in the file, so a resumed or repeated import of the same
+// file quarantines each record only once.
+async fn flush(batch: Vec<Item>, prefix: &str, source: &str, graph: &Graph, counts: &mut Counts) -> Result<(), String> {
+    let items = batch.len();
+    let mut records = Vec::with_capacity(items);
+    for (position, item) in (counts.records..).zip(batch) {
+        let id = format!("{}-{}", prefix, position);
+        match item {
+            Item::Record(record) => records.push((id, record)),
+            Item::Invalid { raw, reason } => {
+                warn!("Invalid record in {}: {}", source, reason);
+                let quarantined = quarantine::quarantine(&[(id, raw)], &reason, source, graph).await?;
+                status::add(&status::RECORDS_QUARANTINED, quarantined);
+                counts.quarantined += quarantined;
+            },
+        }
+    }
+
+    let report = ingest::ingest_with_ids(records, source, graph).await?;
+
+    counts.records += items;
+    counts.batches += 1;
+    counts.written += report.written;
+    counts.quarantined += report.quarantined;
+    counts.rejected += report.rejected;
+    Ok(())
+}
+
+// Imports `path` in the foreground and returns the final progress report. Stops after the
+// current batch when the server shuts down; running it again resumes from the checkpoint.
+pub async fn run(path: &Path, options: ImportOptions, source: &str, graph: &Graph) -> Result<Value, String> {
+    let path = std::fs::canonicalize(path).map_err(|e| format!("Cannot import {}: {}", path.display(), e))?;
+    let id = import_id(&path);
+    {
+        let mut imports = IMPORTS.lock().unwrap_or_else(|e| e.into_inner());
+        if imports.get(&id).is_some_and(|p| p.state == ImportState::Running) {
+            return Err(format!("{} is already being imported (id {})", path.display(), id));
+        }
+        // Reserved right away so a second request for the same file is refused
+        let now = chrono::Utc::now().to_rfc3339();
+        imports.insert(id.clone(), ImportProgress {
+            id: id.clone(),
+            file: path.display().to_string(),
+            format: options.format.unwrap_or(ImportFormat::Json),
+            state: ImportState::Running,
+            resumed_at: 0,
+            counts: Counts::default(),
+            bytes_read: 0,
+            bytes_total: 0,
+            started_at: now,
+            finished_at: None,
+            error: None,
+        });
+    }
+
+    let result = import_file(&id, &path, options, source, graph).await;
+    let finished_at = chrono::Utc::now().to_rfc3339();
+    update(&id, |progress| {
+        progress.finished_at = Some(finished_at);
+        match &result {
+            Ok(state) => progress.state = *state,
+            Err(e) => {
+                progress.state = ImportState::Failed;
+                progress.error = Some(e.clone());
+            },
+        }
+    });
+    let report = progress(Some(&id))?;
+    match result {
+        Ok(ImportState::Completed) => info!("Import of {} completed: {}", path.display(), report),
+        Ok(_) => warn!("Import of {} interrupted, it resumes from the checkpoint next time", path.display()),
+        Err(e) => {
+            error!("Import of {} failed: {}", path.display(), e);
+            status::record_error(&e);
+            return Err(format!("{} (progress is kept, run the import again to resume)", e));
+        },
+    }
+    Ok(report)
+}
+
+async fn import_file(id: &str, path: &Path, options: ImportOptions, source: &str, graph: &Graph) -> Result<ImportState, String> {
+    let format = match options.format {
+        Some(format) => format,
+        None => ImportFormat::from_path(path)?,
+    };
+    let batch_size = options.batch_size.unwrap_or(config::get().ingest.batch_size).max(1);
+    let metadata = std::fs::metadata(path).map_err(|e| format!("Cannot import {}: {}", path.display(), e))?;
+    let size = metadata.len();
+    let modified = metadata.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+
+    let checkpoint_file = checkpoint_path(id);
+    // Names this version of the file in quarantine ids
+    let prefix = format!("import-{}-{}-{}", id, size, modified);
+    if options.restart {
+        let _ = std::fs::remove_file(&checkpoint_file);
+    }
+    let mut counts = resume_counts(read_checkpoint(&checkpoint_file)?, path, format, size, modified)?;
+    let resumed_at = counts.records;
+    update(id, |progress| {
+        progress.format = format;
+        progress.resumed_at = resumed_at;
+        progress.counts = counts;
+        progress.bytes_total = size;
+    });
+    info!("Importing {} ({} bytes, {:?}) in batches of {}", path.display(), size, format, batch_size);
+
+    let bytes = Arc::new(AtomicU64::new(0));
+    let (tx, mut rx) = mpsc::channel(batch_size * 2);
+    let parser = {
+        let (path, bytes) = (path.to_path_buf(), bytes.clone());
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = parse_file(&path, format, bytes, &tx) {
+                let _ = tx.blocking_send(Err(e));
+            }
+        })
+    };
+
+    let mut position = 0;
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut last_report = Instant::now();
+    let outcome = loop {
+        match rx.recv().await {
+            Some(Ok(item)) => {
+                position += 1;
+                // Already imported before the interruption
+                if position <= resumed_at {
+                    continue;
+                }
+                batch.push(item);
+                if batch.len() < batch_size {
+                    continue;
+                }
+            },
+            Some(Err(e)) => break Err(e),
+            None if batch.is_empty() => break Ok(ImportState::Completed),
+            // The last, partial batch
+            None => {},
+        }
+
+        if let Err(e) = flush(std::mem::take(&mut batch), &prefix, source, graph, &mut counts).await {
+            break Err(e);
+        }
+        let checkpoint = Checkpoint {
+            file: path.display().to_string(),
+            format,
+            size,
+            modified,
+            counts,
+            updated_at: chrono::Utc::now().to_rfc3339(),
+        };
+        if let Err(e) = write_checkpoint(&checkpoint_file, &checkpoint) {
+            break Err(e);
+        }
+        let bytes_read = bytes.load(Ordering::Relaxed);
+        update(id, |progress| {
+            progress.counts = counts;
+            progress.bytes_read = bytes_read;
+        });
+        if last_report.elapsed() >= PROGRESS_INTERVAL {
+            info!("Import of {}: {} records, {} of {} bytes", path.display(), counts.records, bytes_read, size);
+            last_report = Instant::now();
+        }
+
+        if shutdown::token().is_cancelled() {
+            break Ok(ImportState::Interrupted);
+        }
+    };
+
+    // Dropping the receiver makes the parser stop at its next record
+    drop(rx);
+    let _ = parser.await;
+    if outcome == Ok(ImportState::Completed) {
+        update(id, |progress| progress.bytes_read = size);
+        if let Err(e) = std::fs::remove_file(&checkpoint_file) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove import checkpoint {}: {}", checkpoint_file.display(), e);
+            }
+        }
+    }
+    outcome
+}
+
+// Starts an import in the background for the `import` command and returns its id.
+// Shutdown waits for the current batch before the process exits.
+pub fn spawn(path: PathBuf, options: ImportOptions, source: String, graph: &'static Graph) -> Result<Value, String> {
+    if !path.is_file() {
+        return Err(format!("{} is not a file on the server", path.display()));
+    }
+    if !migrations::is_compatible() {
+        return Err("The database schema has not been checked yet, is Neo4j reachable?".to_string());
+    }
+    let id = std::fs::canonicalize(&path).map(|p| import_id(&p)).map_err(|e| format!("Cannot import {}: {}", path.display(), e))?;
+    let running = IMPORTS.lock().unwrap_or_else(|e| e.into_inner())
+        .get(&id)
+        .is_some_and(|p| p.state == ImportState::Running);
+    if running {
+        return Err(format!("{} is already being imported (id {})", path.display(), id));
+    }
+    shutdown::tracker().spawn(async move {
+        // Failures are logged and show up in `import_status`
+        let _ = run(&path, options, &source, graph).await;
+    });
+    Ok(json!({ "id": id, "state": "started" }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Everything the parser sent, with the error it stopped on
+    fn parse(parser: impl FnOnce(&ItemSender) -> Result<(), String>) -> (Vec<Item>, Result<(), String>) {
+        let (tx, mut rx) = mpsc::channel(64);
+        let result = parser(&tx);
+        drop(tx);
+        let mut items = Vec::new();
+        while let Ok(item) = rx.try_recv() {
+            items.push(item.expect("parsers return errors instead of sending them"));
+        }
+        (items, result)
+    }
+
+    fn checkpoint(counts: Counts) -> Checkpoint {
+        Checkpoint {
+            file: "readings.jsonl".into(),
+            format: ImportFormat::Jsonl,
+            size: 100,
+            modified: 1_700_000_000,
+            counts,
+            updated_at: "2024-01-01T00:00:00Z".into(),
+        }
+    }
+
+    #[test]
+    fn invalid_lines_count_towards_the_resume_position() {
+        let text = "{\"uuid\": \"a\"}\n\nnot json\n{\"uuid\": \"b\"}\n";
+        let (items, result) = parse(|tx| parse_jsonl(text.as_bytes(), tx));
+        assert_eq!(result, Ok(()));
+        assert_eq!(items.len(), 3);
+        assert!(matches!(&items[1], Item::Invalid { reason, .. } if reason.starts_with("line 3:")));
+    }
+
+    #[test]
+    fn json_records_are_read_from_an_array_or_data() {
+        let (items, result) = parse(|tx| parse_json(r#"[{"uuid": "a"}, {"uuid": "b"}]"#.as_bytes(), tx));
+        assert_eq!((items.len(), result), (2, Ok(())));
+
+        let (items, result) = parse(|tx| parse_json(r#"{"type": "batch", "data": [{"uuid": "a"}]}"#.as_bytes(), tx));
+        assert_eq!((items.len(), result), (1, Ok(())));
+
+        let (_, result) = parse(|tx| parse_json(r#"{"type": "batch"}"#.as_bytes(), tx));
+        assert!(result.unwrap_err().contains("missing field `data`"));
+    }
+
+    #[test]
+    fn csv_rows_become_records() {
+        let text = "uuid,color,temperature,energy_cost\na,red,21.5,x\n";
+        let (items, result) = parse(|tx| parse_csv(text.as_bytes(), tx));
+        assert_eq!(result, Ok(()));
+        let [Item::Record(record)] = items.as_slice() else { panic!("expected one record") };
+        assert_eq!(record, &json!({
+            "uuid": "a",
+            "color": "red",
+            "energy_cost": "x",
+            "sensor_data": { "temperature": 21.5 }
+        }));
+    }
+
+    #[test]
+    fn resumes_only_from_a_checkpoint_of_the_same_file() {
+        let path = Path::new("readings.jsonl");
+        let counts = Counts { records: 40, batches: 4, written: 35, quarantined: 3, rejected: 0 };
+
+        let resumed = resume_counts(Some(checkpoint(counts)), path, ImportFormat::Jsonl, 100, 1_700_000_000).unwrap();
+        assert_eq!((resumed.records, resumed.batches, resumed.written), (40, 4, 35));
+
+        assert_eq!(resume_counts(None, path, ImportFormat::Jsonl, 100, 1_700_000_000).unwrap().records, 0);
+        assert!(resume_counts(Some(checkpoint(counts)), path, ImportFormat::Jsonl, 101, 1_700_000_000).is_err());
+        assert!(resume_counts(Some(checkpoint(counts)), path, ImportFormat::Jsonl, 100, 1_700_000_001).is_err());
+        assert!(resume_counts(Some(checkpoint(counts)), path, ImportFormat::Json, 100, 1_700_000_000).is_err());
+    }
+
+    #[test]
+    fn checkpoints_round_trip_through_the_file() {
+        let dir = std::env::temp_dir().join(format!("datacenter-import-{}", uuid::Uuid::new_v4().simple()));
+        let path = dir.join("checkpoint.json");
+        assert!(read_checkpoint(&path).unwrap().is_none());
+
+        let counts = Counts { records: 7, ..Counts::default() };
+        write_checkpoint(&path, &checkpoint(counts)).unwrap();
+        let read = read_checkpoint(&path).unwrap().expect("checkpoint was written");
+        assert_eq!((read.counts.records, read.size, read.format), (7, 100, ImportFormat::Jsonl));
+        assert!(!path.with_extension("json.tmp").exists());
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(read_checkpoint(&path).unwrap_err().starts_with("Corrupt import checkpoint"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
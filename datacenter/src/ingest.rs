@@ -0,0 +1,51 @@
+use log::{info, error};
+use neo4rs::Graph;
+use serde_json::{Value, json};
+
+use crate::device::{apply_device_policy, UnknownDevicePolicy};
+use crate::live;
+use crate::quarantine::{split_valid, with_ids};
+use crate::query::{create_new_relation, IngestReport, IngestSettings};
+use crate::status;
+
+// Runs one batch of readings through validation, the device policy and the Neo4j write.
+// An error means the batch has to be retried later; `batch` names a batch that is retried,
+// so its records are quarantined only on the first attempt.
+pub async fn ingest_records(records: Vec<Value>, source: &str, batch: Option<&str>, graph: &Graph) -> Result<IngestReport, String> {
+    ingest_with_ids(with_ids(records, batch), source, graph).await
+}
+
+// Like `ingest_records`, for records that already carry their quarantine ids
+pub async fn ingest_with_ids(records: Vec<(String, Value)>, source: &str, graph: &Graph) -> Result<IngestReport, String> {
+    let (valid, quarantined) = split_valid(records, source, graph).await?;
+    if quarantined > 0 {
+        info!("Quarantined {} invalid records from {}", quarantined, source);
+        status::add(&status::RECORDS_QUARANTINED, quarantined);
+    }
+    let valid_count = valid.len();
+    let accepted = apply_device_policy(valid, UnknownDevicePolicy::from_config(), source, graph).await?;
+    let rejected = valid_count - accepted.len();
+    status::add(&status::RECORDS_REJECTED, rejected);
+    if accepted.is_empty() {
+        info!("No records left to ingest after validation and device policy");
+        return Ok(IngestReport { quarantined, rejected, ..IngestReport::default() });
+    }
+
+    let batch = json!({ "data": accepted });
+    match create_new_relation(&batch, graph, IngestSettings::from_config()).await {
+        Ok(report) => {
+            let report = IngestReport { quarantined, rejected, ..report };
+            info!("Wrote {} of {} records from {} to Neo4j in {} chunks", report.written, report.submitted, source, report.chunks);
+            status::add(&status::RECORDS_WRITTEN, report.written);
+            if let Some(records) = batch["data"].as_array() {
+                live::publish(records);
+            }
+            Ok(report)
+        },
+        Err(e) => {
+            error!("Failed to create new relations in Neo4j: {}", e);
+            status::record_error(&e);
+            Err(e)
+        },
+    }
+}
@@ -1,69 +1,138 @@
-use log::{info, error};
-use serde_json::Value;
-
-use crate::query::create_new_relation;
-use crate::db::{get_read_db, get_db};
-use crate::command_handler::router;
-
-pub async fn process_json(json: &Value) {
-    info!("Processing JSON: {}", json);
-    
- 
-    if let Some(message_type) = json.get("type") {
-        match message_type.as_str() {
-            Some("message") => handle_message(json),
-            Some("command") => handle_command(json).await,
-            Some("data") => {
-                
-                handle_data(json).await;
-            },
-            _ => info!("Unknown message type: {:?}", message_type),
-        }
-    } else {
-        info!("JSON has no type field");
-    }
-}
-
-fn handle_message(json: &Value) {
-    if let Some(content) = json.get("content") {
-        info!("Received message: {:?}", content);
-    }
-}
-
-async fn handle_command(json: &Value) {
-    if let Some(command) = json.get("command") {
-        info!("Received command: {:?}", command);
-        
-        
-        if let Some(cmd_str) = command.as_str() {
-            match router(cmd_str).await {
-                Ok(true) => info!("Command '{}' executed successfully", cmd_str),
-                Ok(false) => error!("Command '{}' execution failed", cmd_str),
-                Err(e) => error!("Error processing command '{}': {}", cmd_str, e),
-            }
-        } else {
-            error!("Command is not a string: {:?}", command);
-        }
-    } else {
-        error!("No 'command' field found in JSON");
-    }
-}
-
-
-async fn handle_data(json: &Value) {
-    if let Some(data) = json.get("data") {
-        let db = match get_db().await {
-            Ok(db) => db,
-            Err(e) => {
-                error!("Failed to get database connection: {}", e);
-                return;
-            },
-        };
-        info!("Received data: {:?}", data);
-      
-        match create_new_relation(json, &db).await {
-            true => info!("Successfully created new relations in Neo4j"),
-            false => error!("Failed to create new relations in Neo4j"),
-        }
-    }
-}
\ No newline at end of file
+use log::{debug, info, error};
+use serde_json::{Value, json};
+
+use crate::auth::Role;
+use crate::command_handler::{router, CommandContext};
+use crate::dedupe;
+use crate::logging;
+use crate::message_schema::{self, Transport};
+use crate::models::IngestAck;
+use crate::status;
+use crate::wal;
+
+// Returns the response to send back to the client, if the message type has one.
+// `source` identifies the connection the message arrived on (e.g. "tcp:10.0.0.5:53122"),
+// `role` is what the client authenticated as.
+pub async fn process_json(json: &Value, source: &str, role: Role) -> Option<Value> {
+    debug!("Processing JSON: {}", logging::payload(json));
+
+    let errors = message_schema::validate(json, Transport::Tcp);
+    if !errors.is_empty() {
+        info!("Rejected invalid message from {}: {}", source, json!(errors));
+        return Some(message_schema::error_response(json, errors));
+    }
+    if let Some(message_type) = json.get("type") {
+        match message_type.as_str() {
+            Some("message") => {
+                handle_message(json);
+                None
+            },
+            Some("command") => {
+                let ctx = CommandContext { role, source: source.to_string() };
+                Some(handle_command(json, &ctx).await)
+            },
+            Some("data") => Some(json!(handle_data(json, source).await)),
+            Some("schema") => Some(match message_schema::describe(json, Transport::Tcp) {
+                Ok(schema) => json!({ "type": "schema", "schema": schema }),
+                Err(e) => json!({ "type": "error", "request": "schema", "message": e }),
+            }),
+            _ => {
+                info!("Unknown message type: {:?}", message_type);
+                None
+            },
+        }
+    } else {
+        info!("JSON has no type field");
+        None
+    }
+}
+
+fn handle_message(json: &Value) {
+    if let Some(content) = json.get("content") {
+        info!("Received message: {}", logging::payload(content));
+    }
+}
+
+async fn handle_command(json: &Value, ctx: &CommandContext) -> Value {
+    if let Some(command) = json.get("command") {
+        info!("Received command: {}", command);
+        
+        
+        if let Some(cmd_str) = command.as_str() {
+            let args = json.get("args").cloned().unwrap_or_else(|| json!({}));
+            match router(cmd_str, &args, ctx).await {
+                Ok(result) => {
+                    info!("Command '{}' executed successfully", cmd_str);
+                    json!({ "command": cmd_str, "success": true, "result": result })
+                },
+                Err(e) => {
+                    error!("Error processing command '{}': {}", cmd_str, e);
+                    json!({ "command": cmd_str, "success": false, "message": e.to_string() })
+                },
+            }
+        } else {
+            error!("Command is not a string: {:?}", command);
+            json!({ "success": false, "message": "'command' must be a string" })
+        }
+    } else {
+        error!("No 'command' field found in JSON");
+        json!({ "success": false, "message": "No 'command' field found in JSON" })
+    }
+}
+
+
+// Appends the batch to the write-ahead log and acknowledges it. Validation and the Neo4j
+// write happen in the WAL flusher, so data is never lost while the database is down.
+// Batches and records may carry a `message_id`; repeats within the dedupe window are
+// acknowledged as "duplicate" and not stored again.
+pub async fn handle_data(json: &Value, source: &str) -> IngestAck {
+    let Some(records) = json.get("data").and_then(Value::as_array) else {
+        error!("Invalid JSON structure: 'data' array not found");
+        return IngestAck::error(None, "'data' array not found");
+    };
+    info!("Received {} records", records.len());
+    debug!("Received data: {}", logging::payload(&json["data"]));
+    status::add(&status::BATCHES_RECEIVED, 1);
+    status::add(&status::RECORDS_RECEIVED, records.len());
+    let message_id = json.get("message_id").and_then(Value::as_str);
+
+    let mut marked = Vec::new();
+    if let Some(id) = message_id {
+        let key = format!("batch:{}", id);
+        if dedupe::check_and_mark(&key) {
+            info!("Duplicate batch '{}' from {}", id, source);
+            status::add(&status::DUPLICATES, records.len());
+            return IngestAck { records: Some(records.len()), ..IngestAck::new("duplicate", message_id) };
+        }
+        marked.push(key);
+    }
+
+    let (fresh, record_keys, duplicates) = dedupe::filter_records(records);
+    status::add(&status::DUPLICATES, duplicates);
+    marked.extend(record_keys);
+    if fresh.is_empty() && !records.is_empty() {
+        return IngestAck { duplicates: Some(duplicates), ..IngestAck::new("duplicate", message_id) };
+    }
+
+    let wal = match wal::init().await {
+        Ok(wal) => wal,
+        Err(e) => {
+            error!("Write-ahead log unavailable: {}", e);
+            dedupe::forget(&marked);
+            return IngestAck::error(message_id, e);
+        }
+    };
+    match wal.append(&fresh, source, &marked).await {
+        Ok(seq) => IngestAck {
+            seq: Some(seq),
+            records: Some(fresh.len()),
+            duplicates: Some(duplicates),
+            ..IngestAck::new("accepted", message_id)
+        },
+        Err(e) => {
+            error!("Failed to append batch from {} to WAL: {}", source, e);
+            dedupe::forget(&marked);
+            IngestAck::error(message_id, e)
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use log::{info, warn, error};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::sync::{Arc, LazyLock};
+use tokio::sync::broadcast;
+use tracing::Instrument;
+
+use crate::auth::Role;
+use crate::command_handler::{db, CommandContext};
+use crate::logging;
+use crate::message_schema::{self, Transport};
+use crate::metrics;
+use crate::protocol::{self, Inbound, Session};
+use crate::request_handler;
+use crate::shutdown;
+
+// Live stream of ingested readings for dashboards. Every batch written to Neo4j is
+// broadcast; each WebSocket client holds a subscription that picks readings by device and
+// color and turns readings outside its thresholds into alerts. Besides `subscribe` and
+// `unsubscribe`, clients may send any query message the MQTT API answers.
+//
+// Client messages:
+//   {"type": "subscribe", "devices": [..], "colors": [..], "alerts_only": false,
+//    "thresholds": {"temperature": {"min": 10, "max": 30}, "humidity": {"max": 80}}}
+//   {"type": "unsubscribe"}
+//   {"type": "uuid", "data": "..", "request_id": ..} and the other query types
+// Server messages: subscribed, unsubscribed, reading, alert, lagged, response and error.
+
+// Batches a slow client may fall behind before it is told it missed some
+const CHANNEL_CAPACITY: usize = 1024;
+const THRESHOLD_FIELDS: [&str; 4] = ["temperature", "humidity", "energy_consume", "energy_cost"];
+
+static EVENTS: LazyLock<broadcast::Sender<Arc<Vec<Value>>>> = LazyLock::new(|| broadcast::channel(CHANNEL_CAPACITY).0);
+
+// Hands freshly written readings to the subscribed clients
+pub fn publish(records: &[Value]) {
+    if EVENTS.receiver_count() > 0 {
+        // Fails only when the last subscriber left in the meantime
+        let _ = EVENTS.send(Arc::new(records.to_vec()));
+    }
+}
+
+// Clients with an active subscription
+pub fn subscribers() -> usize {
+    EVENTS.receiver_count()
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Range {
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Subscription {
+    // Empty means every device or color
+    devices: Vec<String>,
+    colors: Vec<String>,
+    thresholds: BTreeMap<String, Range>,
+    // Only send readings that break a threshold
+    alerts_only: bool,
+}
+
+impl Subscription {
+    fn from_json(json: &Value) -> Result<Subscription, String> {
+        let mut fields = json.as_object().cloned().unwrap_or_default();
+        fields.retain(|key, _| key != "type" && key != "request_id");
+        let subscription: Subscription = serde_json::from_value(Value::Object(fields))
+            .map_err(|e| format!("Invalid subscription: {}", e))?;
+        if let Some(field) = subscription.thresholds.keys().find(|field| !THRESHOLD_FIELDS.contains(&field.as_str())) {
+            return Err(format!("Unknown threshold '{}', expected one of {}", field, THRESHOLD_FIELDS.join(", ")));
+        }
+        Ok(subscription)
+    }
+
+    fn matches(&self, record: &Value) -> bool {
+        let listed = |list: &[String], field: &str| {
+            list.is_empty() || record.get(field).and_then(Value::as_str).is_some_and(|value| list.iter().any(|v| v == value))
+        };
+        listed(&self.devices, "uuid") && listed(&self.colors, "color")
+    }
+
+    // Thresholds the reading breaks, with the offending value
+    fn violations(&self, record: &Value) -> Vec<Value> {
+        self.thresholds.iter().filter_map(|(field, range)| {
+            let value = field_value(record, field)?;
+            let broken = range.min.is_some_and(|min| value < min) || range.max.is_some_and(|max| value > max);
+            broken.then(|| json!({ "field": field, "value": value, "min": range.min, "max": range.max }))
+        }).collect()
+    }
+
+    fn events(&self, record: &Value) -> Vec<Value> {
+        if !self.matches(record) {
+            return Vec::new();
+        }
+        let violations = self.violations(record);
+        let mut events = Vec::new();
+        if !self.alerts_only {
+            events.push(json!({ "type": "reading", "reading": record }));
+        }
+        if !violations.is_empty() {
+            events.push(json!({ "type": "alert", "reading": record, "violations": violations }));
+        }
+        events
+    }
+}
+
+fn field_value(record: &Value, field: &str) -> Option<f64> {
+    match field {
+        "temperature" | "humidity" => record.get("sensor_data")?.get(field)?.as_f64(),
+        _ => record.get(field)?.as_f64(),
+    }
+}
+
+// Waits for the next batch, or forever while the client is not subscribed
+async fn next_batch(events: &mut Option<broadcast::Receiver<Arc<Vec<Value>>>>) -> Result<Arc<Vec<Value>>, broadcast::error::RecvError> {
+    match events {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: Value) -> bool {
+    socket.send(Message::Text(message.to_string().into())).await.is_ok()
+}
+
+// Serves one WebSocket client until it disconnects or the server shuts down
+pub async fn serve_socket(mut socket: WebSocket, source: String) {
+    info!("Live stream client {} connected", source);
+    let ctx = CommandContext { role: Role::User, source };
+    let mut subscription = Subscription::default();
+    let mut events = None;
+    let mut session = Session::new(Transport::Live);
+
+    'stream: loop {
+        tokio::select! {
+            _ = shutdown::token().cancelled() => {
+                let frame = CloseFrame { code: axum::extract::ws::close_code::AWAY, reason: "Server shutting down".into() };
+                let _ = socket.send(Message::Close(Some(frame))).await;
+                break;
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = client_message(text.as_str(), &mut subscription, &mut events, &mut session, &ctx).await;
+                    if !send(&mut socket, reply).await {
+                        break;
+                    }
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum, binary frames are not part of the protocol
+                Some(Ok(_)) => {},
+            },
+            batch = next_batch(&mut events) => match batch {
+                Ok(records) => {
+                    for event in records.iter().flat_map(|record| subscription.events(record)) {
+                        if !send(&mut socket, protocol::respond(event, &Value::Null, session.version())).await {
+                            break 'stream;
+                        }
+                    }
+                },
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Live stream client {} fell behind by {} batches", ctx.source, missed);
+                    let notice = json!({ "type": "lagged", "missed_batches": missed });
+                    if !send(&mut socket, protocol::respond(notice, &Value::Null, session.version())).await {
+                        break;
+                    }
+                },
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        }
+    }
+    info!("Live stream client {} disconnected", ctx.source);
+}
+
+async fn client_message(
+    text: &str,
+    subscription: &mut Subscription,
+    events: &mut Option<broadcast::Receiver<Arc<Vec<Value>>>>,
+    session: &mut Session,
+    ctx: &CommandContext
+) -> Value {
+    let json: Value = match serde_json::from_str(text) {
+        Ok(json) => json,
+        Err(e) => return protocol::respond(json!({ "type": "error", "message": format!("Invalid JSON: {}", e) }), &Value::Null, session.version()),
+    };
+    metrics::message_received("ws", &json);
+    let json = match session.receive(json) {
+        Inbound::Message(json) => json,
+        Inbound::Reply(reply) => return reply,
+    };
+    let kind = json.get("type").and_then(Value::as_str).unwrap_or_default().to_string();
+
+    let errors = message_schema::validate(&json, Transport::Live);
+    let mut reply = match kind.as_str() {
+        _ if !errors.is_empty() => message_schema::error_response(&json, errors),
+        "subscribe" => match Subscription::from_json(&json) {
+            Ok(new) => {
+                info!("Live stream client {} subscribed to devices {:?}, colors {:?}", ctx.source, new.devices, new.colors);
+                *subscription = new;
+                events.get_or_insert_with(|| EVENTS.subscribe());
+                json!({ "type": "subscribed", "subscription": subscription })
+            },
+            Err(e) => json!({ "type": "error", "request": kind, "message": e }),
+        },
+        "unsubscribe" => {
+            *events = None;
+            json!({ "type": "unsubscribed" })
+        },
+        _ => {
+            let result = match db().await {
+                Ok(graph) => request_handler::handle(&json, graph, ctx, Transport::Live)
+                    .instrument(logging::request_span(&json))
+                    .await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(reply) => json!({ "type": "response", "request": kind, "result": reply.body }),
+                Err(e) => {
+                    error!("{}", e);
+                    json!({ "type": "error", "request": kind, "message": e })
+                },
+            }
+        },
+    };
+    if let Some(request_id) = json.get("request_id") {
+        reply["request_id"] = request_id.clone();
+    }
+    protocol::respond(reply, &json, protocol::version(&json))
+}
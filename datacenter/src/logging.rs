@@ -0,0 +1,172 @@
+use serde_json::{Map, Value, json};
+use std::sync::OnceLock;
+use tracing::{field, info_span};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use uuid::Uuid;
+
+use crate::config::{LogFormat, LoggingConfig, PayloadLogging};
+
+// Logging goes through `tracing`; the `log` macros used across the crate are bridged into it,
+// so they carry the connection/request span they were emitted in. Filter, output format and
+// payload logging come from the [logging] config section. With the `otel` feature, spans are
+// also exported over OTLP when OTEL_EXPORTER_OTLP_ENDPOINT is set.
+static PAYLOADS: OnceLock<PayloadLogging> = OnceLock::new();
+
+// Values under these keys never show up in logs unless payloads = "full"
+const SECRET_KEYS: &[&str] = &["password", "admin_password", "token", "confirm", "secret", "authorization"];
+const MAX_ITEMS: usize = 5;
+const MAX_STRING: usize = 200;
+
+// `to_stderr` keeps stdout free for the output of the admin subcommands
+pub fn init(config: &LoggingConfig, to_stderr: bool) -> Result<(), String> {
+    let _ = PAYLOADS.set(config.payloads);
+    let filter = EnvFilter::try_new(&config.filter).map_err(|e| format!("Invalid log filter '{}': {}", config.filter, e))?;
+    let fmt = tracing_subscriber::fmt::layer().with_writer(move || -> Box<dyn std::io::Write> {
+        if to_stderr { Box::new(std::io::stderr()) } else { Box::new(std::io::stdout()) }
+    });
+    let fmt = match config.format {
+        LogFormat::Json => fmt.json().with_current_span(true).with_span_list(true).boxed(),
+        LogFormat::Text => fmt.boxed(),
+    };
+
+    let registry = tracing_subscriber::registry().with(filter).with(fmt);
+    #[cfg(feature = "otel")]
+    let registry = registry.with(otel::layer()?);
+    registry.try_init().map_err(|e| format!("Logger already initialized: {}", e))
+}
+
+// Flushes spans that are still buffered for export
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    otel::shutdown();
+}
+
+// One span per message, so everything logged while handling it can be correlated.
+// Clients may send their own `request_id`; otherwise one is generated.
+pub fn request_span(json: &Value) -> tracing::Span {
+    let request_id = json.get("request_id").and_then(Value::as_str)
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let kind = json.get("type").and_then(Value::as_str).unwrap_or("unknown");
+    let span = info_span!("request", request_id = %request_id, kind, command = field::Empty);
+    if let Some(command) = json.get("command").and_then(Value::as_str) {
+        span.record("command", command);
+    }
+    span
+}
+
+// Renders a payload for a log line according to the `payloads` setting
+pub fn payload(value: &Value) -> String {
+    match PAYLOADS.get().copied().unwrap_or(PayloadLogging::Redacted) {
+        PayloadLogging::Off => "<omitted>".to_string(),
+        PayloadLogging::Redacted => redact(value).to_string(),
+        PayloadLogging::Full => value.to_string(),
+    }
+}
+
+// Masks secrets and shortens long arrays and strings, so a batch of readings logs as a
+// handful of records instead of the whole thing
+fn redact(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let redacted: Map<String, Value> = map.iter()
+                .map(|(key, value)| {
+                    if SECRET_KEYS.contains(&key.to_lowercase().as_str()) {
+                        (key.clone(), json!("***"))
+                    } else {
+                        (key.clone(), redact(value))
+                    }
+                })
+                .collect();
+            Value::Object(redacted)
+        },
+        Value::Array(items) if items.len() > MAX_ITEMS => {
+            let mut shortened: Vec<Value> = items.iter().take(MAX_ITEMS).map(redact).collect();
+            shortened.push(json!(format!("... {} more", items.len() - MAX_ITEMS)));
+            Value::Array(shortened)
+        },
+        Value::Array(items) => Value::Array(items.iter().map(redact).collect()),
+        Value::String(s) if s.chars().count() > MAX_STRING => {
+            json!(format!("{}... ({} chars)", s.chars().take(MAX_STRING).collect::<String>(), s.chars().count()))
+        },
+        other => other.clone(),
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::Resource;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use std::sync::OnceLock;
+    use tracing_subscriber::Layer;
+    use tracing_subscriber::registry::LookupSpan;
+
+    static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+    // A no-op layer unless OTEL_EXPORTER_OTLP_ENDPOINT is set
+    pub fn layer<S>() -> Result<Option<Box<dyn Layer<S> + Send + Sync>>, String>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span> + Send + Sync,
+    {
+        if std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_err() {
+            return Ok(None);
+        }
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .build()
+            .map_err(|e| format!("Failed to create OTLP exporter: {}", e))?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name("datacenter").build())
+            .build();
+        let tracer = provider.tracer("datacenter");
+        let _ = PROVIDER.set(provider);
+        Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer).boxed()))
+    }
+
+    pub fn shutdown() {
+        if let Some(provider) = PROVIDER.get() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush OpenTelemetry spans: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_secrets_at_any_depth() {
+        let redacted = redact(&json!({
+            "type": "login",
+            "Password": "hunter2",
+            "args": { "confirm": "abc", "scope": { "token": "t" } }
+        }));
+        assert_eq!(redacted, json!({
+            "type": "login",
+            "Password": "***",
+            "args": { "confirm": "***", "scope": { "token": "***" } }
+        }));
+    }
+
+    #[test]
+    fn shortens_long_arrays() {
+        let redacted = redact(&json!([1, 2, 3, 4, 5, 6, 7]));
+        assert_eq!(redacted, json!([1, 2, 3, 4, 5, "... 2 more"]));
+        assert_eq!(redact(&json!([1, 2, 3, 4, 5])), json!([1, 2, 3, 4, 5]));
+    }
+
+    #[test]
+    fn shortens_long_strings_by_characters() {
+        let long = "ä".repeat(MAX_STRING + 10);
+        let redacted = redact(&json!(long));
+        assert_eq!(redacted, json!(format!("{}... ({} chars)", "ä".repeat(MAX_STRING), MAX_STRING + 10)));
+        assert_eq!(redact(&json!("short")), json!("short"));
+    }
+}
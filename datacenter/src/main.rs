@@ -1,103 +1,376 @@
-use log::{info, error};
-use neo4rs::{Graph, query};
-use dotenv::dotenv;
-use std::env;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use std::io;
-use serde_json::{Value, Error as JsonError};
-
-mod db;
-mod auth;
-mod json_handler;
-mod query;
-mod mqtt_handler;
-mod command_handler;
-
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    dotenv().ok();
-    
-    if env_logger::try_init().is_err() {
-        eprintln!("Logger already initialized.");
-    }
-    info!("Starting the server...");
-    let db = match db::get_db().await {
-        Ok(db) => db,
-        Err(e) => {
-            error!("Failed to get database connection: {}", e);
-            return Err(io::Error::new(io::ErrorKind::Other, format!("Database connection failed: {}", e)));
-        },
-    };
-
-    // Start MQTT client
-    tokio::spawn(async {
-        if let Err(e) = mqtt_handler::start_mqtt_client().await {
-            error!("MQTT client error: {:?}", e);
-        }
-    });
-
-    let password = env::var("SERVER_PASSWORD").expect("SERVER_PASSWORD not set in .env");
-    let listener = TcpListener::bind("0.0.0.0:12345").await?;
-    info!("Server is listening on 0.0.0.0:12345");
-    loop {
-        match listener.accept().await {
-            Ok((socket, addr)) => {
-                info!("New connection from: {}", addr);
-                let password_clone = password.clone();
-                tokio::spawn(async move {
-                    if let Err(e) = handle_client(socket, password_clone).await {
-                        error!("Error handling client {}: {:?}", addr, e);
-                    }
-                });
-            }
-            Err(e) => error!("Failed to accept connection: {:?}", e),
-        }
-    }
-}
-
-async fn handle_client(mut socket: TcpStream, correct_password: String) -> io::Result<()> {
-    if !auth::authenticate_client(&mut socket, &correct_password).await? {
-        return Ok(());
-    }
-    
-    loop {
-        match receive_json(&mut socket).await {
-            Ok(Some(json)) => {
-                json_handler::process_json(&json).await;
-            },
-            Ok(None) => {
-                info!("Client disconnected.");
-                break;
-            },
-            Err(e) => {
-                error!("Error receiving JSON: {:?}", e);
-                break;
-            }
-        }
-    }
-    
-    Ok(())
-}
-
-async fn receive_json(socket: &mut TcpStream) -> io::Result<Option<Value>> {
-    let mut buf = [0; 4096];
-    let n = socket.read(&mut buf).await?;
-    
-    if n == 0 {
-        return Ok(None); // Client disconnected
-    }
-    
-    let data = String::from_utf8_lossy(&buf[..n]);
-    match serde_json::from_str::<Value>(&data) {
-        Ok(json) => {
-            info!("Received JSON: {}", json);
-            Ok(Some(json))
-        },
-        Err(e) => {
-            error!("Invalid JSON received: {:?}", e);
-            socket.write_all(b"Error: Invalid JSON format\n").await?;
-            Err(io::Error::new(io::ErrorKind::InvalidData, e))
-        }
-    }
-}
\ No newline at end of file
+use log::{debug, info, error, warn};
+use dotenv::dotenv;
+use clap::Parser;
+use cli::{Cli, Command};
+use config::Config;
+use export::ExportRequest;
+use codec::Encoding;
+use message_schema::Transport;
+use protocol::Inbound;
+use query::ReadingFilter;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use serde_json::{Value, json};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{Instrument, info_span};
+
+mod db;
+mod auth;
+mod json_handler;
+mod codec;
+mod protocol;
+mod message_schema;
+mod query;
+mod mqtt_handler;
+mod command_handler;
+mod commands;
+mod device;
+mod quarantine;
+mod ingest;
+mod wal;
+mod dedupe;
+mod schema;
+mod migrations;
+mod reset;
+mod import;
+mod export;
+mod api;
+mod graphql;
+mod live;
+mod request_handler;
+mod models;
+mod shutdown;
+mod status;
+mod metrics;
+mod logging;
+mod config;
+mod cli;
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    dotenv().ok();
+
+    let cli = Cli::parse();
+    let mut config = match Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprint!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let command = match cli.command {
+        _ if cli.check_config => Command::CheckConfig,
+        Some(command) => command,
+        None => Command::Serve { bind: None },
+    };
+    if let Command::Serve { bind: Some(bind) } = &command {
+        config.server.bind = bind.clone();
+        let problems = config.validate();
+        if !problems.is_empty() {
+            eprintln!("Invalid configuration:\n  - {}", problems.join("\n  - "));
+            std::process::exit(1);
+        }
+    }
+    let config = config::init(config);
+
+    // Admin commands keep stdout for their result
+    let to_stderr = !matches!(command, Command::Serve { .. });
+    if let Err(e) = logging::init(&config.logging, to_stderr) {
+        eprintln!("{}", e);
+    }
+
+    let result = match command {
+        Command::Serve { .. } => return serve(config).await,
+        Command::CheckConfig => {
+            cli::check_config(config);
+            Ok(())
+        },
+        Command::Migrate { dry_run, status } => cli::migrate(dry_run, status).await,
+        Command::Import { file, format, batch_size, restart } => cli::import(&file, format, batch_size, restart).await,
+        Command::Export { format, from, to, devices, colors, output } => {
+            let filter = ReadingFilter { from, to, devices, colors, limit: None };
+            cli::export(ExportRequest { format, filter }, output).await
+        },
+        Command::Reset { scope, export, batch_size, yes } => cli::reset(&scope, export, batch_size, yes).await,
+        Command::Status { addr, password } => cli::status(addr, password).await,
+    };
+    logging::shutdown();
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+enum StartupError {
+    // Neo4j is not answering yet; try again later
+    Unavailable(String),
+    // A newer schema or a failed migration
+    Fatal(String),
+}
+
+// Checks the schema version, then creates constraints and indexes and applies pending
+// migrations
+async fn prepare_schema(config: &'static Config) -> Result<(), StartupError> {
+    let db = db::get_db().await.map_err(|e| StartupError::Unavailable(e.to_string()))?;
+    match tokio::time::timeout(Duration::from_secs(30), migrations::check_compatible(db)).await {
+        Ok(Ok(_)) => {},
+        Ok(Err(migrations::SchemaError::Unavailable(e))) => return Err(StartupError::Unavailable(e)),
+        Ok(Err(e)) => return Err(StartupError::Fatal(e.to_string())),
+        Err(_) => return Err(StartupError::Unavailable("Neo4j did not answer in time".into())),
+    }
+    if let Err(e) = schema::ensure_schema(db).await {
+        warn!("Schema bootstrap incomplete: {}", e);
+    }
+    if config.schema.auto_migrate {
+        migrations::migrate(db).await.map_err(StartupError::Fatal)?;
+    }
+    Ok(())
+}
+
+// Neo4j was not there at startup: prepares the schema as soon as it answers, and shuts the
+// server down if the database turns out to belong to a newer build
+fn spawn_schema_check(config: &'static Config) {
+    tokio::spawn(async move {
+        let token = shutdown::token();
+        let mut delay = Duration::from_secs(1);
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {},
+                _ = token.cancelled() => return,
+            }
+            match prepare_schema(config).await {
+                Ok(()) => {
+                    info!("Neo4j is reachable, schema checked");
+                    return;
+                },
+                Err(StartupError::Unavailable(e)) => {
+                    debug!("Schema check postponed: {}", e);
+                    delay = (delay * 2).min(Duration::from_secs(60));
+                },
+                Err(StartupError::Fatal(e)) => {
+                    error!("{}", e);
+                    status::record_error(&e);
+                    shutdown::trigger(&e);
+                    return;
+                },
+            }
+        }
+    });
+}
+
+async fn serve(config: &'static Config) -> io::Result<()> {
+    info!("Starting the server...");
+    status::mark_started();
+    // Without Neo4j the server still accepts data; it stays in the WAL until the database is
+    // back and its schema has been checked. Only a schema newer than this build is fatal.
+    match prepare_schema(config).await {
+        Ok(()) => {},
+        Err(StartupError::Unavailable(e)) => {
+            warn!("Database not reachable at startup, buffering ingest in the WAL until the schema can be checked: {}", e);
+            spawn_schema_check(config);
+        },
+        Err(StartupError::Fatal(e)) => {
+            error!("{}", e);
+            return Err(io::Error::other(e));
+        },
+    }
+
+    shutdown::listen_for_signals();
+
+    let wal = wal::init().await.map_err(io::Error::other)?;
+    let flusher = wal::spawn_flusher(wal);
+
+    let metrics_server = tokio::spawn(async {
+        if let Err(e) = metrics::serve().await {
+            error!("{}", e);
+        }
+    });
+
+    let api_server = tokio::spawn(async {
+        if let Err(e) = api::serve().await {
+            error!("{}", e);
+        }
+    });
+
+    // Start MQTT client
+    let mqtt = tokio::spawn(async {
+        if let Err(e) = mqtt_handler::start_mqtt_client().await {
+            error!("MQTT client error: {:?}", e);
+        }
+    });
+
+    let password = config.server.password.clone();
+    let admin_password = config.server.admin_password.clone().filter(|p| !p.is_empty());
+    if admin_password.is_none() {
+        warn!("No admin password configured, admin-only commands are disabled");
+    }
+    let listener = TcpListener::bind(&config.server.bind).await?;
+    info!("Server is listening on {}", config.server.bind);
+    let token = shutdown::token();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = token.cancelled() => break,
+        };
+        match accepted {
+            Ok((socket, addr)) => {
+                let span = info_span!("connection", conn_id = next_connection_id(), addr = %addr);
+                let password_clone = password.clone();
+                let admin_password_clone = admin_password.clone();
+                shutdown::tracker().spawn(async move {
+                    info!("New connection");
+                    if let Err(e) = handle_client(socket, addr, password_clone, admin_password_clone).await {
+                        error!("Error handling client: {:?}", e);
+                    }
+                }.instrument(span));
+            }
+            Err(e) => error!("Failed to accept connection: {:?}", e),
+        }
+    }
+
+    // Stop accepting, let connections finish their current message, then flush the WAL
+    // and say goodbye on MQTT. Everything shares one deadline.
+    drop(listener);
+    info!("Listener closed, draining {} connections", shutdown::tracker().len());
+    let deadline = tokio::time::Instant::now() + shutdown::deadline();
+    shutdown::tracker().close();
+    if tokio::time::timeout_at(deadline, shutdown::tracker().wait()).await.is_err() {
+        warn!("{} connections still open at the shutdown deadline", shutdown::tracker().len());
+    }
+    if tokio::time::timeout_at(deadline, flusher).await.is_err() {
+        warn!("WAL flush did not finish before the shutdown deadline, {} stays on disk", wal.backlog());
+    }
+    if tokio::time::timeout_at(deadline, mqtt).await.is_err() {
+        warn!("MQTT client did not disconnect before the shutdown deadline");
+    }
+    let _ = tokio::time::timeout_at(deadline, metrics_server).await;
+    let _ = tokio::time::timeout_at(deadline, api_server).await;
+    // neo4rs has no explicit close; the connection pools are closed when the process exits
+    info!("Shutdown complete");
+    logging::shutdown();
+    Ok(())
+}
+
+fn next_connection_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+async fn handle_client(mut socket: TcpStream, addr: SocketAddr, correct_password: String, admin_password: Option<String>) -> io::Result<()> {
+    let Some(role) = auth::authenticate_client(&mut socket, &correct_password, admin_password.as_deref()).await? else {
+        return Ok(());
+    };
+    let source = format!("tcp:{}", addr);
+    let _connected = status::TcpClientGuard::new();
+    let mut session = protocol::Session::new(Transport::Tcp);
+
+    loop {
+        // A message that is already being processed is finished, but no new one is read
+        let received = tokio::select! {
+            received = receive_message(&mut socket, session.encoding()) => received,
+            _ = shutdown::token().cancelled() => {
+                let notice = json!({ "type": "shutdown", "message": "Server is shutting down" });
+                let _ = send_message(&mut socket, &notice, session.encoding()).await;
+                info!("Closing connection for shutdown");
+                break;
+            }
+        };
+        match received {
+            Ok(Some(json)) => {
+                metrics::message_received("tcp", &json);
+                // A `hello` switching encodings is still answered in the old one
+                let encoding = session.encoding();
+                let response = match session.receive(json) {
+                    Inbound::Reply(reply) => Some(reply),
+                    Inbound::Message(json) => json_handler::process_json(&json, &source, role)
+                        .instrument(logging::request_span(&json))
+                        .await
+                        .map(|response| protocol::respond(response, &json, protocol::version(&json))),
+                };
+                if let Some(response) = response {
+                    send_message(&mut socket, &response, encoding).await?;
+                }
+            },
+            Ok(None) => {
+                info!("Client disconnected.");
+                break;
+            },
+            Err(e) => {
+                error!("Error receiving message: {:?}", e);
+                break;
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+// Largest length-prefixed frame a binary-encoded client may send
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+async fn receive_message(socket: &mut TcpStream, encoding: Encoding) -> io::Result<Option<Value>> {
+    if encoding == Encoding::Json {
+        return receive_json(socket).await;
+    }
+    let length = match socket.read_u32().await {
+        Ok(length) => length as usize,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if length > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes exceeds {} bytes", length, MAX_FRAME)));
+    }
+    let mut frame = vec![0; length];
+    socket.read_exact(&mut frame).await?;
+    match encoding.decode::<Value>(&frame) {
+        Ok(json) => {
+            debug!("Received {}: {}", encoding.name(), logging::payload(&json));
+            Ok(Some(json))
+        },
+        Err(e) => {
+            error!("Invalid {} message received: {}", encoding.name(), e);
+            let notice = json!({ "type": "error", "message": format!("Invalid {} message: {}", encoding.name(), e) });
+            send_message(socket, &notice, encoding).await?;
+            Err(io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+}
+
+// JSON responses end with a newline; binary ones are prefixed with their length
+async fn send_message(socket: &mut TcpStream, message: &Value, encoding: Encoding) -> io::Result<()> {
+    let mut bytes = encoding.encode(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    match encoding {
+        Encoding::Json => bytes.push(b'\n'),
+        _ => {
+            let length = u32::try_from(bytes.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "response too large for a frame"))?;
+            bytes.splice(0..0, length.to_be_bytes());
+        },
+    }
+    socket.write_all(&bytes).await
+}
+
+async fn receive_json(socket: &mut TcpStream) -> io::Result<Option<Value>> {
+    let mut buf = [0; 4096];
+    let n = socket.read(&mut buf).await?;
+    
+    if n == 0 {
+        return Ok(None); // Client disconnected
+    }
+    
+    let data = String::from_utf8_lossy(&buf[..n]);
+    match serde_json::from_str::<Value>(&data) {
+        Ok(json) => {
+            debug!("Received JSON: {}", logging::payload(&json));
+            Ok(Some(json))
+        },
+        Err(e) => {
+            error!("Invalid JSON received: {:?}", e);
+            socket.write_all(b"Error: Invalid JSON format\n").await?;
+            Err(io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use jsonschema::error::ValidationErrorKind;
+use jsonschema::Validator;
+use serde_json::{Map, Value, json};
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+use crate::codec::{COMPRESSIONS, ENCODINGS};
+use crate::protocol;
+
+// JSON Schemas for every inbound message type. Messages are checked against them before
+// they are handled, and the sender gets each problem with the JSON pointer of the field
+// that caused it. Clients fetch the schemas with `{"type": "schema"}`.
+//
+// The schemas describe envelopes, not readings: records inside a `data` batch are still
+// validated one by one and quarantined when broken, so one bad record never refuses a batch.
+// Unknown fields are allowed, since older clients send extra ones (e.g. command arguments
+// next to `command`). Messages are validated in their version 1 shape, see protocol.rs.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Mqtt,
+    Live,
+}
+
+const TCP_TYPES: &[&str] = &["hello", "message", "command", "data", "schema"];
+const QUERY_TYPES: &[&str] = &[
+    "hello", "uuid", "all", "color", "time_range", "temperature_humidity", "timestamp", "energy_cost", "energy_consume",
+    "register_device", "update_device", "decommission_device", "status", "command", "device", "devices", "schema",
+];
+const LIVE_TYPES: &[&str] = &["subscribe", "unsubscribe"];
+
+impl Transport {
+    // Message types the transport accepts
+    pub fn types(self) -> Vec<&'static str> {
+        match self {
+            Transport::Tcp => TCP_TYPES.to_vec(),
+            Transport::Mqtt => QUERY_TYPES.to_vec(),
+            Transport::Live => QUERY_TYPES.iter().chain(LIVE_TYPES).copied().collect(),
+        }
+    }
+
+    // Wire encodings the transport understands, see codec.rs
+    pub fn encodings(self) -> Vec<&'static str> {
+        match self {
+            Transport::Tcp | Transport::Mqtt => ENCODINGS.to_vec(),
+            Transport::Live => vec!["json"],
+        }
+    }
+
+    // Capabilities announced in the `hello` response
+    pub fn features(self) -> Vec<&'static str> {
+        match self {
+            Transport::Tcp => vec!["message_ids", "command_roles"],
+            Transport::Mqtt => vec!["client_targeting", "chunked_responses", "compressed_responses"],
+            Transport::Live => vec!["subscriptions", "threshold_alerts", "request_ids"],
+        }
+    }
+}
+
+// A message with the given extra properties, `required` among them
+fn message(kind: &str, description: &str, properties: Value, required: &[&str]) -> Value {
+    let mut all = Map::new();
+    all.insert("type".into(), json!({ "const": kind }));
+    // Echoed in WebSocket responses; `client_id` addresses one MQTT server instance
+    all.insert("request_id".into(), json!({}));
+    all.insert("client_id".into(), json!({ "type": "string" }));
+    all.insert("version".into(), json!({ "enum": protocol::VERSIONS }));
+    // Encoding of the response over MQTT, when it should differ from the request's
+    all.insert("accept".into(), json!({ "enum": ENCODINGS }));
+    // Compression of the MQTT response before it is chunked
+    all.insert("compression".into(), json!({ "enum": COMPRESSIONS }));
+    if let Value::Object(properties) = properties {
+        all.extend(properties);
+    }
+    let mut required_fields = vec!["type"];
+    required_fields.extend_from_slice(required);
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": kind,
+        "description": description,
+        "type": "object",
+        "properties": all,
+        "required": required_fields
+    })
+}
+
+fn string() -> Value {
+    json!({ "type": "string", "minLength": 1 })
+}
+
+fn strings() -> Value {
+    json!({ "type": "array", "items": { "type": "string" } })
+}
+
+fn device_spec(id_only: bool) -> Value {
+    let mut properties = json!({ "id": string() });
+    if !id_only {
+        for field in ["name", "location", "type", "owner"] {
+            properties[field] = json!({ "type": ["string", "null"] });
+        }
+        properties["tags"] = strings();
+        properties["reporting_interval"] = json!({ "type": ["integer", "null"], "minimum": 1 });
+    }
+    json!({ "type": "object", "properties": properties, "required": ["id"] })
+}
+
+fn range() -> Value {
+    json!({
+        "type": "object",
+        "properties": { "min": { "type": "number" }, "max": { "type": "number" } },
+        "additionalProperties": false
+    })
+}
+
+static SCHEMAS: LazyLock<BTreeMap<&'static str, Value>> = LazyLock::new(|| {
+    let none = json!({});
+    BTreeMap::from([
+        ("hello", message("hello", "Negotiates the protocol version; answers with versions and features", json!({
+            "versions": { "type": "array", "items": { "type": "integer" }, "minItems": 1 },
+            "encoding": { "enum": ENCODINGS }
+        }), &[])),
+        ("message", message("message", "Free text that is logged", json!({ "content": {} }), &[])),
+        ("command", message("command", "Runs a registry command, see the `help` command", json!({
+            "command": string(),
+            "args": { "type": "object" }
+        }), &["command"])),
+        ("data", message("data", "A batch of readings to ingest", json!({
+            "data": { "type": "array", "items": { "type": "object" } },
+            "message_id": { "type": "string" }
+        }), &["data"])),
+        ("uuid", message("uuid", "Latest reading of a device UUID", json!({ "data": string() }), &["data"])),
+        ("all", message("all", "Latest reading of every device", none.clone(), &[])),
+        ("color", message("color", "Readings with a color", json!({ "data": string() }), &["data"])),
+        ("time_range", message("time_range", "Readings between two timestamps", json!({
+            "start": string(),
+            "end": string()
+        }), &["start", "end"])),
+        ("temperature_humidity", message("temperature_humidity", "Readings with a temperature or humidity", json!({
+            "temperature": { "type": "number" },
+            "humidity": { "type": "number" }
+        }), &["temperature", "humidity"])),
+        ("timestamp", message("timestamp", "Temperature and humidity at a timestamp", json!({ "data": string() }), &["data"])),
+        ("energy_cost", message("energy_cost", "Readings with an energy cost", json!({ "data": { "type": "number" } }), &["data"])),
+        ("energy_consume", message("energy_consume", "Readings with an energy consumption", json!({ "data": { "type": "number" } }), &["data"])),
+        ("register_device", message("register_device", "Registers a device; needs the admin role", json!({ "device": device_spec(false) }), &["device"])),
+        ("update_device", message("update_device", "Updates the given fields of a device; needs the admin role", json!({ "device": device_spec(false) }), &["device"])),
+        ("decommission_device", message("decommission_device", "Decommissions a device; needs the admin role", json!({ "device": device_spec(true) }), &["device"])),
+        ("status", message("status", "Server status", none.clone(), &[])),
+        ("device", message("device", "A registered device", json!({ "data": string() }), &["data"])),
+        ("devices", message("devices", "Registered devices matching all filters", json!({
+            "filters": {
+                "type": "object",
+                "properties": {
+                    "status": { "type": "string" },
+                    "location": { "type": "string" },
+                    "type": { "type": "string" },
+                    "owner": { "type": "string" },
+                    "tag": { "type": "string" }
+                }
+            }
+        }), &[])),
+        ("subscribe", message("subscribe", "Starts or replaces the live stream subscription", json!({
+            "devices": strings(),
+            "colors": strings(),
+            "thresholds": {
+                "type": "object",
+                "properties": {
+                    "temperature": range(),
+                    "humidity": range(),
+                    "energy_consume": range(),
+                    "energy_cost": range()
+                },
+                "additionalProperties": false
+            },
+            "alerts_only": { "type": "boolean" }
+        }), &[])),
+        ("unsubscribe", message("unsubscribe", "Stops the live stream", none.clone(), &[])),
+        ("schema", message("schema", "These schemas, or the one of a single message type", json!({ "for": string() }), &[])),
+    ])
+});
+
+static VALIDATORS: LazyLock<BTreeMap<&'static str, Validator>> = LazyLock::new(|| {
+    SCHEMAS.iter()
+        .map(|(kind, schema)| (*kind, jsonschema::validator_for(schema).expect("message schemas are valid")))
+        .collect()
+});
+
+fn problem(path: &str, message: impl Into<String>) -> Value {
+    json!({ "path": path, "message": message.into() })
+}
+
+// Every problem with the message as `{"path": <JSON pointer>, "message": ..}`; empty when
+// it is valid for the transport
+pub fn validate(json: &Value, transport: Transport) -> Vec<Value> {
+    if !json.is_object() {
+        return vec![problem("", "Message must be a JSON object")];
+    }
+    let Some(kind) = json.get("type") else {
+        return vec![problem("/type", "'type' is required")];
+    };
+    let types = transport.types();
+    let Some(validator) = kind.as_str().filter(|kind| types.contains(kind)).and_then(|kind| VALIDATORS.get(kind)) else {
+        return vec![problem("/type", format!("{} is not one of {}", kind, types.join(", ")))];
+    };
+
+    validator.iter_errors(json).map(|error| {
+        let mut path = error.instance_path.as_str().to_string();
+        // Point at the missing field rather than at the object lacking it
+        if let ValidationErrorKind::Required { property } = &error.kind {
+            path = format!("{}/{}", path, property.as_str().unwrap_or_default());
+        }
+        problem(&path, error.to_string())
+    }).collect()
+}
+
+// The reply to a message that failed validation
+pub fn error_response(json: &Value, errors: Vec<Value>) -> Value {
+    json!({
+        "type": "error",
+        "request": json.get("type"),
+        "message": "Message does not match its schema",
+        "errors": errors
+    })
+}
+
+// Answers `{"type": "schema"}` with all schemas of the transport, or `{"for": <type>}` with
+// one, in the protocol version of the request
+pub fn describe(json: &Value, transport: Transport) -> Result<Value, String> {
+    let version = json.get("version").and_then(Value::as_u64).unwrap_or(1);
+    let types = transport.types();
+    match json.get("for").and_then(Value::as_str) {
+        Some(kind) if types.contains(&kind) => Ok(protocol::schema_for(&SCHEMAS[kind], version)),
+        Some(kind) => Err(format!("No message type '{}', expected one of {}", kind, types.join(", "))),
+        None => Ok(json!(types.iter()
+            .map(|kind| (*kind, protocol::schema_for(&SCHEMAS[kind], version)))
+            .collect::<BTreeMap<_, _>>())),
+    }
+}
@@ -0,0 +1,155 @@
+use axum::Router;
+use axum::http::{StatusCode, header};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use log::{info, error};
+use prometheus::{Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use serde_json::Value;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config;
+use crate::shutdown;
+use crate::status;
+use crate::wal;
+
+// Prometheus metrics served on `metrics.bind` (default 0.0.0.0:9100) at /metrics.
+// Ingest counters, TCP connections and the WAL backlog are kept by `status` and copied
+// over on every scrape; everything else is recorded here directly.
+static REGISTRY: LazyLock<Registry> = LazyLock::new(|| Registry::new_custom(Some("datacenter".into()), None).expect("valid metrics prefix"));
+
+// Message types we label by name; anything else is counted as "other" so clients can't
+// create unbounded label values
+const KNOWN_TYPES: &[&str] = &[
+    "message", "command", "data",
+    "uuid", "all", "color", "time_range", "temperature_humidity", "timestamp", "energy_cost", "energy_consume",
+    "register_device", "update_device", "decommission_device", "status", "device", "devices",
+    "subscribe", "unsubscribe", "graphql", "schema", "hello",
+];
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY.register(Box::new(metric.clone())).expect("metric registered twice");
+    metric
+}
+
+pub static MESSAGES_RECEIVED: LazyLock<IntCounterVec> = LazyLock::new(|| register(IntCounterVec::new(
+    Opts::new("messages_received_total", "Messages received, by transport and message type"),
+    &["transport", "type"],
+).unwrap()));
+
+pub static NEO4J_QUERY_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| register(HistogramVec::new(
+    HistogramOpts::new("neo4j_query_duration_seconds", "Neo4j query latency, by query function")
+        .buckets(vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]),
+    &["query"],
+).unwrap()));
+
+pub static MQTT_PUBLISHES: LazyLock<IntCounterVec> = LazyLock::new(|| register(IntCounterVec::new(
+    Opts::new("mqtt_publishes_total", "MQTT messages published, by kind (response, chunk, summary)"),
+    &["kind"],
+).unwrap()));
+
+pub static CHUNKED_RESPONSES: LazyLock<IntCounterVec> = LazyLock::new(|| register(IntCounterVec::new(
+    Opts::new("chunked_responses_total", "Responses too large for one message that were split into chunks"),
+    &["transport"],
+).unwrap()));
+
+pub static COMPRESSED_RESPONSES: LazyLock<IntCounterVec> = LazyLock::new(|| register(IntCounterVec::new(
+    Opts::new("compressed_responses_total", "MQTT responses compressed on the client's request"),
+    &["compression"],
+).unwrap()));
+
+pub static AUTH_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| register(IntCounter::new(
+    "auth_failures_total", "Clients that sent a wrong TCP password or HTTP bearer token",
+).unwrap()));
+
+static RECORDS_RECEIVED: LazyLock<IntCounter> = LazyLock::new(|| register(IntCounter::new(
+    "records_received_total", "Records received in data batches",
+).unwrap()));
+
+static RECORDS_INGESTED: LazyLock<IntCounter> = LazyLock::new(|| register(IntCounter::new(
+    "records_ingested_total", "Records written to Neo4j",
+).unwrap()));
+
+static RECORDS_REJECTED: LazyLock<IntCounterVec> = LazyLock::new(|| register(IntCounterVec::new(
+    Opts::new("records_rejected_total", "Records not written, by reason (invalid, unknown_device, duplicate)"),
+    &["reason"],
+).unwrap()));
+
+static TCP_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| register(IntGauge::new(
+    "tcp_connections_active", "Authenticated TCP clients currently connected",
+).unwrap()));
+
+static WAL_PENDING_BATCHES: LazyLock<IntGauge> = LazyLock::new(|| register(IntGauge::new(
+    "wal_pending_batches", "Batches in the write-ahead log not yet written to Neo4j",
+).unwrap()));
+
+static WAL_PENDING_RECORDS: LazyLock<IntGauge> = LazyLock::new(|| register(IntGauge::new(
+    "wal_pending_records", "Records in the write-ahead log not yet written to Neo4j",
+).unwrap()));
+
+pub fn message_received(transport: &str, json: &Value) {
+    let kind = json.get("type").and_then(Value::as_str)
+        .filter(|kind| KNOWN_TYPES.contains(kind))
+        .unwrap_or("other");
+    MESSAGES_RECEIVED.with_label_values(&[transport, kind]).inc();
+}
+
+// Observes the query's duration when dropped
+pub fn query_timer(query: &str) -> HistogramTimer {
+    NEO4J_QUERY_SECONDS.with_label_values(&[query]).start_timer()
+}
+
+// Counters only go up, so the status atomics are copied by adding the difference
+fn sync_counter(counter: &IntCounter, source: &AtomicU64) {
+    let current = source.load(Ordering::Relaxed);
+    counter.inc_by(current.saturating_sub(counter.get()));
+}
+
+fn sync_from_status() {
+    sync_counter(&RECORDS_RECEIVED, &status::RECORDS_RECEIVED);
+    sync_counter(&RECORDS_INGESTED, &status::RECORDS_WRITTEN);
+    sync_counter(&RECORDS_REJECTED.with_label_values(&["invalid"]), &status::RECORDS_QUARANTINED);
+    sync_counter(&RECORDS_REJECTED.with_label_values(&["unknown_device"]), &status::RECORDS_REJECTED);
+    sync_counter(&RECORDS_REJECTED.with_label_values(&["duplicate"]), &status::DUPLICATES);
+    TCP_CONNECTIONS.set(status::TCP_CLIENTS.load(Ordering::Relaxed) as i64);
+
+    if let Some(wal) = wal::WAL.get() {
+        let backlog = wal.backlog();
+        WAL_PENDING_BATCHES.set(backlog["pending_batches"].as_i64().unwrap_or(0));
+        WAL_PENDING_RECORDS.set(backlog["pending_records"].as_i64().unwrap_or(0));
+    }
+}
+
+pub fn render() -> Result<String, String> {
+    sync_from_status();
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)
+        .map_err(|e| format!("Failed to encode metrics: {}", e))?;
+    String::from_utf8(buffer).map_err(|e| format!("Failed to encode metrics: {}", e))
+}
+
+async fn metrics_handler() -> impl IntoResponse {
+    match render() {
+        Ok(body) => (StatusCode::OK, [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response(),
+        Err(e) => {
+            error!("{}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e).into_response()
+        },
+    }
+}
+
+fn router() -> Router {
+    Router::new().route("/metrics", get(metrics_handler))
+}
+
+// Serves /metrics until shutdown
+pub async fn serve() -> Result<(), String> {
+    let addr = &config::get().metrics.bind;
+    let listener = tokio::net::TcpListener::bind(addr).await
+        .map_err(|e| format!("Failed to bind metrics endpoint on {}: {}", addr, e))?;
+    info!("Metrics available on http://{}/metrics", addr);
+    axum::serve(listener, router())
+        .with_graceful_shutdown(shutdown::token().cancelled())
+        .await
+        .map_err(|e| format!("Metrics server failed: {}", e))
+}
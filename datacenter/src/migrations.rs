@@ -0,0 +1,194 @@
+use log::{info, error};
+use neo4rs::{Graph, query};
+use serde_json::{Value, json};
+use std::fmt;
+use std::sync::LazyLock;
+use tokio::sync::watch;
+
+// Ordered graph migrations compiled into the binary. The version of the last applied
+// migration is stored on the single `SchemaVersion` node. Never edit a released
+// migration, add a new one with the next version instead.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub statements: &'static [&'static str],
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "baseline",
+        statements: &[],
+    },
+    Migration {
+        version: 2,
+        name: "device_status_default",
+        statements: &[
+            "MATCH (d:Device) WHERE d.status IS NULL SET d.status = 'active'",
+        ],
+    },
+    Migration {
+        version: 3,
+        name: "rejected_record_source_default",
+        statements: &[
+            "MATCH (r:RejectedRecord) WHERE r.source IS NULL SET r.source = 'unknown'",
+            "MATCH (r:RejectedRecord) WHERE r.id IS NULL SET r.id = randomUUID()",
+        ],
+    },
+    Migration {
+        version: 4,
+        name: "reading_color_and_consumption",
+        // Readings now link their color and consumption like their other values. Older
+        // readings only get them when the device ever reported a single one.
+        statements: &[
+            "MATCH (u:UUID)-[:HAS_COLOR]->(c:Color) WITH u, collect(c) AS colors WHERE size(colors) = 1 \
+             MATCH (u)-[:HAS_TIMESTAMP]->(t:Timestamp) WITH t, colors[0] AS c MERGE (t)-[:HAS_COLOR]->(c)",
+            "MATCH (u:UUID)-[:HAS_ENERGYCONSUME]->(e:EnergyConsume) WITH u, collect(e) AS values WHERE size(values) = 1 \
+             MATCH (u)-[:HAS_TIMESTAMP]->(t:Timestamp) WITH t, values[0] AS e MERGE (t)-[:HAS_CONSUMPTION]->(e)",
+        ],
+    },
+];
+
+// Set once a schema check passed. Writers wait for it, so nothing is written to a database
+// that turns out to belong to a newer build.
+static COMPATIBLE: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::channel(false).0);
+
+#[derive(Debug)]
+pub enum SchemaError {
+    // The database was migrated by a newer build
+    Newer { current: i64, latest: i64 },
+    // The version could not be read, usually because Neo4j is not reachable
+    Unavailable(String),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::Newer { current, latest } => write!(
+                f, "Database schema version {} is newer than the latest version {} known to this build", current, latest
+            ),
+            SchemaError::Unavailable(e) => f.write_str(e),
+        }
+    }
+}
+
+impl From<SchemaError> for String {
+    fn from(error: SchemaError) -> String {
+        error.to_string()
+    }
+}
+
+pub fn is_compatible() -> bool {
+    *COMPATIBLE.borrow()
+}
+
+// Resolves once a schema check has passed
+pub async fn compatible() {
+    let _ = COMPATIBLE.subscribe().wait_for(|compatible| *compatible).await;
+}
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+pub async fn current_version(graph: &Graph) -> Result<i64, String> {
+    let version_query = query(r#"
+        OPTIONAL MATCH (v:SchemaVersion {id: 'datacenter'})
+        RETURN coalesce(v.version, 0) AS version
+    "#);
+    let mut result = graph.execute(version_query).await
+        .map_err(|e| format!("Failed to read schema version: {}", e))?;
+    match result.next().await {
+        Ok(Some(row)) => Ok(row.get::<i64>("version").unwrap_or(0)),
+        Ok(None) => Ok(0),
+        Err(e) => Err(format!("Failed to read schema version: {}", e)),
+    }
+}
+
+fn pending(current: i64) -> Vec<&'static Migration> {
+    MIGRATIONS.iter().filter(|m| m.version > current).collect()
+}
+
+fn describe(migration: &Migration) -> Value {
+    json!({
+        "version": migration.version,
+        "name": migration.name,
+        "statements": migration.statements
+    })
+}
+
+// Refuses to run against a database migrated by a newer build
+pub async fn check_compatible(graph: &Graph) -> Result<i64, SchemaError> {
+    let current = current_version(graph).await.map_err(SchemaError::Unavailable)?;
+    if current > latest_version() {
+        return Err(SchemaError::Newer { current, latest: latest_version() });
+    }
+    COMPATIBLE.send_replace(true);
+    Ok(current)
+}
+
+pub async fn status(graph: &Graph) -> Result<Value, String> {
+    let current = current_version(graph).await?;
+    Ok(json!({
+        "current_version": current,
+        "latest_version": latest_version(),
+        "pending": pending(current).into_iter().map(|m| json!({ "version": m.version, "name": m.name })).collect::<Vec<_>>()
+    }))
+}
+
+// Lists the migrations `migrate` would apply, with their statements, without touching the database
+pub async fn dry_run(graph: &Graph) -> Result<Value, String> {
+    let current = check_compatible(graph).await?;
+    Ok(json!({
+        "current_version": current,
+        "would_apply": pending(current).into_iter().map(describe).collect::<Vec<_>>()
+    }))
+}
+
+// Applies all pending migrations in order. Each migration and its version bump run in one
+// transaction, so a failed migration leaves the database at the previous version.
+pub async fn migrate(graph: &Graph) -> Result<Value, String> {
+    let current = check_compatible(graph).await?;
+    let mut applied = Vec::new();
+
+    for migration in pending(current) {
+        info!("Applying migration {} ({})", migration.version, migration.name);
+        let mut txn = graph.start_txn().await
+            .map_err(|e| format!("Failed to start transaction for migration {}: {}", migration.version, e))?;
+
+        for statement in migration.statements {
+            if let Err(e) = txn.run(query(statement)).await {
+                error!("Migration {} ({}) failed: {}", migration.version, migration.name, e);
+                if let Err(rollback_err) = txn.rollback().await {
+                    error!("Rollback failed: {}", rollback_err);
+                }
+                return Err(format!("Migration {} ({}) failed: {}", migration.version, migration.name, e));
+            }
+        }
+
+        let version_query = query(r#"
+            MERGE (v:SchemaVersion {id: 'datacenter'})
+            SET v.version = $version,
+                v.name = $name,
+                v.applied_at = toString(datetime()),
+                v.history = coalesce(v.history, []) + [$entry]
+        "#)
+        .param("version", migration.version)
+        .param("name", migration.name)
+        .param("entry", format!("{}:{}", migration.version, migration.name));
+        if let Err(e) = txn.run(version_query).await {
+            if let Err(rollback_err) = txn.rollback().await {
+                error!("Rollback failed: {}", rollback_err);
+            }
+            return Err(format!("Failed to record schema version {}: {}", migration.version, e));
+        }
+
+        txn.commit().await
+            .map_err(|e| format!("Failed to commit migration {}: {}", migration.version, e))?;
+        applied.push(json!({ "version": migration.version, "name": migration.name }));
+    }
+
+    let version = current_version(graph).await?;
+    info!("Schema at version {} ({} migrations applied)", version, applied.len());
+    Ok(json!({ "current_version": version, "applied": applied }))
+}
@@ -0,0 +1,207 @@
+use async_graphql::{Enum, InputObject, SimpleObject};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::{IntoParams, ToSchema};
+
+// Shapes returned by the query layer, the REST API and the GraphQL schema. The OpenAPI
+// document served at /openapi.json and the GraphQL schema are generated from these types,
+// so a field added here shows up in both.
+
+/// Sensor values of one reading
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, SimpleObject)]
+pub struct SensorData {
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+}
+
+/// One reading in the format it is ingested and exported in
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, SimpleObject)]
+#[graphql(complex)]
+#[schema(example = json!({
+    "uuid": "abc123xyz001",
+    "color": "blue",
+    "sensor_data": { "temperature": 22.1, "humidity": 60 },
+    "timestamp": "2025-03-10 14:30:00",
+    "energy_consume": 0.3,
+    "energy_cost": 0.007
+}))]
+pub struct ReadingRecord {
+    /// Device UUID
+    pub uuid: String,
+    pub color: Option<String>,
+    pub sensor_data: SensorData,
+    /// Compared as a string, e.g. "2025-03-10 14:30:00"
+    pub timestamp: String,
+    pub energy_consume: Option<f64>,
+    pub energy_cost: Option<f64>,
+}
+
+/// A batch of readings for ingest. Records failing validation are quarantined, not refused.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct IngestBatch {
+    pub data: Vec<ReadingRecord>,
+    /// Retries with the same id within the dedupe window are acknowledged as duplicates
+    pub message_id: Option<String>,
+}
+
+/// Acknowledgement of an ingest batch, the same over TCP, MQTT and HTTP
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct IngestAck {
+    /// Always "ack"
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// accepted, duplicate or error
+    pub status: String,
+    pub message_id: Option<String>,
+    /// Position in the write-ahead log
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub records: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duplicates: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl IngestAck {
+    pub fn new(status: &str, message_id: Option<&str>) -> Self {
+        IngestAck {
+            kind: "ack".to_string(),
+            status: status.to_string(),
+            message_id: message_id.map(str::to_string),
+            seq: None,
+            records: None,
+            duplicates: None,
+            message: None,
+        }
+    }
+
+    pub fn error(message_id: Option<&str>, message: impl Into<String>) -> Self {
+        IngestAck { message: Some(message.into()), ..IngestAck::new("error", message_id) }
+    }
+}
+
+/// Readings matching a query, oldest first
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ReadingsPage {
+    pub count: usize,
+    pub limit: usize,
+    pub readings: Vec<ReadingRecord>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema, SimpleObject)]
+pub struct Stats {
+    pub avg: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+/// Statistics over a set of readings
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, SimpleObject)]
+pub struct Aggregate {
+    /// Device UUID or color when grouped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    pub readings: i64,
+    pub temperature: Stats,
+    pub humidity: Stats,
+    /// Sum over all readings
+    pub energy_consume: Option<f64>,
+    /// Sum over all readings
+    pub energy_cost: Option<f64>,
+}
+
+/// A registered device
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, SimpleObject)]
+#[graphql(complex)]
+pub struct Device {
+    pub id: String,
+    pub name: Option<String>,
+    pub location: Option<String>,
+    #[serde(rename = "type")]
+    #[graphql(name = "type")]
+    pub device_type: Option<String>,
+    pub owner: Option<String>,
+    pub tags: Vec<String>,
+    /// Expected seconds between readings
+    pub reporting_interval: Option<i64>,
+    /// active or decommissioned
+    pub status: String,
+    pub registered_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub decommissioned_at: Option<String>,
+}
+
+/// A device id with its registration and latest reading; either may be missing
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DeviceDetails {
+    pub id: String,
+    pub device: Option<Device>,
+    pub latest_reading: Option<ReadingRecord>,
+}
+
+/// Filters for reading queries
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReadingParams {
+    /// Earliest timestamp
+    pub from: Option<String>,
+    /// Latest timestamp
+    pub to: Option<String>,
+    /// Comma-separated device UUIDs
+    pub device: Option<String>,
+    /// Comma-separated colors
+    pub color: Option<String>,
+    /// Maximum number of readings, 1 to 10000 (default 1000)
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, ToSchema, Enum)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupBy {
+    Device,
+    Color,
+}
+
+/// Filters and grouping for aggregates
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AggregateParams {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// Comma-separated device UUIDs
+    pub device: Option<String>,
+    /// Comma-separated colors
+    pub color: Option<String>,
+    /// One aggregate per device or color instead of a single one
+    pub group_by: Option<GroupBy>,
+}
+
+/// Device list filters; all must match
+#[derive(Debug, Clone, Default, Serialize, Deserialize, IntoParams, InputObject)]
+#[into_params(parameter_in = Query)]
+#[graphql(name = "DeviceFilter")]
+pub struct DeviceParams {
+    pub status: Option<String>,
+    pub location: Option<String>,
+    #[serde(rename = "type")]
+    #[param(rename = "type")]
+    #[graphql(name = "type")]
+    pub device_type: Option<String>,
+    pub owner: Option<String>,
+    pub tag: Option<String>,
+}
+
+/// Result of a registry command
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CommandResponse {
+    pub command: String,
+    /// Command specific, see the `help` command
+    pub result: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: String,
+}
@@ -1,375 +1,431 @@
-use rumqttc::{MqttOptions, AsyncClient, Event, Incoming, QoS, Transport};
-use tokio::time::{Duration, Instant};
-use log::{info, error, warn};
-use serde_json::{Value, Result as JsonResult};
-use std::env;
-use std::error::Error;
-use uuid::Uuid;
-use crate::query::get_specific_uuid_node;
-use crate::db::get_db;
-use neo4rs::Graph;
-
-pub async fn start_mqtt_client() -> Result<(), Box<dyn Error>> {
-    // Generate a unique client ID for this connection
-    let client_id = format!("rust-mqtt-client-{}", Uuid::new_v4());
-    
-    let mut mqtt_options = MqttOptions::new(
-        &client_id,
-        "mosquitto-broker",
-        1883
-    );
-    
-    mqtt_options.set_credentials(
-        env::var("MQTT_USER").unwrap_or_else(|_| "admin".into()),
-        env::var("MQTT_PASSWORD").unwrap_or_else(|_| "admin".into())
-    );
-    let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
-    
-    // Verbindungs-Timeout
-    let connect_timeout = Duration::from_secs(10);
-    let start_time = Instant::now();
-    let mut connected = false;
-    // Phase 1: Verbindungsherstellung 
-    while Instant::now().duration_since(start_time) < connect_timeout {
-        match eventloop.poll().await {
-            Ok(Event::Incoming(Incoming::ConnAck(ack))) => {
-                info!("✅ Broker-Verbindung hergestellt: {:?}, Client-ID: {}", ack, client_id);
-                connected = true;
-                break;
-            },
-            Ok(event) => warn!("Zwischenereignis: {:?}", event),
-            Err(e) => {
-                error!("❌ Verbindungsfehler: {}", e);
-                return Err(e.into());
-            }
-        }
-    }
-    if !connected {
-        error!("⌛ Timeout: Broker nicht erreichbar!");
-        return Err("Broker offline".into());
-    }
-    // Phase 2: Normalbetrieb
-    info!("🚀 Starte normalen Betrieb... Client-ID: {}", client_id);
-    
-    // Publish our client ID to a central topic so other clients know we exist
-    let connection_message = serde_json::json!({
-        "type": "client_connect",
-        "client_id": client_id
-    });
-    
-    client.publish(
-        "rust/clients", 
-        QoS::AtLeastOnce, 
-        false, 
-        serde_json::to_vec(&connection_message)?
-    ).await?;
-    
-    // Subscribe to the general topic
-    client.subscribe("rust/topic", QoS::AtMostOnce).await?;
-    
-    // Also subscribe to our client-specific topic
-    let client_topic = format!("rust/topic/{}", client_id);
-    client.subscribe(&client_topic, QoS::AtMostOnce).await?;
-    
-    loop {
-        match eventloop.poll().await {
-            Ok(Event::Incoming(Incoming::Publish(publish))) => {
-                let db = match get_db().await {
-                    Ok(db) => db,
-                    Err(e) => {
-                        error!("Database connection failed: {}", e);
-                        continue;
-                    },
-                };
-                handle_message(&publish, &client, &db, &client_id).await?;
-            },
-            Ok(Event::Incoming(Incoming::Disconnect)) => {
-                info!("🔌 Verbindung getrennt für Client-ID: {}", client_id);
-                break;
-            },
-            Err(e) => {
-                error!("⚠️ Fehler im Eventloop: {}, Client-ID: {}", e, client_id);
-                break;
-            }
-            _ => {}
-        }
-    }
-    Ok(())
-}
-
-async fn handle_message(
-    publish: &rumqttc::Publish,
-    client: &AsyncClient,
-    db: &Graph,
-    client_id: &str
-) -> Result<(), Box<dyn Error>> {
-    info!("📨 Nachricht empfangen: {:?} für Client-ID: {}", publish.payload, client_id);
-    let payload_str = std::str::from_utf8(&publish.payload)?;
-    
-    // Check if this message is for us specifically
-    let is_client_specific = publish.topic.contains(client_id);
-    
-    // Parse the message
-    parse_and_process_json(payload_str, &db, client, client_id, is_client_specific).await?;
-    
-    Ok(())
-}
-
-async fn parse_and_process_json(
-    json_str: &str, 
-    db: &Graph,
-    client: &AsyncClient,
-    client_id: &str,
-    is_client_specific: bool
-) -> Result<(), Box<dyn std::error::Error>> {
-    let parsed: serde_json::Result<Value> = serde_json::from_str(json_str);
-    
-    match parsed {
-        Ok(json_value) => {
-            // Check if the message has a target client_id
-            let target_client_id = json_value.get("client_id").and_then(Value::as_str);
-            
-            // Only process if:
-            // 1. No specific target is specified, OR
-            // 2. We are the specific target, OR
-            // 3. Message came in on our specific topic (is_client_specific)
-            if target_client_id.is_none() || target_client_id == Some(client_id) || is_client_specific {
-                match json_value.get("type").and_then(Value::as_str) {
-                    Some("uuid") => {
-                        // Process UUID type
-                        if let Some(data) = json_value.get("data").and_then(Value::as_str) {
-                            info!("Processing UUID: {} for Client-ID: {}", data, client_id);
-                            match get_specific_uuid_node(data, &db).await {
-                                Some(node) => {
-                                    info!("Found node for UUID {}: {:?}", data, node);
-                                    // Use client-specific response topic
-                                    let response_topic = format!("rust/response/{}/{}", client_id, data);
-                                    publish_result(client, &response_topic, &node).await?;
-                                },
-                                None => {
-                                    info!("No node found for UUID: {}", data);
-                                    let response_topic = format!("rust/response/{}/{}", client_id, data);
-                                    let empty_response = serde_json::json!({
-                                        "uuid": data,
-                                        "found": false,
-                                        "message": "No data found for this UUID"
-                                    });
-                                    publish_result(client, &response_topic, &empty_response).await?;
-                                }
-                            }
-                        } else {
-                            error!("Missing or invalid 'data' field for type 'uuid'. Input: {}", json_str);
-                        }
-                    },
-                    Some("all") => {
-                        info!("Processing 'all' request for Client-ID: {}", client_id);
-                        match crate::query::get_all_uuid_nodes(&db).await {
-                            Some(all_nodes) => {
-                                let response_topic = format!("rust/response/{}/all", client_id);
-                                publish_result(client, &response_topic, &all_nodes).await?;
-                            },
-                            None => {
-                                error!("Failed to get all UUID nodes for Client-ID: {}", client_id);
-                            }
-                        }
-                    },
-                    Some("color") => {
-                        info!("Processing 'color' data for Client-ID: {}", client_id);
-                        if let Some(color_data) = json_value.get("data").and_then(Value::as_str) {
-                            match crate::query::get_nodes_with_color(color_data, &db).await {
-                                Some(processed) => {
-                                    let response_topic = format!("rust/response/{}/color", client_id);
-                                    publish_result(client, &response_topic, &processed).await?;
-                                },
-                                None => {
-                                    error!("Failed to get nodes with color: {} for Client-ID: {}", color_data, client_id);
-                                }
-                            }
-                        } else {
-                            error!("Missing or invalid 'data' field for type 'color'. Input: {}", json_str);
-                        }
-                    },
-                    Some("time_range") => {
-                        info!("Processing 'time_range' data for Client-ID: {}", client_id);
-                        let start = json_value.get("start").and_then(Value::as_str);
-                        let end = json_value.get("end").and_then(Value::as_str);
-                        
-                        if let (Some(start_time), Some(end_time)) = (start, end) {
-                            match crate::query::get_nodes_in_time_range(start_time, end_time, &db).await {
-                                Some(nodes) => {
-                                    let response_topic = format!("rust/response/{}/time_range", client_id);
-                                    publish_result(client, &response_topic, &nodes).await?;
-                                },
-                                None => {
-                                    error!("Failed to get nodes in time range from {} to {} for Client-ID: {}", 
-                                           start_time, end_time, client_id);
-                                }
-                            }
-                        } else {
-                            error!("Missing or invalid 'start' or 'end' fields for type 'time_range'. Input: {}", json_str);
-                        }
-                    },
-                    Some("temperature_humidity") => {
-                        info!("Processing 'temperature_humidity' data for Client-ID: {}", client_id);
-                        let temp = json_value.get("temperature").and_then(Value::as_f64);
-                        let humidity = json_value.get("humidity").and_then(Value::as_f64);
-                        
-                        if let (Some(temp_val), Some(humidity_val)) = (temp, humidity) {
-                            match crate::query::get_nodes_with_temperature_or_humidity(temp_val, humidity_val, &db).await {
-                                Some(nodes) => {
-                                    let response_topic = format!("rust/response/{}/temperature_humidity", client_id);
-                                    publish_result(client, &response_topic, &nodes).await?;
-                                },
-                                None => {
-                                    error!("Failed to get nodes with temperature {} and humidity {} for Client-ID: {}", 
-                                           temp_val, humidity_val, client_id);
-                                }
-                            }
-                        } else {
-                            error!("Missing or invalid 'temperature' or 'humidity' fields for type 'temperature_humidity'. Input: {}", json_str);
-                        }
-                    },
-                    Some("timestamp") => {
-                        info!("Processing 'timestamp' data for Client-ID: {}", client_id);
-                        if let Some(timestamp) = json_value.get("data").and_then(Value::as_str) {
-                            match crate::query::get_temperature_humidity_at_time(&db, timestamp).await {
-                                Some((temp, humidity)) => {
-                                    let response = serde_json::json!({
-                                        "timestamp": timestamp,
-                                        "temperature": temp,
-                                        "humidity": humidity
-                                    });
-                                    let response_topic = format!("rust/response/{}/timestamp", client_id);
-                                    publish_result(client, &response_topic, &response).await?;
-                                },
-                                None => {
-                                    error!("Failed to get temperature and humidity at timestamp: {} for Client-ID: {}", 
-                                           timestamp, client_id);
-                                }
-                            }
-                        } else {
-                            error!("Missing or invalid 'data' field for type 'timestamp'. Input: {}", json_str);
-                        }
-                    },
-                    Some("energy_cost") => {
-                        info!("Processing 'energy_cost' data for Client-ID: {}", client_id);
-                        if let Some(cost) = json_value.get("data").and_then(Value::as_f64) {
-                            match crate::query::get_nodes_with_energy_cost(cost, &db).await {
-                                Some(nodes) => {
-                                    let response_topic = format!("rust/response/{}/energy_cost", client_id);
-                                    publish_result(client, &response_topic, &nodes).await?;
-                                },
-                                None => {
-                                    error!("Failed to get nodes with energy cost: {} for Client-ID: {}", cost, client_id);
-                                }
-                            }
-                        } else {
-                            error!("Missing or invalid 'data' field for type 'energy_cost'. Input: {}", json_str);
-                        }
-                    },
-                    Some("energy_consume") => {
-                        info!("Processing 'energy_consume' data for Client-ID: {}", client_id);
-                        if let Some(consume) = json_value.get("data").and_then(Value::as_f64) {
-                            match crate::query::get_nodes_with_energy_consume(consume, &db).await {
-                                Some(nodes) => {
-                                    let response_topic = format!("rust/response/{}/energy_consume", client_id);
-                                    publish_result(client, &response_topic, &nodes).await?;
-                                },
-                                None => {
-                                    error!("Failed to get nodes with energy consumption: {} for Client-ID: {}", consume, client_id);
-                                }
-                            }
-                        } else {
-                            error!("Missing or invalid 'data' field for type 'energy_consume'. Input: {}", json_str);
-                        }
-                    },
-                    Some(other) => {
-                        error!("Unknown type '{}' in JSON for Client-ID: {}. Full input: {}", other, client_id, json_str);
-                    },
-                    None => {
-                        error!("Missing 'type' field in JSON for Client-ID: {}: {}", client_id, json_str);
-                    }
-                }
-            } else {
-                // Message is for another client, we ignore it
-                info!("Skipping message intended for another client: {}", target_client_id.unwrap_or("unknown"));
-            }
-        },
-        Err(e) => {
-            error!("Failed to parse JSON for Client-ID: {}: {}", client_id, e);
-        }
-    }
-    Ok(())
-}
-
-async fn publish_result(client: &AsyncClient, topic: &str, data: &Value) -> Result<(), Box<dyn std::error::Error>> {
-    const MAX_PACKET_SIZE: usize = 5000; // Safe limit below broker's 10240 bytes
-    
-    let response_json = serde_json::to_vec(data)?;
-    
-    if response_json.len() <= MAX_PACKET_SIZE {
-        // Normal publishing for messages within size limit
-        client.publish(topic, QoS::AtMostOnce, false, response_json).await?;
-        info!("Result published to topic: {}", topic);
-    } else {
-        // Split large messages into chunks
-        info!("Large message detected ({} bytes). Splitting into chunks...", response_json.len());
-        
-        // Convert the original Value to a mutable JSON object to add split information
-        let mut data_map = if let Value::Object(map) = data.clone() {
-            map
-        } else {
-            // If not an object, wrap it in one
-            let mut map = serde_json::Map::new();
-            map.insert("data".to_string(), data.clone());
-            map
-        };
-        
-        // Calculate number of chunks needed
-        let total_chunks = (response_json.len() + MAX_PACKET_SIZE - 1) / MAX_PACKET_SIZE;
-        let json_str = serde_json::to_string(data)?;
-        
-        // Split the JSON string into chunks
-        for chunk_index in 0..total_chunks {
-            let start = chunk_index * MAX_PACKET_SIZE;
-            let end = std::cmp::min(start + MAX_PACKET_SIZE, json_str.len());
-            let chunk = &json_str[start..end];
-            
-            // Create a new JSON object for each chunk
-            let mut chunk_data = serde_json::Map::new();
-            
-            // Add split metadata
-            chunk_data.insert("split_index".to_string(), Value::Number(serde_json::Number::from(chunk_index + 1)));
-            chunk_data.insert("total_splits".to_string(), Value::Number(serde_json::Number::from(total_chunks)));
-            chunk_data.insert("original_size".to_string(), Value::Number(serde_json::Number::from(json_str.len())));
-            
-            // Add the data chunk
-            chunk_data.insert("chunk".to_string(), Value::String(chunk.to_string()));
-            
-            // Convert to JSON and publish
-            let chunk_json = Value::Object(chunk_data);
-            let chunk_bytes = serde_json::to_vec(&chunk_json)?;
-            
-            // Construct split-specific topic
-            let split_topic = format!("{}/split/{}/{}", topic, chunk_index + 1, total_chunks);
-            
-            client.publish(&split_topic, QoS::AtLeastOnce, false, chunk_bytes).await?;
-            info!("Published chunk {}/{} to topic: {}", chunk_index + 1, total_chunks, split_topic);
-        }
-        
-        // Publish a summary message to the original topic
-        let mut summary = serde_json::Map::new();
-        summary.insert("message_split".to_string(), Value::Bool(true));
-        summary.insert("total_chunks".to_string(), Value::Number(serde_json::Number::from(total_chunks)));
-        summary.insert("original_size".to_string(), Value::Number(serde_json::Number::from(json_str.len())));
-        summary.insert("base_topic".to_string(), Value::String(topic.to_string()));
-        
-        let summary_json = Value::Object(summary);
-        let summary_bytes = serde_json::to_vec(&summary_json)?;
-        
-        client.publish(topic, QoS::AtLeastOnce, false, summary_bytes).await?;
-        info!("Published split summary to topic: {}", topic);
-    }
-    
-    Ok(())
-}
\ No newline at end of file
+use rumqttc::{MqttOptions, AsyncClient, Event, EventLoop, Incoming, LastWill, Outgoing, QoS};
+use tokio::time::{Duration, Instant};
+use log::{debug, info, error, warn};
+use serde::Serialize;
+use serde_json::Value;
+use std::error::Error;
+use tracing::{Instrument, info_span};
+use uuid::Uuid;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use crate::codec::{Compression, Encoding};
+use crate::db::get_db;
+use crate::auth::Role;
+use crate::command_handler::CommandContext;
+use crate::config;
+use crate::logging;
+use crate::message_schema::{self, Transport};
+use crate::metrics;
+use crate::protocol::{self, Inbound};
+use crate::request_handler;
+use crate::shutdown;
+use crate::status;
+use neo4rs::Graph;
+
+pub async fn start_mqtt_client() -> Result<(), Box<dyn Error>> {
+    // Generate a unique client ID for this connection
+    let client_id = format!("rust-mqtt-client-{}", Uuid::new_v4());
+    let span = info_span!("mqtt", client_id = %client_id);
+    run(client_id).instrument(span).await
+}
+
+async fn run(client_id: String) -> Result<(), Box<dyn Error>> {
+    status::set_mqtt_state("connecting", Some(&client_id));
+    
+    let settings = &config::get().mqtt;
+    let mut mqtt_options = MqttOptions::new(
+        &client_id,
+        &settings.host,
+        settings.port
+    );
+    
+    mqtt_options.set_credentials(&settings.user, &settings.password);
+    // The broker announces us as offline if we vanish without a clean disconnect
+    let last_will = serde_json::json!({
+        "type": "client_disconnect",
+        "client_id": client_id,
+        "clean": false
+    });
+    mqtt_options.set_last_will(LastWill::new(&settings.clients_topic, serde_json::to_vec(&last_will)?, QoS::AtLeastOnce, false));
+    let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
+    
+    // Connection timeout
+    let connect_timeout = Duration::from_secs(settings.connect_timeout_secs);
+    let start_time = Instant::now();
+    let mut connected = false;
+    // Phase 1: connect
+    while Instant::now().duration_since(start_time) < connect_timeout {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Incoming::ConnAck(ack))) => {
+                info!("Connected to MQTT broker: {:?}", ack);
+                status::set_mqtt_state("connected", None);
+                connected = true;
+                break;
+            },
+            Ok(event) => debug!("MQTT event while connecting: {:?}", event),
+            Err(e) => {
+                error!("MQTT connection failed: {}", e);
+                status::set_mqtt_state("disconnected", None);
+                status::record_error(&format!("MQTT connection failed: {}", e));
+                return Err(e.into());
+            }
+        }
+    }
+    if !connected {
+        error!("MQTT broker not reachable within {:?}", connect_timeout);
+        status::set_mqtt_state("disconnected", None);
+        return Err("Broker offline".into());
+    }
+    // Phase 2: normal operation
+    info!("MQTT client ready");
+    
+    // Publish our client ID to a central topic so other clients know we exist
+    let connection_message = serde_json::json!({
+        "type": "client_connect",
+        "client_id": client_id
+    });
+    
+    client.publish(
+        &settings.clients_topic, 
+        QoS::AtLeastOnce, 
+        false, 
+        serde_json::to_vec(&connection_message)?
+    ).await?;
+    
+    // Subscribe to the general topic
+    client.subscribe(&settings.request_topic, QoS::AtMostOnce).await?;
+    
+    // Also subscribe to our client-specific topic
+    let client_topic = format!("{}/{}", settings.request_topic, client_id);
+    client.subscribe(&client_topic, QoS::AtMostOnce).await?;
+    
+    loop {
+        let event = tokio::select! {
+            event = eventloop.poll() => event,
+            _ = shutdown::token().cancelled() => {
+                disconnect(&client, &mut eventloop, &client_id).await;
+                break;
+            }
+        };
+        match event {
+            Ok(Event::Incoming(Incoming::Publish(publish))) => {
+                let db = match get_db().await {
+                    Ok(db) => db,
+                    Err(e) => {
+                        error!("Database connection failed: {}", e);
+                        continue;
+                    },
+                };
+                handle_message(&publish, &client, db, &client_id).await?;
+            },
+            Ok(Event::Incoming(Incoming::Disconnect)) => {
+                info!("MQTT broker closed the connection");
+                status::set_mqtt_state("disconnected", None);
+                break;
+            },
+            Err(e) => {
+                error!("MQTT event loop error: {}", e);
+                status::set_mqtt_state("disconnected", None);
+                status::record_error(&format!("MQTT event loop error: {}", e));
+                break;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Publishes the offline message and disconnects cleanly, polling the event loop until the
+// disconnect has actually been sent
+async fn disconnect(client: &AsyncClient, eventloop: &mut EventLoop, client_id: &str) {
+    info!("Disconnecting MQTT client {} for shutdown", client_id);
+    status::set_mqtt_state("disconnecting", None);
+    let offline_message = serde_json::json!({
+        "type": "client_disconnect",
+        "client_id": client_id,
+        "clean": true
+    });
+    if let Ok(payload) = serde_json::to_vec(&offline_message) {
+        if let Err(e) = client.publish(&config::get().mqtt.clients_topic, QoS::AtLeastOnce, false, payload).await {
+            error!("Failed to publish offline message: {}", e);
+        }
+    }
+    if let Err(e) = client.disconnect().await {
+        error!("Failed to request MQTT disconnect: {}", e);
+        return;
+    }
+
+    let drain = async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Outgoing(Outgoing::Disconnect)) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+    };
+    if tokio::time::timeout(Duration::from_secs(5), drain).await.is_err() {
+        warn!("MQTT disconnect for {} timed out", client_id);
+    }
+    status::set_mqtt_state("disconnected", None);
+}
+
+async fn handle_message(
+    publish: &rumqttc::Publish,
+    client: &AsyncClient,
+    db: &Graph,
+    client_id: &str
+) -> Result<(), Box<dyn Error>> {
+    info!("Message received on {} ({} bytes)", publish.topic, publish.payload.len());
+    
+    // Check if this message is for us specifically
+    let is_client_specific = publish.topic.contains(client_id);
+    
+    // Parse the message
+    parse_and_process_message(&publish.payload, db, client, client_id, is_client_specific).await?;
+    
+    Ok(())
+}
+
+async fn parse_and_process_message(
+    payload: &[u8],
+    db: &Graph,
+    client: &AsyncClient,
+    client_id: &str,
+    is_client_specific: bool
+) -> Result<(), Box<dyn std::error::Error>> {
+    let encoding = Encoding::detect(payload);
+    
+    match encoding.decode::<Value>(payload) {
+        Ok(json_value) => {
+            metrics::message_received("mqtt", &json_value);
+            // Answer in the request's encoding unless it asks for another one
+            let response_encoding = json_value.get("accept").and_then(Value::as_str)
+                .and_then(|name| name.parse().ok())
+                .unwrap_or(encoding);
+            // Unknown names are reported by the schema check, uncompressed
+            let compression = json_value.get("compression").and_then(Value::as_str)
+                .and_then(|name| name.parse().ok());
+            let format = Format { encoding: response_encoding, compression };
+            let span = logging::request_span(&json_value);
+            process_message(&json_value, db, client, client_id, is_client_specific, format).instrument(span).await?;
+        },
+        Err(e) => {
+            error!("Failed to parse {} message for Client-ID: {}: {}", encoding.name(), client_id, e);
+        }
+    }
+    Ok(())
+}
+
+async fn process_message(
+    json_value: &Value,
+    db: &Graph,
+    client: &AsyncClient,
+    client_id: &str,
+    is_client_specific: bool,
+    format: Format
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Check if the message has a target client_id
+    let target_client_id = json_value.get("client_id").and_then(Value::as_str);
+    
+    // Only process if:
+    // 1. No specific target is specified, OR
+    // 2. We are the specific target, OR
+    // 3. Message came in on our specific topic (is_client_specific)
+    if target_client_id.is_none() || target_client_id == Some(client_id) || is_client_specific {
+        // No connection to remember a `hello` for, so every message names its version
+        let json_value = match protocol::Session::new(Transport::Mqtt).receive(json_value.clone()) {
+            Inbound::Message(json) => json,
+            Inbound::Reply(reply) => {
+                let suffix = reply.get("type").and_then(Value::as_str).unwrap_or("error");
+                publish_result(client, &response_topic(client_id, suffix), &reply, format).await?;
+                return Ok(());
+            },
+        };
+        let version = protocol::version(&json_value);
+        let errors = message_schema::validate(&json_value, Transport::Mqtt);
+        if !errors.is_empty() {
+            info!("Rejected invalid message for Client-ID {}: {}", client_id, serde_json::json!(errors));
+            let response_topic = response_topic(client_id, "error");
+            let response = protocol::respond(message_schema::error_response(&json_value, errors), &json_value, version);
+            publish_result(client, &response_topic, &response, format).await?;
+            return Ok(());
+        }
+        // MQTT clients are never admins
+        let ctx = CommandContext { role: Role::User, source: format!("mqtt:{}", client_id) };
+        match request_handler::handle(&json_value, db, &ctx, Transport::Mqtt).await {
+            Ok(reply) => {
+                let response_topic = response_topic(client_id, &reply.suffix);
+                publish_result(client, &response_topic, &protocol::respond(reply.body, &json_value, version), format).await?;
+            },
+            Err(e) => error!("{}", e),
+        }
+    } else {
+        // Message is for another client, we ignore it
+        info!("Skipping message intended for another client: {}", target_client_id.unwrap_or("unknown"));
+    }
+    Ok(())
+}
+
+// `<response_topic>/<client id>/<suffix>`
+fn response_topic(client_id: &str, suffix: &str) -> String {
+    format!("{}/{}/{}", config::get().mqtt.response_topic, client_id, suffix)
+}
+
+// How a response goes out: its encoding, and the compression the request asked for
+#[derive(Debug, Clone, Copy)]
+struct Format {
+    encoding: Encoding,
+    compression: Option<Compression>,
+}
+
+// A compressed response small enough for a single packet. JSON carries the compressed
+// bytes as base64, binary encodings as raw bytes.
+#[derive(Serialize)]
+struct Compressed<'a> {
+    compression: &'static str,
+    uncompressed_size: usize,
+    data: ChunkData<'a>,
+}
+
+// One part of a response too large for a single packet. JSON responses are split as text,
+// binary ones as raw bytes.
+#[derive(Serialize)]
+struct Chunk<'a> {
+    split_index: usize,
+    total_splits: usize,
+    original_size: usize,
+    // Set when the chunks together are a compressed response; `original_size` is then the
+    // size of the compressed content
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uncompressed_size: Option<usize>,
+    chunk: ChunkData<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ChunkData<'a> {
+    Text(&'a str),
+    Binary(#[serde(with = "serde_bytes")] &'a [u8]),
+}
+
+// Start and end of each chunk of at most `max` bytes
+fn chunk_bounds(bytes: &[u8], max: usize, text: bool) -> Vec<(usize, usize)> {
+    let mut bounds = Vec::new();
+    let mut start = 0;
+    while start < bytes.len() {
+        let mut end = std::cmp::min(start + max, bytes.len());
+        // Back off continuation bytes (10xxxxxx) so the chunk ends on a character boundary
+        while text && end < bytes.len() && end > start + 1 && bytes[end] & 0xC0 == 0x80 {
+            end -= 1;
+        }
+        bounds.push((start, end));
+        start = end;
+    }
+    bounds
+}
+
+fn chunk_content(bytes: &[u8], encoding: Encoding) -> Result<ChunkData<'_>, std::str::Utf8Error> {
+    Ok(match encoding {
+        Encoding::Json => ChunkData::Text(std::str::from_utf8(bytes)?),
+        _ => ChunkData::Binary(bytes),
+    })
+}
+
+// The compressed form of an encoded response, if the request asked for compression and it
+// actually saves space. JSON responses get it as base64 text.
+fn compress(response: &[u8], format: Format) -> Result<Option<(Compression, Vec<u8>)>, String> {
+    let Some(compression) = format.compression else {
+        return Ok(None);
+    };
+    let packed = compression.compress(response)?;
+    let content = match format.encoding {
+        Encoding::Json => BASE64.encode(&packed).into_bytes(),
+        _ => packed,
+    };
+    if content.len() >= response.len() {
+        debug!("{} would not shrink the {} byte response, sending it uncompressed", compression.name(), response.len());
+        return Ok(None);
+    }
+    info!("Compressed response with {} from {} to {} bytes", compression.name(), response.len(), content.len());
+    metrics::COMPRESSED_RESPONSES.with_label_values(&[compression.name()]).inc();
+    Ok(Some((compression, content)))
+}
+
+async fn publish_result(client: &AsyncClient, topic: &str, data: &Value, format: Format) -> Result<(), Box<dyn std::error::Error>> {
+    let max_packet_size = config::get().mqtt.max_packet_size;
+    let encoding = format.encoding;
+    
+    let response = encoding.encode(data)?;
+    let uncompressed_size = response.len();
+    // What is published or split: the encoded response or its compressed form
+    let (content, compression) = match compress(&response, format)? {
+        Some((compression, packed)) => (packed, Some(compression)),
+        None => (response, None),
+    };
+    // A compressed response that fits one packet still needs its metadata around it
+    let envelope = match compression {
+        Some(compression) => Some(encoding.encode(&Compressed {
+            compression: compression.name(),
+            uncompressed_size,
+            data: chunk_content(&content, encoding)?,
+        })?),
+        None => None,
+    };
+    
+    if envelope.as_ref().map_or(content.len(), Vec::len) <= max_packet_size {
+        // Normal publishing for messages within size limit
+        client.publish(topic, QoS::AtMostOnce, false, envelope.unwrap_or(content)).await?;
+        metrics::MQTT_PUBLISHES.with_label_values(&["response"]).inc();
+        info!("Result published to topic: {}", topic);
+    } else {
+        // Split large messages into chunks
+        info!("Large message detected ({} bytes). Splitting into chunks...", content.len());
+        metrics::CHUNKED_RESPONSES.with_label_values(&["mqtt"]).inc();
+        
+        // JSON chunks must not cut a UTF-8 character in half
+        let bounds = chunk_bounds(&content, max_packet_size, encoding == Encoding::Json);
+        let total_chunks = bounds.len();
+        
+        for (chunk_index, (start, end)) in bounds.into_iter().enumerate() {
+            // Add split metadata to the data chunk
+            let chunk_data = Chunk {
+                split_index: chunk_index + 1,
+                total_splits: total_chunks,
+                original_size: content.len(),
+                compression: compression.map(Compression::name),
+                uncompressed_size: compression.map(|_| uncompressed_size),
+                chunk: chunk_content(&content[start..end], encoding)?,
+            };
+            let chunk_bytes = encoding.encode(&chunk_data)?;
+            
+            // Construct split-specific topic
+            let split_topic = format!("{}/split/{}/{}", topic, chunk_index + 1, total_chunks);
+            
+            client.publish(&split_topic, QoS::AtLeastOnce, false, chunk_bytes).await?;
+            metrics::MQTT_PUBLISHES.with_label_values(&["chunk"]).inc();
+            info!("Published chunk {}/{} to topic: {}", chunk_index + 1, total_chunks, split_topic);
+        }
+        
+        // Publish a summary message to the original topic
+        let mut summary = serde_json::json!({
+            "message_split": true,
+            "total_chunks": total_chunks,
+            "original_size": content.len(),
+            "base_topic": topic,
+            "encoding": encoding.name()
+        });
+        if let Some(compression) = compression {
+            summary["compression"] = serde_json::json!(compression.name());
+            summary["uncompressed_size"] = serde_json::json!(uncompressed_size);
+        }
+        let summary_bytes = encoding.encode(&summary)?;
+        
+        client.publish(topic, QoS::AtLeastOnce, false, summary_bytes).await?;
+        metrics::MQTT_PUBLISHES.with_label_values(&["summary"]).inc();
+        info!("Published split summary to topic: {}", topic);
+    }
+    
+    Ok(())
+}
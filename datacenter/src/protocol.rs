@@ -0,0 +1,432 @@
+use log::info;
+use serde_json::{Value, json};
+
+use crate::codec::Encoding;
+use crate::message_schema::{self, Transport};
+
+// Versions of the TCP, MQTT and WebSocket message protocol. Handlers speak version 1
+// internally; messages in a newer version are translated on the way in and responses on
+// the way out, so gateways can move to a new version one at a time.
+//
+// Version 1: the original messages. A message without `version` is version 1 unless the
+// connection negotiated another one with `hello`.
+// Version 2: every message carries `"version": 2`. Query arguments live in a `params`
+// object (`{"type": "uuid", "params": {"uuid": ..}}`), command arguments too, and a `data`
+// batch lists its readings under `records`; anything else in those containers is an error.
+// Responses are envelopes with `version` and `type`; query results arrive as
+// `{"type": "response", "request": .., "result": ..}`.
+pub const VERSIONS: [u64; 2] = [1, 2];
+
+// Where a version 1 field lives in version 2: (message type, v1 field, v2 JSON pointer)
+const FIELDS: &[(&str, &str, &str)] = &[
+    ("data", "data", "/records"),
+    ("command", "args", "/params"),
+    ("uuid", "data", "/params/uuid"),
+    ("color", "data", "/params/color"),
+    ("timestamp", "data", "/params/timestamp"),
+    ("energy_cost", "data", "/params/value"),
+    ("energy_consume", "data", "/params/value"),
+    ("device", "data", "/params/id"),
+    ("devices", "filters", "/params"),
+    ("time_range", "start", "/params/start"),
+    ("time_range", "end", "/params/end"),
+    ("temperature_humidity", "temperature", "/params/temperature"),
+    ("temperature_humidity", "humidity", "/params/humidity"),
+];
+
+// Version 2 containers that do not exist in version 1
+const V2_CONTAINERS: [&str; 2] = ["params", "records"];
+
+fn fields(kind: &str) -> impl Iterator<Item = (&'static str, &'static str)> + '_ {
+    FIELDS.iter().filter(move |(k, _, _)| *k == kind).map(|(_, v1, v2)| (*v1, *v2))
+}
+
+// The version a translated message was sent in
+pub fn version(json: &Value) -> u64 {
+    json.get("version").and_then(Value::as_u64).unwrap_or(1)
+}
+
+fn message_type(json: &Value) -> &str {
+    json.get("type").and_then(Value::as_str).unwrap_or_default()
+}
+
+pub enum Inbound {
+    // A message in the version 1 shape, with `version` set to the one the client speaks
+    Message(Value),
+    // Answered by the protocol layer itself: `hello` or an unsupported version
+    Reply(Value),
+}
+
+// Protocol state of one client. TCP and WebSocket connections keep it for their lifetime;
+// MQTT is connectionless, so each message gets a fresh one.
+pub struct Session {
+    transport: Transport,
+    negotiated: Option<u64>,
+    encoding: Encoding,
+}
+
+impl Session {
+    pub fn new(transport: Transport) -> Self {
+        Session { transport, negotiated: None, encoding: Encoding::Json }
+    }
+
+    // The wire encoding negotiated with `hello`; JSON until then
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    // The version responses go out in when the message did not name one
+    pub fn version(&self) -> u64 {
+        self.negotiated.unwrap_or(1)
+    }
+
+    pub fn receive(&mut self, mut json: Value) -> Inbound {
+        let version = match json.get("version") {
+            None => self.version(),
+            Some(requested) => match requested.as_u64().filter(|v| VERSIONS.contains(v)) {
+                Some(version) => version,
+                None => return Inbound::Reply(json!({
+                    "type": "error",
+                    "message": format!("Unsupported protocol version {}", requested),
+                    "versions": VERSIONS
+                })),
+            },
+        };
+        if message_type(&json) == "hello" {
+            return Inbound::Reply(self.hello(&json, version));
+        }
+        if version > 1 {
+            json = match upgrade_to_v1(json) {
+                Ok(json) => json,
+                Err((json, e)) => return Inbound::Reply(respond(json!({
+                    "type": "error",
+                    "request": message_type(&json),
+                    "message": e
+                }), &json, version)),
+            };
+        }
+        if let Value::Object(map) = &mut json {
+            map.insert("version".into(), json!(version));
+        }
+        Inbound::Message(json)
+    }
+
+    // Picks the newest version both sides speak, and the requested encoding, and remembers
+    // them for the connection. The reply itself still goes out in the old encoding.
+    fn hello(&mut self, json: &Value, version: u64) -> Value {
+        let errors = message_schema::validate(json, self.transport);
+        if !errors.is_empty() {
+            return respond(message_schema::error_response(json, errors), json, version);
+        }
+        let encoding = match json.get("encoding").and_then(Value::as_str) {
+            None => self.encoding,
+            Some(name) => match name.parse::<Encoding>() {
+                Ok(encoding) if encoding == Encoding::Json || self.transport == Transport::Tcp => encoding,
+                Ok(_) => return respond(json!({
+                    "type": "error",
+                    "request": "hello",
+                    "message": format!("Only TCP connections switch encodings; {:?} supports {}", self.transport, self.transport.encodings().join(", "))
+                }), json, version),
+                Err(e) => return respond(json!({ "type": "error", "request": "hello", "message": e }), json, version),
+            },
+        };
+        let offered: Vec<u64> = match json.get("versions").and_then(Value::as_array) {
+            Some(versions) => versions.iter().filter_map(Value::as_u64).collect(),
+            None => vec![version],
+        };
+        let Some(chosen) = offered.iter().copied().filter(|v| VERSIONS.contains(v)).max() else {
+            return json!({
+                "type": "error",
+                "request": "hello",
+                "message": format!("None of the offered versions {:?} is supported", offered),
+                "versions": VERSIONS
+            });
+        };
+        info!("Negotiated protocol version {} and {} encoding over {:?}", chosen, encoding.name(), self.transport);
+        self.negotiated = Some(chosen);
+        self.encoding = encoding;
+        json!({
+            "type": "hello",
+            "version": chosen,
+            "versions": VERSIONS,
+            "encoding": encoding.name(),
+            "encodings": self.transport.encodings(),
+            "server": env!("CARGO_PKG_NAME"),
+            "server_version": env!("CARGO_PKG_VERSION"),
+            "message_types": self.transport.types(),
+            "features": self.transport.features()
+        })
+    }
+}
+
+// Moves the version 2 fields to where the handlers expect them. Fails with the message
+// when it has a container, or a field in one, that its type does not use, since that would
+// be dropped.
+fn upgrade_to_v1(mut json: Value) -> Result<Value, (Value, String)> {
+    let kind = message_type(&json).to_string();
+    if let Some(field) = unmapped_field(&json, &kind) {
+        let e = format!("'{}' is not part of a version 2 '{}' message", field, kind);
+        return Err((json, e));
+    }
+    let moved: Vec<(&str, Value)> = fields(&kind)
+        .filter_map(|(v1, v2)| json.pointer(v2).cloned().map(|value| (v1, value)))
+        .collect();
+    if let Value::Object(map) = &mut json {
+        for container in V2_CONTAINERS {
+            map.remove(container);
+        }
+        for (v1, value) in moved {
+            map.insert(v1.to_string(), value);
+        }
+    }
+    Ok(json)
+}
+
+// The first v2 container or container field in `json` that no FIELDS entry of `kind` maps
+fn unmapped_field(json: &Value, kind: &str) -> Option<String> {
+    for container in V2_CONTAINERS {
+        let Some(value) = json.get(container) else { continue };
+        let prefix = format!("/{}", container);
+        let pointers: Vec<&str> = fields(kind).map(|(_, v2)| v2).filter(|v2| v2.starts_with(&prefix)).collect();
+        if pointers.is_empty() {
+            return Some(container.to_string());
+        }
+        // A container moved as a whole keeps everything in it
+        if pointers.contains(&prefix.as_str()) {
+            continue;
+        }
+        let Some(object) = value.as_object() else {
+            return Some(container.to_string());
+        };
+        if let Some(key) = object.keys().find(|key| !pointers.contains(&format!("{}/{}", prefix, key).as_str())) {
+            return Some(format!("{}.{}", container, key));
+        }
+    }
+    None
+}
+
+// Turns a handler's response to `request` (in its version 1 shape) into the client's version
+pub fn respond(response: Value, request: &Value, version: u64) -> Value {
+    if version < 2 {
+        return response;
+    }
+    match response {
+        Value::Object(mut map) if map.contains_key("type") => {
+            // Validation errors point into the message as the client sent it
+            if let Some(Value::Array(errors)) = map.get_mut("errors") {
+                for error in errors {
+                    if let Some(path) = error.get("path").and_then(Value::as_str) {
+                        error["path"] = json!(v2_path(message_type(request), path));
+                    }
+                }
+            }
+            map.insert("version".into(), json!(version));
+            Value::Object(map)
+        },
+        result => {
+            let mut envelope = json!({
+                "version": version,
+                "type": "response",
+                "request": request.get("type"),
+                "result": result
+            });
+            if let Some(request_id) = request.get("request_id") {
+                envelope["request_id"] = request_id.clone();
+            }
+            envelope
+        },
+    }
+}
+
+fn v2_path(kind: &str, path: &str) -> String {
+    for (v1, v2) in fields(kind) {
+        let prefix = format!("/{}", v1);
+        if let Some(rest) = path.strip_prefix(&prefix).filter(|rest| rest.is_empty() || rest.starts_with('/')) {
+            return format!("{}{}", v2, rest);
+        }
+    }
+    path.to_string()
+}
+
+// The version 2 form of a version 1 message schema
+pub fn schema_for(schema: &Value, version: u64) -> Value {
+    let mut schema = schema.clone();
+    if version < 2 {
+        return schema;
+    }
+    let kind = schema["title"].as_str().unwrap_or_default().to_string();
+    for (v1, v2) in fields(&kind) {
+        let Some(field) = schema["properties"].as_object_mut().and_then(|properties| properties.remove(v1)) else {
+            continue;
+        };
+        let required = remove_required(&mut schema, v1);
+        let segments: Vec<&str> = v2.trim_start_matches('/').split('/').collect();
+        let mut parent = &mut schema;
+        for (depth, segment) in segments.iter().enumerate() {
+            if required {
+                add_required(parent, segment);
+            }
+            let properties = parent["properties"].as_object_mut().expect("message schemas have properties");
+            if depth + 1 == segments.len() {
+                properties.insert(segment.to_string(), field);
+                break;
+            }
+            parent = properties.entry(segment.to_string())
+                .or_insert_with(|| json!({ "type": "object", "properties": {} }));
+        }
+    }
+    schema["properties"]["version"] = json!({ "const": version });
+    schema
+}
+
+fn remove_required(schema: &mut Value, field: &str) -> bool {
+    let Some(required) = schema["required"].as_array_mut() else {
+        return false;
+    };
+    let before = required.len();
+    required.retain(|name| name != field);
+    before != required.len()
+}
+
+fn add_required(schema: &mut Value, field: &str) {
+    let required = schema.as_object_mut().expect("message schemas are objects")
+        .entry("required")
+        .or_insert_with(|| Value::Array(Vec::new()));
+    if let Value::Array(names) = required {
+        if !names.iter().any(|name| name == field) {
+            names.push(json!(field));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(inbound: Inbound) -> Value {
+        match inbound {
+            Inbound::Message(json) => json,
+            Inbound::Reply(reply) => panic!("expected a message, got the reply {}", reply),
+        }
+    }
+
+    fn reply(inbound: Inbound) -> Value {
+        match inbound {
+            Inbound::Reply(reply) => reply,
+            Inbound::Message(json) => panic!("expected a reply, got the message {}", json),
+        }
+    }
+
+    #[test]
+    fn version_1_messages_pass_through() {
+        let mut session = Session::new(Transport::Mqtt);
+        let json = message(session.receive(json!({ "type": "uuid", "data": "a" })));
+        assert_eq!(json, json!({ "type": "uuid", "data": "a", "version": 1 }));
+    }
+
+    #[test]
+    fn version_2_messages_are_translated_to_version_1() {
+        let mut session = Session::new(Transport::Mqtt);
+        let json = message(session.receive(json!({
+            "version": 2, "type": "time_range", "params": { "start": "s", "end": "e" }
+        })));
+        assert_eq!(json, json!({ "version": 2, "type": "time_range", "start": "s", "end": "e" }));
+
+        let json = message(session.receive(json!({
+            "version": 2, "type": "data", "records": [{ "uuid": "a" }], "message_id": "m1"
+        })));
+        assert_eq!(json, json!({ "version": 2, "type": "data", "data": [{ "uuid": "a" }], "message_id": "m1" }));
+    }
+
+    #[test]
+    fn rejects_version_2_fields_the_type_does_not_use() {
+        let mut session = Session::new(Transport::Mqtt);
+        for (message, field) in [
+            (json!({ "version": 2, "type": "all", "params": { "limit": 5 } }), "params"),
+            (json!({ "version": 2, "type": "uuid", "records": [] }), "records"),
+            (json!({ "version": 2, "type": "uuid", "params": { "uuid": "a", "color": "red" } }), "params.color"),
+            (json!({ "version": 2, "type": "uuid", "params": "a" }), "params"),
+        ] {
+            let kind = message["type"].as_str().unwrap().to_string();
+            let reply = reply(session.receive(message));
+            assert_eq!(reply["type"], "error");
+            assert_eq!(reply["version"], 2);
+            assert_eq!(reply["message"], format!("'{}' is not part of a version 2 '{}' message", field, kind));
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        let mut session = Session::new(Transport::Tcp);
+        let reply = reply(session.receive(json!({ "version": 3, "type": "uuid" })));
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["message"], "Unsupported protocol version 3");
+    }
+
+    #[test]
+    fn version_2_responses_are_wrapped_in_envelopes() {
+        let request = json!({ "version": 2, "type": "uuid", "request_id": "r1" });
+        assert_eq!(respond(json!([1, 2]), &request, 2), json!({
+            "version": 2, "type": "response", "request": "uuid", "request_id": "r1", "result": [1, 2]
+        }));
+        assert_eq!(respond(json!([1, 2]), &request, 1), json!([1, 2]));
+
+        let error = json!({ "type": "error", "errors": [{ "path": "/data", "message": "m" }] });
+        assert_eq!(respond(error, &request, 2), json!({
+            "type": "error", "version": 2, "errors": [{ "path": "/params/uuid", "message": "m" }]
+        }));
+    }
+
+    #[test]
+    fn hello_negotiates_the_newest_common_version() {
+        let mut session = Session::new(Transport::Tcp);
+        let reply = reply(session.receive(json!({ "type": "hello", "versions": [1, 2, 7] })));
+        assert_eq!((reply["version"].clone(), reply["encoding"].clone()), (json!(2), json!("json")));
+        assert_eq!(session.version(), 2);
+
+        // Later messages without a version use the negotiated one
+        let json = message(session.receive(json!({ "type": "command", "command": "help", "params": {} })));
+        assert_eq!(json, json!({ "type": "command", "command": "help", "args": {}, "version": 2 }));
+    }
+
+    #[test]
+    fn hello_fails_without_a_common_version() {
+        let mut session = Session::new(Transport::Tcp);
+        let reply = reply(session.receive(json!({ "type": "hello", "versions": [7] })));
+        assert_eq!(reply["message"], "None of the offered versions [7] is supported");
+        assert_eq!(session.version(), 1);
+    }
+
+    #[test]
+    fn only_tcp_switches_encodings() {
+        let mut tcp = Session::new(Transport::Tcp);
+        reply(tcp.receive(json!({ "type": "hello", "encoding": "cbor" })));
+        assert_eq!(tcp.encoding(), Encoding::Cbor);
+
+        let mut mqtt = Session::new(Transport::Mqtt);
+        let reply = reply(mqtt.receive(json!({ "type": "hello", "encoding": "cbor" })));
+        assert_eq!(reply["type"], "error");
+        assert_eq!(mqtt.encoding(), Encoding::Json);
+    }
+
+    #[test]
+    fn version_2_schemas_nest_fields_under_params() {
+        let schema = json!({
+            "title": "uuid",
+            "type": "object",
+            "properties": { "type": { "const": "uuid" }, "data": { "type": "string" } },
+            "required": ["type", "data"]
+        });
+        let v2 = schema_for(&schema, 2);
+        assert_eq!(v2["properties"]["params"], json!({
+            "type": "object",
+            "properties": { "uuid": { "type": "string" } },
+            "required": ["uuid"]
+        }));
+        assert_eq!(v2["required"], json!(["type", "params"]));
+        assert_eq!(v2["properties"]["version"], json!({ "const": 2 }));
+        assert!(v2["properties"].get("data").is_none());
+        assert_eq!(schema_for(&schema, 1), schema);
+    }
+}
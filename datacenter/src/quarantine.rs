@@ -0,0 +1,402 @@
+use log::{info, error, warn};
+use neo4rs::{Graph, query};
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::config::{self, QuarantineStore};
+use crate::device::{active_device_ids, UnknownDevicePolicy};
+use crate::query::{create_new_relation, IngestSettings};
+
+// Serializes access to the quarantine file
+static FILE_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuarantineBackend {
+    Neo4j,
+    File(PathBuf),
+}
+
+impl QuarantineBackend {
+    pub fn from_config() -> Self {
+        let quarantine = &config::get().quarantine;
+        match quarantine.backend {
+            QuarantineStore::Neo4j => QuarantineBackend::Neo4j,
+            QuarantineStore::File => QuarantineBackend::File(quarantine.file.clone()),
+        }
+    }
+}
+
+// Checks a single ingest record for every field `create_new_relation` merges on.
+// Returns the reason the record has to be quarantined.
+pub fn validate_record(record: &Value) -> Result<(), String> {
+    if !record.is_object() {
+        return Err("record is not a JSON object".to_string());
+    }
+    for field in ["uuid", "color", "timestamp"] {
+        match record.get(field).and_then(Value::as_str) {
+            Some(value) if !value.trim().is_empty() => {},
+            Some(_) => return Err(format!("'{}' is empty", field)),
+            None => return Err(format!("'{}' is missing or not a string", field)),
+        }
+    }
+    for field in ["energy_consume", "energy_cost"] {
+        if record.get(field).and_then(Value::as_f64).is_none() {
+            return Err(format!("'{}' is missing or not a number", field));
+        }
+    }
+    let sensor_data = record.get("sensor_data")
+        .and_then(Value::as_object)
+        .ok_or("'sensor_data' is missing or not an object")?;
+    for field in ["temperature", "humidity"] {
+        if sensor_data.get(field).and_then(Value::as_f64).is_none() {
+            return Err(format!("'sensor_data.{}' is missing or not a number", field));
+        }
+    }
+    Ok(())
+}
+
+// Pairs the records of a batch with their quarantine ids. A batch that is retried until
+// Neo4j takes it (a WAL entry) passes its own id, so each record keeps the same quarantine
+// id on every attempt and is quarantined only once.
+pub fn with_ids(records: Vec<Value>, batch: Option<&str>) -> Vec<(String, Value)> {
+    records.into_iter().enumerate().map(|(index, record)| {
+        let id = match batch {
+            Some(batch) => format!("{}-{}", batch, index),
+            None => Uuid::new_v4().to_string(),
+        };
+        (id, record)
+    }).collect()
+}
+
+// Splits a batch into valid records and quarantines the rest. Returns the valid records
+// and the number of records newly quarantined.
+pub async fn split_valid(records: Vec<(String, Value)>, source: &str, graph: &Graph) -> Result<(Vec<(String, Value)>, usize), String> {
+    let mut valid = Vec::new();
+    let mut invalid: BTreeMap<String, Vec<(String, Value)>> = BTreeMap::new();
+    for (id, record) in records {
+        match validate_record(&record) {
+            Ok(()) => valid.push((id, record)),
+            Err(reason) => {
+                warn!("Invalid record from {}: {}", source, reason);
+                invalid.entry(reason).or_default().push((id, record));
+            }
+        }
+    }
+    let mut quarantined = 0;
+    for (reason, records) in invalid {
+        quarantined += quarantine(&records, &reason, source, graph).await?;
+    }
+    Ok((valid, quarantined))
+}
+
+fn entry(id: &str, raw: &Value, reason: &str, source: &str) -> Value {
+    json!({
+        "id": id,
+        "raw": raw,
+        "reason": reason,
+        "source": source,
+        "received_at": chrono::Utc::now().to_rfc3339()
+    })
+}
+
+// Quarantines records under the given ids. Ids that are already quarantined are skipped;
+// returns the number of records newly quarantined.
+pub async fn quarantine(records: &[(String, Value)], reason: &str, source: &str, graph: &Graph) -> Result<usize, String> {
+    if records.is_empty() {
+        return Ok(0);
+    }
+    let entries: Vec<Value> = records.iter().map(|(id, raw)| entry(id, raw, reason, source)).collect();
+
+    let created = match QuarantineBackend::from_config() {
+        QuarantineBackend::Neo4j => {
+            let rows: Vec<Vec<String>> = entries.iter().map(|e| vec![
+                e["id"].as_str().unwrap_or_default().to_string(),
+                e["raw"].to_string(),
+                e["received_at"].as_str().unwrap_or_default().to_string(),
+            ]).collect();
+            let quarantine_query = query(r#"
+                UNWIND $rows AS row
+                OPTIONAL MATCH (existing:RejectedRecord {id: row[0]})
+                WITH row, existing
+                WHERE existing IS NULL
+                CREATE (:RejectedRecord {
+                    id: row[0],
+                    raw: row[1],
+                    received_at: row[2],
+                    reason: $reason,
+                    source: $source
+                })
+                RETURN count(*) AS created
+            "#)
+            .param("rows", rows)
+            .param("reason", reason)
+            .param("source", source);
+
+            let quarantine_failed = |e: neo4rs::Error| {
+                error!("Failed to quarantine {} records: {}", records.len(), e);
+                format!("Failed to quarantine records: {}", e)
+            };
+            let mut result = graph.execute(quarantine_query).await.map_err(quarantine_failed)?;
+            match result.next().await.map_err(quarantine_failed)? {
+                Some(row) => row.get::<i64>("created").unwrap_or(0) as usize,
+                None => 0,
+            }
+        },
+        QuarantineBackend::File(path) => {
+            let _guard = FILE_LOCK.lock().await;
+            let existing: HashSet<String> = read_file_entries(&path).await?
+                .into_iter()
+                .filter_map(|e| e["id"].as_str().map(str::to_string))
+                .collect();
+            let mut lines = String::new();
+            let mut created = 0;
+            for e in entries.iter().filter(|e| !existing.contains(e["id"].as_str().unwrap_or_default())) {
+                lines.push_str(&e.to_string());
+                lines.push('\n');
+                created += 1;
+            }
+            let mut file = OpenOptions::new().create(true).append(true).open(&path).await
+                .map_err(|e| format!("Failed to open quarantine file {}: {}", path.display(), e))?;
+            file.write_all(lines.as_bytes()).await
+                .map_err(|e| format!("Failed to write quarantine file {}: {}", path.display(), e))?;
+            created
+        },
+    };
+    if created < records.len() {
+        info!("{} of {} records from {} were already quarantined", records.len() - created, records.len(), source);
+    }
+    info!("Quarantined {} records from {}: {}", created, source, reason);
+    Ok(created)
+}
+
+async fn read_file_entries(path: &PathBuf) -> Result<Vec<Value>, String> {
+    match fs::read_to_string(path).await {
+        Ok(content) => Ok(content.lines()
+            .filter(|l| !l.trim().is_empty())
+            .filter_map(|l| serde_json::from_str(l).ok())
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("Failed to read quarantine file {}: {}", path.display(), e)),
+    }
+}
+
+async fn write_file_entries(path: &PathBuf, entries: &[Value]) -> Result<(), String> {
+    let mut content = String::new();
+    for e in entries {
+        content.push_str(&e.to_string());
+        content.push('\n');
+    }
+    fs::write(path, content).await
+        .map_err(|e| format!("Failed to write quarantine file {}: {}", path.display(), e))
+}
+
+fn row_to_entry(row: &neo4rs::Row) -> Value {
+    let raw: String = row.get("raw").unwrap_or_default();
+    json!({
+        "id": row.get::<String>("id").unwrap_or_default(),
+        "raw": serde_json::from_str::<Value>(&raw).unwrap_or(Value::String(raw)),
+        "reason": row.get::<String>("reason").unwrap_or_default(),
+        "source": row.get::<String>("source").unwrap_or_default(),
+        "received_at": row.get::<String>("received_at").unwrap_or_default()
+    })
+}
+
+// Returns the newest quarantined records first
+pub async fn list(limit: usize, graph: &Graph) -> Result<Vec<Value>, String> {
+    match QuarantineBackend::from_config() {
+        QuarantineBackend::Neo4j => {
+            let list_query = query(r#"
+                MATCH (r:RejectedRecord)
+                RETURN r.id AS id, r.raw AS raw, r.reason AS reason,
+                       r.source AS source, r.received_at AS received_at
+                ORDER BY r.received_at DESC
+                LIMIT $limit
+            "#)
+            .param("limit", limit.min(i64::MAX as usize) as i64);
+
+            let mut result = graph.execute(list_query).await
+                .map_err(|e| format!("Failed to list quarantined records: {}", e))?;
+            let mut entries = Vec::new();
+            while let Ok(Some(row)) = result.next().await {
+                entries.push(row_to_entry(&row));
+            }
+            Ok(entries)
+        },
+        QuarantineBackend::File(path) => {
+            let _guard = FILE_LOCK.lock().await;
+            let entries = read_file_entries(&path).await?;
+            Ok(entries.into_iter().rev().take(limit).collect())
+        },
+    }
+}
+
+pub async fn get(id: &str, graph: &Graph) -> Result<Option<Value>, String> {
+    match QuarantineBackend::from_config() {
+        QuarantineBackend::Neo4j => {
+            let get_query = query(r#"
+                MATCH (r:RejectedRecord {id: $id})
+                RETURN r.id AS id, r.raw AS raw, r.reason AS reason,
+                       r.source AS source, r.received_at AS received_at
+            "#)
+            .param("id", id);
+
+            let mut result = graph.execute(get_query).await
+                .map_err(|e| format!("Failed to read quarantined record: {}", e))?;
+            match result.next().await {
+                Ok(Some(row)) => Ok(Some(row_to_entry(&row))),
+                Ok(None) => Ok(None),
+                Err(e) => Err(format!("Failed to read quarantined record: {}", e)),
+            }
+        },
+        QuarantineBackend::File(path) => {
+            let _guard = FILE_LOCK.lock().await;
+            let entries = read_file_entries(&path).await?;
+            Ok(entries.into_iter().find(|e| e["id"] == id))
+        },
+    }
+}
+
+// Removes the given record, or every quarantined record when `id` is None.
+// Returns the number of removed records.
+pub async fn purge(id: Option<&str>, graph: &Graph) -> Result<usize, String> {
+    match QuarantineBackend::from_config() {
+        QuarantineBackend::Neo4j => {
+            let purge_query = query(r#"
+                MATCH (r:RejectedRecord)
+                WHERE $id = '' OR r.id = $id
+                DETACH DELETE r
+                RETURN count(r) AS removed
+            "#)
+            .param("id", id.unwrap_or(""));
+
+            let mut result = graph.execute(purge_query).await
+                .map_err(|e| format!("Failed to purge quarantined records: {}", e))?;
+            match result.next().await {
+                Ok(Some(row)) => Ok(row.get::<i64>("removed").unwrap_or(0) as usize),
+                Ok(None) => Ok(0),
+                Err(e) => Err(format!("Failed to purge quarantined records: {}", e)),
+            }
+        },
+        QuarantineBackend::File(path) => {
+            let _guard = FILE_LOCK.lock().await;
+            let entries = read_file_entries(&path).await?;
+            let before = entries.len();
+            let kept: Vec<Value> = match id {
+                Some(id) => entries.into_iter().filter(|e| e["id"] != id).collect(),
+                None => Vec::new(),
+            };
+            write_file_entries(&path, &kept).await?;
+            Ok(before - kept.len())
+        },
+    }
+}
+
+// Re-validates quarantined records and ingests the ones that pass. Successfully replayed
+// records are removed from quarantine, the others stay with their original reason.
+pub async fn replay(id: Option<&str>, graph: &Graph) -> Result<Value, String> {
+    let entries = match id {
+        Some(id) => get(id, graph).await?.into_iter().collect(),
+        None => list(usize::MAX, graph).await?,
+    };
+    if entries.is_empty() {
+        return Err(match id {
+            Some(id) => format!("No quarantined record with id '{}'", id),
+            None => "Quarantine is empty".to_string(),
+        });
+    }
+
+    let policy = UnknownDevicePolicy::from_config();
+    let mut replayed = Vec::new();
+    let mut skipped = Vec::new();
+
+    for entry in entries {
+        let entry_id = entry["id"].as_str().unwrap_or_default().to_string();
+        let raw = entry["raw"].clone();
+
+        if let Err(reason) = validate_record(&raw) {
+            skipped.push(json!({ "id": entry_id, "reason": reason }));
+            continue;
+        }
+        if policy != UnknownDevicePolicy::Accept {
+            let uuid = raw["uuid"].as_str().unwrap_or_default().to_string();
+            if active_device_ids(&[uuid], graph).await?.is_empty() {
+                skipped.push(json!({ "id": entry_id, "reason": "device is still not registered" }));
+                continue;
+            }
+        }
+
+        let batch = json!({ "data": [raw] });
+        match create_new_relation(&batch, graph, IngestSettings::from_config()).await {
+            Ok(_) => {
+                purge(Some(&entry_id), graph).await?;
+                replayed.push(entry_id);
+            },
+            Err(e) => skipped.push(json!({ "id": entry_id, "reason": format!("ingest failed: {}", e) })),
+        }
+    }
+
+    info!("Replayed {} quarantined records, {} skipped", replayed.len(), skipped.len());
+    Ok(json!({ "replayed": replayed, "skipped": skipped }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn record() -> Value {
+        json!({
+            "uuid": "device-1",
+            "color": "red",
+            "timestamp": "2024-01-01T00:00:00Z",
+            "energy_consume": 1.5,
+            "energy_cost": 0.3,
+            "sensor_data": { "temperature": 21.0, "humidity": 40 }
+        })
+    }
+
+    #[test]
+    fn accepts_a_complete_record() {
+        assert_eq!(validate_record(&record()), Ok(()));
+    }
+
+    #[test]
+    fn rejects_non_objects() {
+        assert_eq!(validate_record(&json!([1, 2])), Err("record is not a JSON object".to_string()));
+    }
+
+    #[test]
+    fn rejects_missing_and_empty_strings() {
+        let mut missing = record();
+        missing.as_object_mut().unwrap().remove("color");
+        assert_eq!(validate_record(&missing), Err("'color' is missing or not a string".to_string()));
+
+        let mut empty = record();
+        empty["uuid"] = json!("  ");
+        assert_eq!(validate_record(&empty), Err("'uuid' is empty".to_string()));
+
+        let mut number = record();
+        number["timestamp"] = json!(1700000000);
+        assert_eq!(validate_record(&number), Err("'timestamp' is missing or not a string".to_string()));
+    }
+
+    #[test]
+    fn rejects_non_numeric_measurements() {
+        let mut cost = record();
+        cost["energy_cost"] = json!("0.3");
+        assert_eq!(validate_record(&cost), Err("'energy_cost' is missing or not a number".to_string()));
+
+        let mut sensor = record();
+        sensor["sensor_data"] = json!(null);
+        assert_eq!(validate_record(&sensor), Err("'sensor_data' is missing or not an object".to_string()));
+
+        let mut humidity = record();
+        humidity["sensor_data"]["humidity"] = json!(null);
+        assert_eq!(validate_record(&humidity), Err("'sensor_data.humidity' is missing or not a number".to_string()));
+    }
+}
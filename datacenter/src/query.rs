@@ -1,408 +1,817 @@
-use neo4rs::{Graph, query};
-use log::error;
-use serde_json::Value;
-use std::collections::HashMap;
-use serde_json::json;
-
-pub async fn create_new_relation(data: &Value, graph: &Graph) -> bool {
-    
-    if let Some(data_array) = data.get("data").and_then(|d| d.as_array()) {
-        if data_array.is_empty() {
-            return false;
-        }
-        
-        let mut neo4j_data = Vec::new();
-        
-        for item in data_array {
-            let mut record = HashMap::<String, String>::new();
-
-            
-            if let Some(uuid) = item.get("uuid").and_then(|v| v.as_str()) {
-                record.insert("uuid".to_string(), uuid.to_string());
-            }
-
-            if let Some(color) = item.get("color").and_then(|v| v.as_str()) {
-                record.insert("color".to_string(), color.to_string());
-            }
-
-            if let Some(timestamp) = item.get("timestamp").and_then(|v| v.as_str()) {
-                record.insert("timestamp".to_string(), timestamp.to_string());
-            }
-
-          
-            if let Some(energy_consume) = item.get("energy_consume").and_then(|v| v.as_f64()) {
-                let energy_consume_str = energy_consume.to_string();
-                record.insert("energy_consume".to_string(), energy_consume_str);
-            }
-
-            if let Some(energy_cost) = item.get("energy_cost").and_then(|v| v.as_f64()) {
-                let energy_cost_str = energy_cost.to_string();
-                record.insert("energy_cost".to_string(), energy_cost_str);
-            }
-
-           
-            if let Some(sensor_data) = item.get("sensor_data").and_then(|v| v.as_object()) {
-                if let Some(temp) = sensor_data.get("temperature").and_then(|v| v.as_f64()) {
-                    let temp_str = temp.to_string();
-                    record.insert("sensor_data.temperature".to_string(), temp_str);
-                }
-                
-                if let Some(humidity) = sensor_data.get("humidity").and_then(|v| v.as_f64()) {
-                    let humidity_str = humidity.to_string();
-                    record.insert("sensor_data.humidity".to_string(), humidity_str);
-                }
-            }
-            
-            neo4j_data.push(record);
-        }
-        
-        let creation_query = query(r#"
-        UNWIND $data AS record
-        
-        OPTIONAL MATCH (existingUuid:UUID {id: record.uuid})
-        WITH record, existingUuid
-        WHERE existingUuid IS NULL
-        
-        MERGE (uuid:UUID {id: record.uuid})
-        SET uuid.energy_consume = toFloat(record.energy_consume),
-            uuid.energy_cost = toFloat(record.energy_cost)
-        
-        MERGE (color:Color {value: record.color})
-        MERGE (uuid)-[:HAS_COLOR]->(color)
-        
-        MERGE (temperature:Temperature {value: toFloat(record.`sensor_data.temperature`)})
-        MERGE (uuid)-[:HAS_TEMPERATURE]->(temperature)
-        
-        MERGE (humidity:Humidity {value: toFloat(record.`sensor_data.humidity`)})
-        MERGE (uuid)-[:HAS_HUMIDITY]->(humidity)
-        
-        MERGE (timestamp:Timestamp {value: record.timestamp})
-        MERGE (uuid)-[:HAS_TIMESTAMP]->(timestamp)
-        
-        MERGE (timestamp)-[:SENSOR_DATA]->(temperature)
-        MERGE (timestamp)-[:SENSOR_DATA]->(humidity)
-        
-        MERGE (energyCost:EnergyCost {value: toFloat(record.energy_cost)})
-        MERGE (uuid)-[:HAS_ENERGYCOST]->(energyCost)
-        MERGE (timestamp)-[:HAS_PRICE]->(energyCost)
-        
-        MERGE (energyConsume:EnergyConsume {value: toFloat(record.energy_consume)})
-        MERGE (uuid)-[:HAS_ENERGYCONSUME]->(energyConsume)
-        
-        WITH uuid
-        RETURN uuid.id AS processed_uuid
-        "#)
-        .param("data", neo4j_data);
-
-        match graph.execute(creation_query).await {
-            Ok(mut result) => {
-                match result.next().await {
-                    Ok(Some(_)) => true,
-                    _ => {
-                        error!("No new nodes were created (UUIDs might already exist)");
-                        false
-                    }
-                }
-            },
-            Err(e) => {
-                error!("Failed to execute Neo4j query: {}", e);
-                false
-            }
-        }
-    } else {
-        error!("Invalid JSON structure: 'data' array not found");
-        false
-    }
-}
-
-
-
-pub async fn get_specific_uuid_node(uuid: &str, graph: &Graph) -> Option<Value> {
-    let query = query(r#"
-        MATCH (uuidNode:UUID {id: $uuid})
-        OPTIONAL MATCH (uuidNode)-[:HAS_COLOR]->(color:Color)
-        OPTIONAL MATCH (uuidNode)-[:HAS_TIMESTAMP]->(timestamp:Timestamp)
-        WITH uuidNode, color, timestamp
-        ORDER BY timestamp.value DESC
-        LIMIT 1
-        OPTIONAL MATCH (timestamp)-[:SENSOR_DATA]->(temp:Temperature)
-        OPTIONAL MATCH (timestamp)-[:SENSOR_DATA]->(humidity:Humidity)
-        RETURN uuidNode.id AS uuid,
-               color.value AS color,
-               { temperature: temp.value, humidity: humidity.value } AS sensor_data,
-               timestamp.value AS timestamp,
-               uuidNode.energy_consume AS energy_consume,
-               uuidNode.energy_cost AS energy_cost
-    "#)
-    .param("uuid", uuid);
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            if let Ok(Some(row)) = result.next().await {
-                // Extract values from the query result
-                let uuid_val: String = row.get("uuid").unwrap_or_default();
-                let color_val: String = row.get("color").unwrap_or_default();
-                let sensor_data: Value = row.get("sensor_data").unwrap_or(json!({}));
-                let timestamp_val: String = row.get("timestamp").unwrap_or_default();
-                let energy_consume: f64 = row.get("energy_consume").unwrap_or(0.0);
-                let energy_cost: f64 = row.get("energy_cost").unwrap_or(0.0);
-
-                // Construct the final JSON object
-                Some(json!({
-                    "uuid": uuid_val,
-                    "color": color_val,
-                    "sensor_data": {
-                        "temperature": sensor_data["temperature"].as_f64().unwrap_or(0.0),
-                        "humidity": sensor_data["humidity"].as_f64().unwrap_or(0.0)
-                    },
-                    "timestamp": timestamp_val,
-                    "energy_consume": energy_consume,
-                    "energy_cost": energy_cost
-                }))
-            } else {
-                None
-            }
-        },
-        Err(e) => {
-            error!("Failed to execute Neo4j query: {}", e);
-            None
-        }
-    }
-}
-
-// Funktion, um alle UUID-Nodes zu bekommen und in JSON umzuwandeln
-pub async fn get_all_uuid_nodes(graph: &Graph) -> Option<Value> {
-    let query = query(r#"
-        MATCH (uuidNode:UUID)
-        OPTIONAL MATCH (uuidNode)-[:HAS_COLOR]->(color:Color)
-        OPTIONAL MATCH (uuidNode)-[:HAS_TIMESTAMP]->(timestamp:Timestamp)
-        WITH uuidNode, color, timestamp
-        ORDER BY timestamp.value DESC
-        WITH uuidNode, color, COLLECT(timestamp)[0] AS latest_timestamp
-        OPTIONAL MATCH (latest_timestamp)-[:SENSOR_DATA]->(temp:Temperature)
-        OPTIONAL MATCH (latest_timestamp)-[:SENSOR_DATA]->(humidity:Humidity)
-        RETURN uuidNode.id AS uuid,
-               color.value AS color,
-               { temperature: temp.value, humidity: humidity.value } AS sensor_data,
-               latest_timestamp.value AS timestamp,
-               uuidNode.energy_consume AS energy_consume,
-               uuidNode.energy_cost AS energy_cost
-    "#);
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            let mut uuids = Vec::new();
-            while let Ok(Some(row)) = result.next().await {
-                let uuid_val: String = row.get("uuid").unwrap_or_default();
-                let color_val: String = row.get("color").unwrap_or_default();
-                let sensor_data: Value = row.get("sensor_data").unwrap_or(json!({}));
-                let timestamp_val: String = row.get("timestamp").unwrap_or_default();
-                let energy_consume: f64 = row.get("energy_consume").unwrap_or(0.0);
-                let energy_cost: f64 = row.get("energy_cost").unwrap_or(0.0);
-
-                uuids.push(json!({
-                    "uuid": uuid_val,
-                    "color": color_val,
-                    "sensor_data": {
-                        "temperature": sensor_data["temperature"].as_f64().unwrap_or(0.0),
-                        "humidity": sensor_data["humidity"].as_f64().unwrap_or(0.0)
-                    },
-                    "timestamp": timestamp_val,
-                    "energy_consume": energy_consume,
-                    "energy_cost": energy_cost
-                }));
-            }
-            Some(json!(uuids))
-        },
-        Err(e) => {
-            error!("Failed to execute Neo4j query: {}", e);
-            None
-        }
-    }
-}
-
-pub async fn get_temperature_humidity_at_time(graph: &Graph, timestamp: &str) -> Option<(f64, f64)> {
-    let cypher_query = query(r#"
-        MATCH (t:Timestamp {value: $timestamp})-[:SENSOR_DATA]->(temp:Temperature),
-              (t)-[:SENSOR_DATA]->(hum:Humidity)
-        RETURN temp.value AS temperature, hum.value AS humidity
-    "#)
-    .param("timestamp", timestamp);
-
-    match graph.execute(cypher_query).await {
-        Ok(mut result) => {
-            if let Ok(Some(row)) = result.next().await {
-                let temperature: f64 = row.get("temperature").unwrap_or_default();
-                let humidity: f64 = row.get("humidity").unwrap_or_default();
-                return Some((temperature, humidity));
-            }
-            None
-        }
-        Err(e) => {
-            error!("Fehler beim Abrufen der Sensordaten: {}", e);
-            None
-        }
-    }
-}
-
-// Funktion, um alle Nodes innerhalb eines Zeitraums zu bekommen
-pub async fn get_nodes_in_time_range(start: &str, end: &str, graph: &Graph) -> Option<Value> {
-    let query = query(r#"
-        MATCH (uuid:UUID)-[:HAS_TIMESTAMP]->(timestamp:Timestamp)
-        WHERE timestamp.value >= $start AND timestamp.value <= $end
-        RETURN uuid
-    "#)
-    .param("start", start)
-    .param("end", end);
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            let mut uuids = Vec::new();
-            while let Ok(Some(row)) = result.next().await {
-                let node: Value = row.get("uuid").unwrap();
-                uuids.push(node);
-            }
-            Some(json!(uuids))
-        },
-        Err(e) => {
-            error!("Failed to execute Neo4j query: {}", e);
-            None
-        }
-    }
-}
-
-// Funktion, um alle Nodes mit einer bestimmten Temperatur oder Luftfeuchtigkeit zu bekommen
-pub async fn get_nodes_with_temperature_or_humidity(temp: f64, humidity: f64, graph: &Graph) -> Option<Value> {
-    let query = query(r#"
-        MATCH (uuid:UUID)-[:HAS_TEMPERATURE]->(temperature:Temperature {value: $temp}),
-              (uuid)-[:HAS_HUMIDITY]->(humidity:Humidity {value: $humidity})
-        RETURN uuid
-    "#)
-    .param("temp", temp)
-    .param("humidity", humidity);
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            let mut uuids = Vec::new();
-            while let Ok(Some(row)) = result.next().await {
-                let node: Value = row.get("uuid").unwrap();
-                uuids.push(node);
-            }
-            Some(json!(uuids))
-        },
-        Err(e) => {
-            error!("Failed to execute Neo4j query: {}", e);
-            None
-        }
-    }
-}
-
-// Funktion, um alle Nodes mit einer bestimmten Energiekosten zu bekommen
-pub async fn get_nodes_with_energy_cost(energy_cost: f64, graph: &Graph) -> Option<Value> {
-    let query = query(r#"
-        MATCH (uuid:UUID)-[:HAS_ENERGYCOST]->(energyCost:EnergyCost {value: $energy_cost})
-        RETURN uuid
-    "#)
-    .param("energy_cost", energy_cost);
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            let mut uuids = Vec::new();
-            while let Ok(Some(row)) = result.next().await {
-                let node: Value = row.get("uuid").unwrap();
-                uuids.push(node);
-            }
-            Some(json!(uuids))
-        },
-        Err(e) => {
-            error!("Failed to execute Neo4j query: {}", e);
-            None
-        }
-    }
-}
-
-// Funktion, um alle Nodes mit einem bestimmten Energieverbrauch zu bekommen
-pub async fn get_nodes_with_energy_consume(energy_consume: f64, graph: &Graph) -> Option<Value> {
-    let query = query(r#"
-        MATCH (uuid:UUID)-[:HAS_ENERGYCONSUME]->(energyConsume:EnergyConsume {value: $energy_consume})
-        RETURN uuid
-    "#)
-    .param("energy_consume", energy_consume);
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            let mut uuids = Vec::new();
-            while let Ok(Some(row)) = result.next().await {
-                let node: Value = row.get("uuid").unwrap();
-                uuids.push(node);
-            }
-            Some(json!(uuids))
-        },
-        Err(e) => {
-            error!("Failed to execute Neo4j query: {}", e);
-            None
-        }
-    }
-}
-
-// Funktion, um alle Nodes mit einer bestimmten Farbe zu bekommen
-pub async fn get_nodes_with_color(color: &str, graph: &Graph) -> Option<Value> {
-    let query = query(r#"
-        MATCH (uuid:UUID)-[:HAS_COLOR]->(color:Color {value: $color})
-        RETURN uuid
-    "#)
-    .param("color", color);
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            let mut uuids = Vec::new();
-            while let Ok(Some(row)) = result.next().await {
-                let node: Value = row.get("uuid").unwrap();
-                uuids.push(node);
-            }
-            Some(json!(uuids))
-        },
-        Err(e) => {
-            error!("Failed to execute Neo4j query: {}", e);
-            None
-        }
-    }
-}
-
-
-
-pub async fn reset_database_and_set_topology(graph: &Graph) -> Result<bool, String> {
-    
-    let delete_query = query(r#"
-        MATCH (n)
-        DETACH DELETE n
-    "#);
-
-    match graph.execute(delete_query).await {
-        Ok(_) => {
-            log::info!("Alle Nodes und Relationen wurden erfolgreich gelöscht");
-        },
-        Err(e) => {
-            let error_msg = format!("Fehler beim Löschen der Nodes und Relationen: {}", e);
-            error!("{}", error_msg);
-            return Err(error_msg);
-        }
-    }
-
-    
-    let topology_query = query(r#"
-        ALTER DATABASE neo4j SET TOPOLOGY 1 PRIMARIES 2 SECONDARIES
-    "#);
-
-    match graph.execute(topology_query).await {
-        Ok(_) => {
-            log::info!("Topologie erfolgreich auf 1 PRIMARY und 2 SECONDARY gesetzt");
-            Ok(true)
-        },
-        Err(e) => {
-            let error_msg = format!("Fehler beim Setzen der Topologie: {}", e);
-            error!("{}", error_msg);
-            Err(error_msg)
-        }
-    }
-}
\ No newline at end of file
+use crate::config;
+use crate::metrics;
+use crate::models::{Aggregate, GroupBy, ReadingRecord, SensorData, Stats};
+use serde::{Deserialize, Serialize};
+use neo4rs::{Graph, Txn, query};
+use log::error;
+use serde_json::Value;
+use std::collections::HashMap;
+use serde_json::json;
+
+// How the chunks of one ingest batch are committed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitMode {
+    // One transaction for the whole batch, nothing is written if any chunk fails
+    All,
+    // Every chunk commits on its own; chunks committed before a failure stay written
+    Chunk,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IngestSettings {
+    pub batch_size: usize,
+    pub commit_mode: CommitMode,
+}
+
+impl IngestSettings {
+    pub fn from_config() -> Self {
+        let ingest = &config::get().ingest;
+        IngestSettings { batch_size: ingest.batch_size, commit_mode: ingest.commit_mode }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IngestReport {
+    // Records handed to Neo4j
+    pub submitted: usize,
+    // Records Neo4j confirmed
+    pub written: usize,
+    pub chunks: usize,
+    // Filled in by `ingest::ingest_records`: invalid records sent to quarantine and
+    // records dropped by the unknown device policy
+    pub quarantined: usize,
+    pub rejected: usize,
+}
+
+const CREATION_QUERY: &str = r#"
+        UNWIND $data AS record
+        
+        MERGE (uuid:UUID {id: record.uuid})
+        SET uuid.energy_consume = toFloat(record.energy_consume),
+            uuid.energy_cost = toFloat(record.energy_cost)
+        
+        MERGE (color:Color {value: record.color})
+        MERGE (uuid)-[:HAS_COLOR]->(color)
+        
+        MERGE (temperature:Temperature {value: toFloat(record.`sensor_data.temperature`)})
+        MERGE (uuid)-[:HAS_TEMPERATURE]->(temperature)
+        
+        MERGE (humidity:Humidity {value: toFloat(record.`sensor_data.humidity`)})
+        MERGE (uuid)-[:HAS_HUMIDITY]->(humidity)
+        
+        MERGE (timestamp:Timestamp {value: record.timestamp})
+        MERGE (uuid)-[:HAS_TIMESTAMP]->(timestamp)
+        
+        MERGE (timestamp)-[:SENSOR_DATA]->(temperature)
+        MERGE (timestamp)-[:SENSOR_DATA]->(humidity)
+        MERGE (timestamp)-[:HAS_COLOR]->(color)
+        
+        MERGE (energyCost:EnergyCost {value: toFloat(record.energy_cost)})
+        MERGE (uuid)-[:HAS_ENERGYCOST]->(energyCost)
+        MERGE (timestamp)-[:HAS_PRICE]->(energyCost)
+        
+        MERGE (energyConsume:EnergyConsume {value: toFloat(record.energy_consume)})
+        MERGE (uuid)-[:HAS_ENERGYCONSUME]->(energyConsume)
+        MERGE (timestamp)-[:HAS_CONSUMPTION]->(energyConsume)
+        
+        WITH uuid
+        RETURN uuid.id AS processed_uuid
+"#;
+
+// Runs one chunk inside `txn` and returns the number of records written
+async fn write_chunk(txn: &mut Txn, chunk: &[HashMap<String, String>]) -> Result<usize, String> {
+    let creation_query = query(CREATION_QUERY).param("data", chunk.to_vec());
+    let mut result = txn.execute(creation_query).await
+        .map_err(|e| format!("Failed to execute Neo4j query: {}", e))?;
+
+    let mut written = 0;
+    while let Some(_row) = result.next(txn.handle()).await
+        .map_err(|e| format!("Failed to read Neo4j result: {}", e))? {
+        written += 1;
+    }
+    Ok(written)
+}
+
+// Writes the readings in `data` in explicit transactions, `settings.batch_size` records
+// per chunk. With `CommitMode::All` an error rolls back the whole batch; with
+// `CommitMode::Chunk` the chunks committed so far stay written. A retry of the same batch
+// is safe either way because every node and relationship is merged. Duplicates are
+// dropped before this, see dedupe.rs.
+pub async fn create_new_relation(data: &Value, graph: &Graph, settings: IngestSettings) -> Result<IngestReport, String> {
+    let _timer = metrics::query_timer("create_new_relation");
+    
+    if let Some(data_array) = data.get("data").and_then(|d| d.as_array()) {
+        if data_array.is_empty() {
+            return Ok(IngestReport::default());
+        }
+        let mut neo4j_data = Vec::new();
+        
+        for item in data_array {
+            let mut record = HashMap::<String, String>::new();
+
+            
+            if let Some(uuid) = item.get("uuid").and_then(|v| v.as_str()) {
+                record.insert("uuid".to_string(), uuid.to_string());
+            }
+
+            if let Some(color) = item.get("color").and_then(|v| v.as_str()) {
+                record.insert("color".to_string(), color.to_string());
+            }
+
+            if let Some(timestamp) = item.get("timestamp").and_then(|v| v.as_str()) {
+                record.insert("timestamp".to_string(), timestamp.to_string());
+            }
+
+          
+            if let Some(energy_consume) = item.get("energy_consume").and_then(|v| v.as_f64()) {
+                let energy_consume_str = energy_consume.to_string();
+                record.insert("energy_consume".to_string(), energy_consume_str);
+            }
+
+            if let Some(energy_cost) = item.get("energy_cost").and_then(|v| v.as_f64()) {
+                let energy_cost_str = energy_cost.to_string();
+                record.insert("energy_cost".to_string(), energy_cost_str);
+            }
+
+           
+            if let Some(sensor_data) = item.get("sensor_data").and_then(|v| v.as_object()) {
+                if let Some(temp) = sensor_data.get("temperature").and_then(|v| v.as_f64()) {
+                    let temp_str = temp.to_string();
+                    record.insert("sensor_data.temperature".to_string(), temp_str);
+                }
+                
+                if let Some(humidity) = sensor_data.get("humidity").and_then(|v| v.as_f64()) {
+                    let humidity_str = humidity.to_string();
+                    record.insert("sensor_data.humidity".to_string(), humidity_str);
+                }
+            }
+            
+            neo4j_data.push(record);
+        }
+        
+        let mut report = IngestReport { submitted: neo4j_data.len(), ..IngestReport::default() };
+        let chunks: Vec<&[HashMap<String, String>]> = neo4j_data.chunks(settings.batch_size).collect();
+        report.chunks = chunks.len();
+
+        let start_txn = || async {
+            graph.start_txn().await.map_err(|e| {
+                error!("Failed to start Neo4j transaction: {}", e);
+                format!("Failed to start Neo4j transaction: {}", e)
+            })
+        };
+
+        match settings.commit_mode {
+            CommitMode::All => {
+                let mut txn = start_txn().await?;
+                for (index, chunk) in chunks.iter().enumerate() {
+                    match write_chunk(&mut txn, chunk).await {
+                        Ok(written) => report.written += written,
+                        Err(e) => {
+                            error!("Chunk {}/{} failed, rolling back batch: {}", index + 1, report.chunks, e);
+                            if let Err(rollback_err) = txn.rollback().await {
+                                error!("Rollback failed: {}", rollback_err);
+                            }
+                            return Err(e);
+                        }
+                    }
+                }
+                txn.commit().await.map_err(|e| {
+                    error!("Failed to commit Neo4j transaction: {}", e);
+                    format!("Failed to commit Neo4j transaction: {}", e)
+                })?;
+            },
+            CommitMode::Chunk => {
+                for (index, chunk) in chunks.iter().enumerate() {
+                    let mut txn = start_txn().await?;
+                    let written = match write_chunk(&mut txn, chunk).await {
+                        Ok(written) => written,
+                        Err(e) => {
+                            error!("Chunk {}/{} failed after {} records were written: {}", index + 1, report.chunks, report.written, e);
+                            if let Err(rollback_err) = txn.rollback().await {
+                                error!("Rollback failed: {}", rollback_err);
+                            }
+                            return Err(e);
+                        }
+                    };
+                    txn.commit().await.map_err(|e| {
+                        error!("Failed to commit chunk {}/{}: {}", index + 1, report.chunks, e);
+                        format!("Failed to commit chunk {}/{}: {}", index + 1, report.chunks, e)
+                    })?;
+                    report.written += written;
+                }
+            },
+        }
+
+        Ok(report)
+    } else {
+        error!("Invalid JSON structure: 'data' array not found");
+        Err("Invalid JSON structure: 'data' array not found".to_string())
+    }
+}
+
+
+
+pub async fn get_specific_uuid_node(uuid: &str, graph: &Graph) -> Option<ReadingRecord> {
+    let _timer = metrics::query_timer("get_specific_uuid_node");
+    let query = query(r#"
+        MATCH (uuidNode:UUID {id: $uuid})
+        OPTIONAL MATCH (uuidNode)-[:HAS_COLOR]->(color:Color)
+        OPTIONAL MATCH (uuidNode)-[:HAS_TIMESTAMP]->(timestamp:Timestamp)
+        WITH uuidNode, color, timestamp
+        ORDER BY timestamp.value DESC
+        LIMIT 1
+        OPTIONAL MATCH (timestamp)-[:SENSOR_DATA]->(temp:Temperature)
+        OPTIONAL MATCH (timestamp)-[:SENSOR_DATA]->(humidity:Humidity)
+        RETURN uuidNode.id AS uuid,
+               color.value AS color,
+               { temperature: temp.value, humidity: humidity.value } AS sensor_data,
+               timestamp.value AS timestamp,
+               uuidNode.energy_consume AS energy_consume,
+               uuidNode.energy_cost AS energy_cost
+    "#)
+    .param("uuid", uuid);
+
+    match graph.execute(query).await {
+        Ok(mut result) => {
+            if let Ok(Some(row)) = result.next().await {
+                // Extract values from the query result
+                let sensor_data: Value = row.get("sensor_data").unwrap_or(json!({}));
+                Some(ReadingRecord {
+                    uuid: row.get("uuid").unwrap_or_default(),
+                    color: Some(row.get("color").unwrap_or_default()),
+                    sensor_data: SensorData {
+                        temperature: Some(sensor_data["temperature"].as_f64().unwrap_or(0.0)),
+                        humidity: Some(sensor_data["humidity"].as_f64().unwrap_or(0.0)),
+                    },
+                    timestamp: row.get("timestamp").unwrap_or_default(),
+                    energy_consume: Some(row.get("energy_consume").unwrap_or(0.0)),
+                    energy_cost: Some(row.get("energy_cost").unwrap_or(0.0)),
+                })
+            } else {
+                None
+            }
+        },
+        Err(e) => {
+            error!("Failed to execute Neo4j query: {}", e);
+            None
+        }
+    }
+}
+
+// Funktion, um alle UUID-Nodes zu bekommen und in JSON umzuwandeln
+pub async fn get_all_uuid_nodes(graph: &Graph) -> Option<Value> {
+    let _timer = metrics::query_timer("get_all_uuid_nodes");
+    let query = query(r#"
+        MATCH (uuidNode:UUID)
+        OPTIONAL MATCH (uuidNode)-[:HAS_COLOR]->(color:Color)
+        OPTIONAL MATCH (uuidNode)-[:HAS_TIMESTAMP]->(timestamp:Timestamp)
+        WITH uuidNode, color, timestamp
+        ORDER BY timestamp.value DESC
+        WITH uuidNode, color, COLLECT(timestamp)[0] AS latest_timestamp
+        OPTIONAL MATCH (latest_timestamp)-[:SENSOR_DATA]->(temp:Temperature)
+        OPTIONAL MATCH (latest_timestamp)-[:SENSOR_DATA]->(humidity:Humidity)
+        RETURN uuidNode.id AS uuid,
+               color.value AS color,
+               { temperature: temp.value, humidity: humidity.value } AS sensor_data,
+               latest_timestamp.value AS timestamp,
+               uuidNode.energy_consume AS energy_consume,
+               uuidNode.energy_cost AS energy_cost
+    "#);
+
+    match graph.execute(query).await {
+        Ok(mut result) => {
+            let mut uuids = Vec::new();
+            while let Ok(Some(row)) = result.next().await {
+                let uuid_val: String = row.get("uuid").unwrap_or_default();
+                let color_val: String = row.get("color").unwrap_or_default();
+                let sensor_data: Value = row.get("sensor_data").unwrap_or(json!({}));
+                let timestamp_val: String = row.get("timestamp").unwrap_or_default();
+                let energy_consume: f64 = row.get("energy_consume").unwrap_or(0.0);
+                let energy_cost: f64 = row.get("energy_cost").unwrap_or(0.0);
+
+                uuids.push(json!({
+                    "uuid": uuid_val,
+                    "color": color_val,
+                    "sensor_data": {
+                        "temperature": sensor_data["temperature"].as_f64().unwrap_or(0.0),
+                        "humidity": sensor_data["humidity"].as_f64().unwrap_or(0.0)
+                    },
+                    "timestamp": timestamp_val,
+                    "energy_consume": energy_consume,
+                    "energy_cost": energy_cost
+                }));
+            }
+            Some(json!(uuids))
+        },
+        Err(e) => {
+            error!("Failed to execute Neo4j query: {}", e);
+            None
+        }
+    }
+}
+
+pub async fn get_temperature_humidity_at_time(graph: &Graph, timestamp: &str) -> Option<(f64, f64)> {
+    let _timer = metrics::query_timer("get_temperature_humidity_at_time");
+    let cypher_query = query(r#"
+        MATCH (t:Timestamp {value: $timestamp})-[:SENSOR_DATA]->(temp:Temperature),
+              (t)-[:SENSOR_DATA]->(hum:Humidity)
+        RETURN temp.value AS temperature, hum.value AS humidity
+    "#)
+    .param("timestamp", timestamp);
+
+    match graph.execute(cypher_query).await {
+        Ok(mut result) => {
+            if let Ok(Some(row)) = result.next().await {
+                let temperature: f64 = row.get("temperature").unwrap_or_default();
+                let humidity: f64 = row.get("humidity").unwrap_or_default();
+                return Some((temperature, humidity));
+            }
+            None
+        }
+        Err(e) => {
+            error!("Failed to fetch sensor data: {}", e);
+            None
+        }
+    }
+}
+
+// Funktion, um alle Nodes innerhalb eines Zeitraums zu bekommen
+pub async fn get_nodes_in_time_range(start: &str, end: &str, graph: &Graph) -> Option<Value> {
+    let _timer = metrics::query_timer("get_nodes_in_time_range");
+    let query = query(r#"
+        MATCH (uuid:UUID)-[:HAS_TIMESTAMP]->(timestamp:Timestamp)
+        WHERE timestamp.value >= $start AND timestamp.value <= $end
+        RETURN uuid
+    "#)
+    .param("start", start)
+    .param("end", end);
+
+    match graph.execute(query).await {
+        Ok(mut result) => {
+            let mut uuids = Vec::new();
+            while let Ok(Some(row)) = result.next().await {
+                let node: Value = row.get("uuid").unwrap();
+                uuids.push(node);
+            }
+            Some(json!(uuids))
+        },
+        Err(e) => {
+            error!("Failed to execute Neo4j query: {}", e);
+            None
+        }
+    }
+}
+
+// Funktion, um alle Nodes mit einer bestimmten Temperatur oder Luftfeuchtigkeit zu bekommen
+pub async fn get_nodes_with_temperature_or_humidity(temp: f64, humidity: f64, graph: &Graph) -> Option<Value> {
+    let _timer = metrics::query_timer("get_nodes_with_temperature_or_humidity");
+    let query = query(r#"
+        MATCH (uuid:UUID)-[:HAS_TEMPERATURE]->(temperature:Temperature {value: $temp}),
+              (uuid)-[:HAS_HUMIDITY]->(humidity:Humidity {value: $humidity})
+        RETURN uuid
+    "#)
+    .param("temp", temp)
+    .param("humidity", humidity);
+
+    match graph.execute(query).await {
+        Ok(mut result) => {
+            let mut uuids = Vec::new();
+            while let Ok(Some(row)) = result.next().await {
+                let node: Value = row.get("uuid").unwrap();
+                uuids.push(node);
+            }
+            Some(json!(uuids))
+        },
+        Err(e) => {
+            error!("Failed to execute Neo4j query: {}", e);
+            None
+        }
+    }
+}
+
+// Funktion, um alle Nodes mit einer bestimmten Energiekosten zu bekommen
+pub async fn get_nodes_with_energy_cost(energy_cost: f64, graph: &Graph) -> Option<Value> {
+    let _timer = metrics::query_timer("get_nodes_with_energy_cost");
+    let query = query(r#"
+        MATCH (uuid:UUID)-[:HAS_ENERGYCOST]->(energyCost:EnergyCost {value: $energy_cost})
+        RETURN uuid
+    "#)
+    .param("energy_cost", energy_cost);
+
+    match graph.execute(query).await {
+        Ok(mut result) => {
+            let mut uuids = Vec::new();
+            while let Ok(Some(row)) = result.next().await {
+                let node: Value = row.get("uuid").unwrap();
+                uuids.push(node);
+            }
+            Some(json!(uuids))
+        },
+        Err(e) => {
+            error!("Failed to execute Neo4j query: {}", e);
+            None
+        }
+    }
+}
+
+// Funktion, um alle Nodes mit einem bestimmten Energieverbrauch zu bekommen
+pub async fn get_nodes_with_energy_consume(energy_consume: f64, graph: &Graph) -> Option<Value> {
+    let _timer = metrics::query_timer("get_nodes_with_energy_consume");
+    let query = query(r#"
+        MATCH (uuid:UUID)-[:HAS_ENERGYCONSUME]->(energyConsume:EnergyConsume {value: $energy_consume})
+        RETURN uuid
+    "#)
+    .param("energy_consume", energy_consume);
+
+    match graph.execute(query).await {
+        Ok(mut result) => {
+            let mut uuids = Vec::new();
+            while let Ok(Some(row)) = result.next().await {
+                let node: Value = row.get("uuid").unwrap();
+                uuids.push(node);
+            }
+            Some(json!(uuids))
+        },
+        Err(e) => {
+            error!("Failed to execute Neo4j query: {}", e);
+            None
+        }
+    }
+}
+
+// Funktion, um alle Nodes mit einer bestimmten Farbe zu bekommen
+pub async fn get_nodes_with_color(color: &str, graph: &Graph) -> Option<Value> {
+    let _timer = metrics::query_timer("get_nodes_with_color");
+    let query = query(r#"
+        MATCH (uuid:UUID)-[:HAS_COLOR]->(color:Color {value: $color})
+        RETURN uuid
+    "#)
+    .param("color", color);
+
+    match graph.execute(query).await {
+        Ok(mut result) => {
+            let mut uuids = Vec::new();
+            while let Ok(Some(row)) = result.next().await {
+                let node: Value = row.get("uuid").unwrap();
+                uuids.push(node);
+            }
+            Some(json!(uuids))
+        },
+        Err(e) => {
+            error!("Failed to execute Neo4j query: {}", e);
+            None
+        }
+    }
+}
+
+// Filters for reading queries; empty fields match everything. Timestamps compare as strings,
+// like the other time-range queries.
+#[derive(Debug, Clone, Default)]
+pub struct ReadingFilter {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub devices: Vec<String>,
+    pub colors: Vec<String>,
+    pub limit: Option<usize>,
+}
+
+impl ReadingFilter {
+    // Parses `{"from": .., "to": .., "devices": [..], "colors": [..], "limit": n}`, all optional
+    pub fn from_json(json: &Value) -> Result<ReadingFilter, String> {
+        let strings = |key: &str| -> Result<Vec<String>, String> {
+            match json.get(key) {
+                None | Some(Value::Null) => Ok(Vec::new()),
+                Some(Value::Array(items)) => items.iter()
+                    .map(|item| item.as_str().map(str::to_string).ok_or(format!("'{}' must be an array of strings", key)))
+                    .collect(),
+                Some(_) => Err(format!("'{}' must be an array of strings", key)),
+            }
+        };
+        Ok(ReadingFilter {
+            from: json.get("from").and_then(Value::as_str).map(str::to_string),
+            to: json.get("to").and_then(Value::as_str).map(str::to_string),
+            devices: strings("devices")?,
+            colors: strings("colors")?,
+            limit: json.get("limit").and_then(Value::as_u64).map(|n| n as usize),
+        })
+    }
+}
+
+// One reading as stored by `create_new_relation`
+#[derive(Debug, Clone)]
+pub struct Reading {
+    pub uuid: String,
+    pub color: Option<String>,
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+    pub timestamp: String,
+    pub energy_consume: Option<f64>,
+    pub energy_cost: Option<f64>,
+}
+
+impl Reading {
+    fn from_row(row: &neo4rs::Row) -> Reading {
+        Reading {
+            uuid: row.get("uuid").unwrap_or_default(),
+            color: row.get("color").ok(),
+            temperature: row.get("temperature").ok(),
+            humidity: row.get("humidity").ok(),
+            timestamp: row.get("timestamp").unwrap_or_default(),
+            energy_consume: row.get("energy_consume").ok(),
+            energy_cost: row.get("energy_cost").ok(),
+        }
+    }
+
+    // The record in the shape it was ingested in
+    pub fn to_record(&self) -> ReadingRecord {
+        ReadingRecord {
+            uuid: self.uuid.clone(),
+            color: self.color.clone(),
+            sensor_data: SensorData { temperature: self.temperature, humidity: self.humidity },
+            timestamp: self.timestamp.clone(),
+            energy_consume: self.energy_consume,
+            energy_cost: self.energy_cost,
+        }
+    }
+}
+
+// Binds `uuid` and `timestamp` and the values of one reading, `color`, `temperature`,
+// `humidity`, `energy_consume` and `energy_cost`, for every reading that passes `filter`.
+// The values hang off the reading's Timestamp node; the UUID links only say which values a
+// device ever reported, so matching through them multiplies each reading. A Timestamp is
+// shared by devices reporting at the same instant, so each value is the first one linked.
+fn reading_match(filter: &ReadingFilter) -> String {
+    let mut conditions = Vec::new();
+    if filter.from.is_some() {
+        conditions.push("timestamp.value >= $from");
+    }
+    if filter.to.is_some() {
+        conditions.push("timestamp.value <= $to");
+    }
+    if !filter.devices.is_empty() {
+        conditions.push("uuid.id IN $devices");
+    }
+    let where_clause = match conditions.is_empty() {
+        true => String::new(),
+        false => format!("WHERE {}", conditions.join(" AND ")),
+    };
+    let color_clause = match filter.colors.is_empty() {
+        true => "",
+        false => "WHERE color IN $colors",
+    };
+    format!(r#"
+        MATCH (uuid:UUID)-[:HAS_TIMESTAMP]->(timestamp:Timestamp)
+        {}
+        {}
+        {}
+    "#, where_clause, READING_VALUES, color_clause)
+}
+
+const READING_VALUES: &str = r#"
+        WITH uuid, timestamp,
+             head([(timestamp)-[:HAS_COLOR]->(c:Color) | c.value]) AS color,
+             head([(timestamp)-[:SENSOR_DATA]->(t:Temperature) | t.value]) AS temperature,
+             head([(timestamp)-[:SENSOR_DATA]->(h:Humidity) | h.value]) AS humidity,
+             head([(timestamp)-[:HAS_CONSUMPTION]->(e:EnergyConsume) | e.value]) AS energy_consume,
+             head([(timestamp)-[:HAS_PRICE]->(e:EnergyCost) | e.value]) AS energy_cost
+"#;
+
+// The columns `Reading::from_row` reads
+const READING_RETURN: &str = r#"
+        RETURN uuid.id AS uuid,
+               color,
+               temperature,
+               humidity,
+               timestamp.value AS timestamp,
+               energy_consume,
+               energy_cost
+"#;
+
+fn with_filter_params(mut filter_query: neo4rs::Query, filter: &ReadingFilter) -> neo4rs::Query {
+    if let Some(from) = &filter.from {
+        filter_query = filter_query.param("from", from.as_str());
+    }
+    if let Some(to) = &filter.to {
+        filter_query = filter_query.param("to", to.as_str());
+    }
+    if !filter.devices.is_empty() {
+        filter_query = filter_query.param("devices", filter.devices.clone());
+    }
+    if !filter.colors.is_empty() {
+        filter_query = filter_query.param("colors", filter.colors.clone());
+    }
+    if let Some(limit) = filter.limit {
+        filter_query = filter_query.param("limit", limit as i64);
+    }
+    filter_query
+}
+
+fn readings_cypher(filter: &ReadingFilter) -> String {
+    let limit = if filter.limit.is_some() { "LIMIT $limit" } else { "" };
+    format!(r#"
+        {}
+        {}
+        ORDER BY timestamp, uuid
+        {}
+    "#, reading_match(filter), READING_RETURN, limit)
+}
+
+fn latest_readings_cypher() -> String {
+    format!(r#"
+        MATCH (uuid:UUID)-[:HAS_TIMESTAMP]->(timestamp:Timestamp)
+        WHERE uuid.id IN $uuids
+        WITH uuid, max(timestamp.value) AS latest
+        MATCH (uuid)-[:HAS_TIMESTAMP]->(timestamp:Timestamp {{value: latest}})
+        {}
+        {}
+    "#, READING_VALUES, READING_RETURN)
+}
+
+// The newest reading of each of `uuids` that has any, in one query
+pub async fn latest_readings(uuids: &[String], graph: &Graph) -> Result<HashMap<String, Reading>, String> {
+    let _timer = metrics::query_timer("latest_readings");
+    let latest_query = query(&latest_readings_cypher()).param("uuids", uuids.to_vec());
+    let mut result = graph.execute(latest_query).await
+        .map_err(|e| format!("Failed to execute Neo4j query: {}", e))?;
+
+    let mut readings = HashMap::new();
+    while let Some(row) = result.next().await.map_err(|e| format!("Failed to read Neo4j result: {}", e))? {
+        let reading = Reading::from_row(&row);
+        readings.insert(reading.uuid.clone(), reading);
+    }
+    Ok(readings)
+}
+
+fn readings_query(filter: &ReadingFilter) -> neo4rs::Query {
+    with_filter_params(query(&readings_cypher(filter)), filter)
+}
+
+// Streams the readings matching `filter` into `each`, oldest first, and returns how many
+// there were. Rows are handed over one at a time so large exports never sit in memory.
+pub async fn for_each_reading(filter: &ReadingFilter, graph: &Graph, mut each: impl FnMut(Reading) -> Result<(), String>) -> Result<usize, String> {
+    let _timer = metrics::query_timer("for_each_reading");
+    let mut result = graph.execute(readings_query(filter)).await
+        .map_err(|e| format!("Failed to execute Neo4j query: {}", e))?;
+
+    let mut count = 0;
+    while let Some(row) = result.next().await.map_err(|e| format!("Failed to read Neo4j result: {}", e))? {
+        each(Reading::from_row(&row))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Like `for_each_reading`, but hands the readings to a channel so the receiver can work on
+// them elsewhere, e.g. on a blocking thread. Stops when the receiver is gone.
+pub async fn send_readings(filter: &ReadingFilter, graph: &Graph, tx: &tokio::sync::mpsc::Sender<Reading>) -> Result<usize, String> {
+    let _timer = metrics::query_timer("send_readings");
+    let mut result = graph.execute(readings_query(filter)).await
+        .map_err(|e| format!("Failed to execute Neo4j query: {}", e))?;
+
+    let mut count = 0;
+    while let Some(row) = result.next().await.map_err(|e| format!("Failed to read Neo4j result: {}", e))? {
+        tx.send(Reading::from_row(&row)).await.map_err(|_| "The receiver of the readings stopped".to_string())?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// The UUIDs that reported each of `colors`, sorted
+pub async fn devices_with_colors(colors: &[String], graph: &Graph) -> Result<HashMap<String, Vec<String>>, String> {
+    let _timer = metrics::query_timer("devices_with_colors");
+    let devices_query = query(r#"
+        MATCH (uuid:UUID)-[:HAS_COLOR]->(color:Color)
+        WHERE color.value IN $colors
+        WITH color.value AS color, uuid.id AS device
+        ORDER BY device
+        RETURN color, collect(DISTINCT device) AS devices
+    "#)
+    .param("colors", colors.to_vec());
+    let mut result = graph.execute(devices_query).await
+        .map_err(|e| format!("Failed to execute Neo4j query: {}", e))?;
+
+    let mut devices = HashMap::new();
+    while let Some(row) = result.next().await.map_err(|e| format!("Failed to read Neo4j result: {}", e))? {
+        if let Ok(color) = row.get::<String>("color") {
+            devices.insert(color, row.get::<Vec<String>>("devices").unwrap_or_default());
+        }
+    }
+    Ok(devices)
+}
+
+fn aggregate_cypher(filter: &ReadingFilter, group_by: Option<GroupBy>) -> String {
+    let group = match group_by {
+        Some(GroupBy::Device) => "uuid.id AS group_key,",
+        Some(GroupBy::Color) => "color AS group_key,",
+        None => "",
+    };
+    let limit = match (group_by, filter.limit) {
+        (Some(_), Some(_)) => "ORDER BY group_key LIMIT $limit",
+        _ => "",
+    };
+    format!(r#"
+        {}
+        RETURN {}
+               count(*) AS readings,
+               avg(temperature) AS avg_temperature,
+               min(temperature) AS min_temperature,
+               max(temperature) AS max_temperature,
+               avg(humidity) AS avg_humidity,
+               min(humidity) AS min_humidity,
+               max(humidity) AS max_humidity,
+               sum(energy_consume) AS energy_consume,
+               sum(energy_cost) AS energy_cost
+        {}
+    "#, reading_match(filter), group, limit)
+}
+
+// Count, temperature and humidity statistics and energy totals of the readings matching
+// `filter`: one aggregate overall, or one per device or color. With a group and a limit,
+// only the first `limit` groups by key are computed.
+pub async fn aggregate_readings(filter: &ReadingFilter, group_by: Option<GroupBy>, graph: &Graph) -> Result<Vec<Aggregate>, String> {
+    let _timer = metrics::query_timer("aggregate_readings");
+    let aggregate_query = query(&aggregate_cypher(filter, group_by));
+    let mut result = graph.execute(with_filter_params(aggregate_query, filter)).await
+        .map_err(|e| format!("Failed to execute Neo4j query: {}", e))?;
+
+    let mut groups = Vec::new();
+    while let Some(row) = result.next().await.map_err(|e| format!("Failed to read Neo4j result: {}", e))? {
+        let number = |key: &str| row.get::<f64>(key).ok();
+        groups.push(Aggregate {
+            group: group_by.and_then(|_| row.get::<String>("group_key").ok()),
+            readings: row.get("readings").unwrap_or(0),
+            temperature: Stats { avg: number("avg_temperature"), min: number("min_temperature"), max: number("max_temperature") },
+            humidity: Stats { avg: number("avg_humidity"), min: number("min_humidity"), max: number("max_humidity") },
+            energy_consume: number("energy_consume"),
+            energy_cost: number("energy_cost"),
+        });
+    }
+    groups.sort_by(|a, b| a.group.cmp(&b.group));
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> ReadingFilter {
+        ReadingFilter {
+            from: Some("2024-01-01".into()),
+            devices: vec!["a".into()],
+            colors: vec!["red".into()],
+            ..ReadingFilter::default()
+        }
+    }
+
+    // One row per UUID and Timestamp: every other pattern starts at the reading's Timestamp
+    // and yields a single value
+    fn assert_one_row_per_reading(cypher: &str) {
+        assert_eq!(cypher.matches("MATCH").count(), 1, "{}", cypher);
+        assert!(cypher.contains("MATCH (uuid:UUID)-[:HAS_TIMESTAMP]->(timestamp:Timestamp)"));
+        for relation in ["HAS_TEMPERATURE", "HAS_HUMIDITY", "HAS_ENERGYCONSUME", "HAS_ENERGYCOST", "(uuid)-[:HAS_COLOR]"] {
+            assert!(!cypher.contains(relation), "{} in {}", relation, cypher);
+        }
+        for value in [
+            "head([(timestamp)-[:HAS_COLOR]->(c:Color) | c.value]) AS color",
+            "head([(timestamp)-[:SENSOR_DATA]->(t:Temperature) | t.value]) AS temperature",
+            "head([(timestamp)-[:SENSOR_DATA]->(h:Humidity) | h.value]) AS humidity",
+            "head([(timestamp)-[:HAS_CONSUMPTION]->(e:EnergyConsume) | e.value]) AS energy_consume",
+            "head([(timestamp)-[:HAS_PRICE]->(e:EnergyCost) | e.value]) AS energy_cost",
+        ] {
+            assert!(cypher.contains(value), "{} missing from {}", value, cypher);
+        }
+    }
+
+    #[test]
+    fn readings_bind_values_per_reading() {
+        let cypher = readings_cypher(&filter());
+        assert_one_row_per_reading(&cypher);
+        assert!(cypher.contains("WHERE timestamp.value >= $from AND uuid.id IN $devices"));
+        assert!(cypher.contains("WHERE color IN $colors"));
+        assert!(!cypher.contains("LIMIT"));
+        assert!(readings_cypher(&ReadingFilter { limit: Some(5), ..filter() }).contains("LIMIT $limit"));
+    }
+
+    #[test]
+    fn latest_readings_take_the_values_of_the_newest_timestamp() {
+        let cypher = latest_readings_cypher();
+        assert!(cypher.contains("WITH uuid, max(timestamp.value) AS latest"));
+        assert!(cypher.contains("MATCH (uuid)-[:HAS_TIMESTAMP]->(timestamp:Timestamp {value: latest})"));
+        assert!(cypher.contains(READING_VALUES) && cypher.contains(READING_RETURN));
+    }
+
+    #[test]
+    fn aggregates_sum_the_values_of_each_reading() {
+        let cypher = aggregate_cypher(&filter(), None);
+        assert_one_row_per_reading(&cypher);
+        assert!(cypher.contains("sum(energy_consume) AS energy_consume"));
+        assert!(cypher.contains("sum(energy_cost) AS energy_cost"));
+        assert!(!cypher.contains("uuid.energy"), "{}", cypher);
+        assert!(!cypher.contains("group_key"));
+
+        let cypher = aggregate_cypher(&ReadingFilter { limit: Some(3), ..filter() }, Some(GroupBy::Color));
+        assert!(cypher.contains("RETURN color AS group_key,"));
+        assert!(cypher.contains("ORDER BY group_key LIMIT $limit"));
+    }
+}
@@ -0,0 +1,181 @@
+use log::{debug, info, error};
+use neo4rs::Graph;
+use serde_json::{Value, json};
+
+use crate::command_handler::{router, CommandContext};
+use crate::device;
+use crate::logging;
+use crate::message_schema::{self, Transport};
+use crate::query;
+use crate::status;
+
+// One-off query messages (`{"type": "uuid", "data": ...}` and friends). MQTT and the
+// WebSocket stream share them, so both transports answer the same request types with the
+// same payloads.
+
+// A response and the MQTT topic suffix it is published under
+pub struct Reply {
+    pub suffix: String,
+    pub body: Value,
+}
+
+impl Reply {
+    fn new(suffix: &str, body: Value) -> Self {
+        Reply { suffix: suffix.to_string(), body }
+    }
+}
+
+// Answers a query message that arrived over `transport`. Errors are messages for the log;
+// the caller decides whether the client hears about them.
+pub async fn handle(json_value: &Value, db: &Graph, ctx: &CommandContext, transport: Transport) -> Result<Reply, String> {
+    let source = &ctx.source;
+    match json_value.get("type").and_then(Value::as_str) {
+        Some("uuid") => {
+            let data = json_value.get("data").and_then(Value::as_str)
+                .ok_or_else(|| format!("Missing or invalid 'data' field for type 'uuid'. Input: {}", logging::payload(json_value)))?;
+            info!("Processing UUID: {} for {}", data, source);
+            match query::get_specific_uuid_node(data, db).await {
+                Some(node) => {
+                    let node = json!(node);
+                    info!("Found node for UUID {}", data);
+                    debug!("Node for UUID {}: {}", data, logging::payload(&node));
+                    Ok(Reply::new(data, node))
+                },
+                None => {
+                    info!("No node found for UUID: {}", data);
+                    Ok(Reply::new(data, json!({
+                        "uuid": data,
+                        "found": false,
+                        "message": "No data found for this UUID"
+                    })))
+                }
+            }
+        },
+        Some("all") => {
+            info!("Processing 'all' request for {}", source);
+            query::get_all_uuid_nodes(db).await
+                .map(|all_nodes| Reply::new("all", all_nodes))
+                .ok_or_else(|| format!("Failed to get all UUID nodes for {}", source))
+        },
+        Some("color") => {
+            info!("Processing 'color' data for {}", source);
+            let color_data = json_value.get("data").and_then(Value::as_str)
+                .ok_or_else(|| format!("Missing or invalid 'data' field for type 'color'. Input: {}", logging::payload(json_value)))?;
+            query::get_nodes_with_color(color_data, db).await
+                .map(|processed| Reply::new("color", processed))
+                .ok_or_else(|| format!("Failed to get nodes with color: {} for {}", color_data, source))
+        },
+        Some("time_range") => {
+            info!("Processing 'time_range' data for {}", source);
+            let start = json_value.get("start").and_then(Value::as_str);
+            let end = json_value.get("end").and_then(Value::as_str);
+            let (Some(start_time), Some(end_time)) = (start, end) else {
+                return Err(format!("Missing or invalid 'start' or 'end' fields for type 'time_range'. Input: {}", logging::payload(json_value)));
+            };
+            query::get_nodes_in_time_range(start_time, end_time, db).await
+                .map(|nodes| Reply::new("time_range", nodes))
+                .ok_or_else(|| format!("Failed to get nodes in time range from {} to {} for {}", start_time, end_time, source))
+        },
+        Some("temperature_humidity") => {
+            info!("Processing 'temperature_humidity' data for {}", source);
+            let temp = json_value.get("temperature").and_then(Value::as_f64);
+            let humidity = json_value.get("humidity").and_then(Value::as_f64);
+            let (Some(temp_val), Some(humidity_val)) = (temp, humidity) else {
+                return Err(format!("Missing or invalid 'temperature' or 'humidity' fields for type 'temperature_humidity'. Input: {}", logging::payload(json_value)));
+            };
+            query::get_nodes_with_temperature_or_humidity(temp_val, humidity_val, db).await
+                .map(|nodes| Reply::new("temperature_humidity", nodes))
+                .ok_or_else(|| format!("Failed to get nodes with temperature {} and humidity {} for {}", temp_val, humidity_val, source))
+        },
+        Some("timestamp") => {
+            info!("Processing 'timestamp' data for {}", source);
+            let timestamp = json_value.get("data").and_then(Value::as_str)
+                .ok_or_else(|| format!("Missing or invalid 'data' field for type 'timestamp'. Input: {}", logging::payload(json_value)))?;
+            query::get_temperature_humidity_at_time(db, timestamp).await
+                .map(|(temp, humidity)| Reply::new("timestamp", json!({
+                    "timestamp": timestamp,
+                    "temperature": temp,
+                    "humidity": humidity
+                })))
+                .ok_or_else(|| format!("Failed to get temperature and humidity at timestamp: {} for {}", timestamp, source))
+        },
+        Some("energy_cost") => {
+            info!("Processing 'energy_cost' data for {}", source);
+            let cost = json_value.get("data").and_then(Value::as_f64)
+                .ok_or_else(|| format!("Missing or invalid 'data' field for type 'energy_cost'. Input: {}", logging::payload(json_value)))?;
+            query::get_nodes_with_energy_cost(cost, db).await
+                .map(|nodes| Reply::new("energy_cost", nodes))
+                .ok_or_else(|| format!("Failed to get nodes with energy cost: {} for {}", cost, source))
+        },
+        Some("energy_consume") => {
+            info!("Processing 'energy_consume' data for {}", source);
+            let consume = json_value.get("data").and_then(Value::as_f64)
+                .ok_or_else(|| format!("Missing or invalid 'data' field for type 'energy_consume'. Input: {}", logging::payload(json_value)))?;
+            query::get_nodes_with_energy_consume(consume, db).await
+                .map(|nodes| Reply::new("energy_consume", nodes))
+                .ok_or_else(|| format!("Failed to get nodes with energy consumption: {} for {}", consume, source))
+        },
+        Some(action @ ("register_device" | "update_device" | "decommission_device")) => {
+            // Run as the registry commands, so they need the admin role here as well
+            info!("Processing '{}' request for {}", action, source);
+            let spec = json_value.get("device").cloned().unwrap_or(Value::Null);
+            let args = match action {
+                "decommission_device" => match spec.get("id") {
+                    Some(id) => json!({ "id": id }),
+                    None => json!({}),
+                },
+                _ => json!({ "device": spec }),
+            };
+            let response = match router(action, &args, ctx).await {
+                Ok(result) => json!({ "id": result["id"], "success": true }),
+                Err(e) => {
+                    error!("'{}' failed for {}: {}", action, source, e);
+                    json!({ "id": spec.get("id"), "success": false, "message": e.to_string() })
+                }
+            };
+            Ok(Reply::new(action, response))
+        },
+        Some("status") => {
+            info!("Processing 'status' request for {}", source);
+            Ok(Reply::new("status", status::report().await))
+        },
+        Some("command") => {
+            // Same registry as the TCP server, run with the caller's role
+            let command = json_value.get("command").and_then(Value::as_str).unwrap_or_default();
+            info!("Processing command '{}' for {}", command, source);
+            let args = json_value.get("args").cloned().unwrap_or_else(|| json!({}));
+            let response = match router(command, &args, ctx).await {
+                Ok(result) => json!({ "command": command, "success": true, "result": result }),
+                Err(e) => {
+                    error!("Command '{}' failed for {}: {}", command, source, e);
+                    json!({ "command": command, "success": false, "message": e.to_string() })
+                }
+            };
+            Ok(Reply::new("command", response))
+        },
+        Some("device") => {
+            info!("Processing 'device' request for {}", source);
+            let id = json_value.get("data").and_then(Value::as_str)
+                .ok_or_else(|| format!("Missing or invalid 'data' field for type 'device'. Input: {}", logging::payload(json_value)))?;
+            let response = device::get_device(id, db).await.map(|device| json!(device)).unwrap_or_else(|| json!({
+                "id": id,
+                "found": false,
+                "message": "No device registered with this id"
+            }));
+            Ok(Reply::new("device", response))
+        },
+        Some("devices") => {
+            info!("Processing 'devices' request for {}", source);
+            let filters = json_value.get("filters").cloned().unwrap_or(Value::Null);
+            device::list_devices(&filters, None, db).await
+                .map(|devices| Reply::new("devices", json!(devices)))
+                .ok_or_else(|| format!("Failed to list devices for {}", source))
+        },
+        Some("schema") => {
+            info!("Processing 'schema' request for {}", source);
+            message_schema::describe(json_value, transport).map(|schema| Reply::new("schema", schema))
+        },
+        Some(other) => Err(format!("Unknown type '{}' from {}. Full input: {}", other, source, logging::payload(json_value))),
+        None => Err(format!("Missing 'type' field in JSON from {}: {}", source, logging::payload(json_value))),
+    }
+}
@@ -0,0 +1,312 @@
+use log::{info, error};
+use neo4rs::{Graph, Query, query};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::config;
+
+// Reset is a two-step operation: the first `reset` call describes what would be deleted and
+// returns a confirmation token, a second call with `confirm: <token>` from the same
+// connection performs the deletion. Tokens expire after TOKEN_TTL.
+static PENDING: LazyLock<Mutex<HashMap<String, PendingReset>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+const TOKEN_TTL: Duration = Duration::from_secs(120);
+const DEFAULT_BATCH_SIZE: i64 = 10_000;
+
+struct PendingReset {
+    request: ResetRequest,
+    source: String,
+    created: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub enum ResetScope {
+    // Everything except the schema version bookkeeping
+    All,
+    Labels(Vec<String>),
+    // The Timestamp nodes in [from, to] with their links, i.e. the readings in the window.
+    // Devices stay; value nodes are removed once no reading or device links them.
+    TimeRange { from: String, to: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct ResetRequest {
+    pub scope: ResetScope,
+    pub export: bool,
+    pub batch_size: i64,
+}
+
+impl ResetRequest {
+    // Parses `{"scope": {"all": true} | {"labels": [...]} | {"from": .., "to": ..}, "export": bool, "batch_size": n}`
+    pub fn from_json(json: &Value) -> Result<ResetRequest, String> {
+        let scope = json.get("scope").ok_or("Missing 'scope': use {\"all\": true}, {\"labels\": [...]} or {\"from\": .., \"to\": ..}")?;
+
+        let labels = scope.get("labels").and_then(Value::as_array);
+        let from = scope.get("from").and_then(Value::as_str);
+        let to = scope.get("to").and_then(Value::as_str);
+        let all = scope.get("all").and_then(Value::as_bool) == Some(true);
+
+        let scope = match (all, labels, from, to) {
+            (true, None, None, None) => ResetScope::All,
+            (false, Some(labels), None, None) => {
+                let labels: Vec<String> = labels.iter().filter_map(Value::as_str).map(str::to_string).collect();
+                if labels.is_empty() {
+                    return Err("'scope.labels' must be a non-empty array of strings".to_string());
+                }
+                ResetScope::Labels(labels)
+            },
+            (false, None, Some(from), Some(to)) => ResetScope::TimeRange { from: from.to_string(), to: to.to_string() },
+            _ => return Err("'scope' must contain exactly one of 'all', 'labels' or 'from'/'to'".to_string()),
+        };
+
+        let batch_size = json.get("batch_size").and_then(Value::as_i64)
+            .filter(|size| *size > 0)
+            .unwrap_or(DEFAULT_BATCH_SIZE);
+        let export = json.get("export").and_then(Value::as_bool).unwrap_or(false);
+        Ok(ResetRequest { scope, export, batch_size })
+    }
+}
+
+// MATCH clause binding `n` to the nodes in scope
+fn scope_match(scope: &ResetScope) -> &'static str {
+    match scope {
+        ResetScope::All => "MATCH (n) WHERE NOT n:SchemaVersion",
+        ResetScope::Labels(_) => "MATCH (n) WHERE any(label IN labels(n) WHERE label IN $labels) AND NOT n:SchemaVersion",
+        ResetScope::TimeRange { .. } => "MATCH (n:Timestamp) WHERE n.value >= $from AND n.value <= $to",
+    }
+}
+
+fn scoped_query(scope: &ResetScope, tail: &str) -> Query {
+    let q = query(&format!("{} {}", scope_match(scope), tail));
+    match scope {
+        ResetScope::All => q,
+        ResetScope::Labels(labels) => q.param("labels", labels.clone()),
+        ResetScope::TimeRange { from, to } => q.param("from", from.clone()).param("to", to.clone()),
+    }
+}
+
+async fn count_in_scope(scope: &ResetScope, graph: &Graph) -> Result<i64, String> {
+    let mut result = graph.execute(scoped_query(scope, "RETURN count(DISTINCT n) AS nodes")).await
+        .map_err(|e| format!("Failed to count nodes in scope: {}", e))?;
+    match result.next().await {
+        Ok(Some(row)) => Ok(row.get::<i64>("nodes").unwrap_or(0)),
+        Ok(None) => Ok(0),
+        Err(e) => Err(format!("Failed to count nodes in scope: {}", e)),
+    }
+}
+
+fn describe(scope: &ResetScope) -> Value {
+    match scope {
+        ResetScope::All => json!({ "all": true }),
+        ResetScope::Labels(labels) => json!({ "labels": labels }),
+        ResetScope::TimeRange { from, to } => json!({ "from": from, "to": to }),
+    }
+}
+
+// Step one: validates the request and hands out a confirmation token
+pub async fn prepare(request: ResetRequest, source: &str, graph: &Graph) -> Result<Value, String> {
+    let nodes = count_in_scope(&request.scope, graph).await?;
+    let token = Uuid::new_v4().to_string();
+    let scope = describe(&request.scope);
+
+    let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+    pending.retain(|_, p| p.created.elapsed() < TOKEN_TTL);
+    pending.insert(token.clone(), PendingReset {
+        request: request.clone(),
+        source: source.to_string(),
+        created: Instant::now(),
+    });
+    drop(pending);
+
+    info!("Reset of {} nodes ({}) requested by {}, waiting for confirmation", nodes, scope, source);
+    Ok(json!({
+        "confirmation_required": true,
+        "token": token,
+        "expires_in_secs": TOKEN_TTL.as_secs(),
+        "scope": scope,
+        "nodes_in_scope": nodes,
+        "export": request.export,
+        "batch_size": request.batch_size
+    }))
+}
+
+// Step two: checks the token and runs the deletion that was prepared with it
+pub async fn confirm(token: &str, source: &str, graph: &Graph) -> Result<Value, String> {
+    let pending = {
+        let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+        pending.retain(|_, p| p.created.elapsed() < TOKEN_TTL);
+        match pending.remove(token) {
+            Some(p) if p.source == source => p,
+            Some(p) => {
+                // Leave the token usable for the connection that asked for it
+                let owner = p.source.clone();
+                pending.insert(token.to_string(), p);
+                return Err(format!("Confirmation token belongs to another connection ({})", owner));
+            },
+            None => return Err("Unknown or expired confirmation token".to_string()),
+        }
+    };
+
+    let request = pending.request;
+
+    let export_path = if request.export {
+        Some(export_scope(&request.scope, graph).await?)
+    } else {
+        None
+    };
+
+    let deleted = delete_scope(&request.scope, request.batch_size, graph).await?;
+    if matches!(request.scope, ResetScope::All) {
+        set_topology(graph).await?;
+    }
+
+    info!("Reset by {} deleted {} nodes ({})", source, deleted, describe(&request.scope));
+    Ok(json!({
+        "reset": true,
+        "scope": describe(&request.scope),
+        "deleted_nodes": deleted,
+        "export_file": export_path.map(|p| p.display().to_string())
+    }))
+}
+
+// Deletes in batches of `batch_size` nodes, each in its own transaction, so large graphs
+// don't have to fit into one transaction's memory
+async fn delete_scope(scope: &ResetScope, batch_size: i64, graph: &Graph) -> Result<i64, String> {
+    let mut deleted = 0;
+    loop {
+        let delete_query = scoped_query(scope, "WITH DISTINCT n LIMIT $batch DETACH DELETE n RETURN count(n) AS deleted")
+            .param("batch", batch_size);
+        let mut result = graph.execute(delete_query).await.map_err(|e| {
+            error!("Reset stopped after {} deleted nodes: {}", deleted, e);
+            format!("Reset stopped after {} deleted nodes: {}", deleted, e)
+        })?;
+        let batch = match result.next().await {
+            Ok(Some(row)) => row.get::<i64>("deleted").unwrap_or(0),
+            Ok(None) => 0,
+            Err(e) => return Err(format!("Reset stopped after {} deleted nodes: {}", deleted, e)),
+        };
+        if batch == 0 {
+            break;
+        }
+        deleted += batch;
+        info!("Reset: {} nodes deleted so far", deleted);
+    }
+
+    if matches!(scope, ResetScope::TimeRange { .. }) {
+        deleted += delete_unlinked_values(batch_size, graph).await?;
+    }
+    Ok(deleted)
+}
+
+// Value nodes are shared by readings, so after a time range reset only those that lost
+// their last link go
+async fn delete_unlinked_values(batch_size: i64, graph: &Graph) -> Result<i64, String> {
+    let mut deleted = 0;
+    loop {
+        let orphan_query = query(r#"
+            MATCH (v)
+            WHERE (v:Temperature OR v:Humidity OR v:Color OR v:EnergyCost OR v:EnergyConsume) AND NOT (v)--()
+            WITH v LIMIT $batch
+            DELETE v
+            RETURN count(v) AS deleted
+        "#)
+        .param("batch", batch_size);
+        let mut result = graph.execute(orphan_query).await
+            .map_err(|e| format!("Failed to delete unlinked values: {}", e))?;
+        let batch = match result.next().await {
+            Ok(Some(row)) => row.get::<i64>("deleted").unwrap_or(0),
+            Ok(None) => 0,
+            Err(e) => return Err(format!("Failed to delete unlinked values: {}", e)),
+        };
+        if batch == 0 {
+            return Ok(deleted);
+        }
+        deleted += batch;
+    }
+}
+
+// Backs up the nodes in scope to the export directory before they are deleted
+async fn export_scope(scope: &ResetScope, graph: &Graph) -> Result<PathBuf, String> {
+    let dir = config::get().export.dir.clone();
+    fs::create_dir_all(&dir).await
+        .map_err(|e| format!("Failed to create export directory {}: {}", dir.display(), e))?;
+    let path = dir.join(format!("reset-{}.jsonl", chrono::Utc::now().format("%Y%m%dT%H%M%SZ")));
+    let exported = export_nodes(scope, &path, graph).await?;
+    info!("Exported {} nodes to {} before reset", exported, path.display());
+    Ok(path)
+}
+
+// Writes every node in scope to `path` as one JSON line with its labels and properties
+async fn export_nodes(scope: &ResetScope, path: &Path, graph: &Graph) -> Result<usize, String> {
+    let mut file = File::create(path).await
+        .map_err(|e| format!("Failed to create export file {}: {}", path.display(), e))?;
+    let mut result = graph.execute(scoped_query(scope, "WITH DISTINCT n RETURN labels(n) AS labels, properties(n) AS properties")).await
+        .map_err(|e| format!("Failed to export nodes: {}", e))?;
+
+    let mut exported = 0;
+    loop {
+        let row = match result.next().await {
+            Ok(Some(row)) => row,
+            Ok(None) => break,
+            Err(e) => return Err(format!("Failed to export nodes: {}", e)),
+        };
+        let labels: Vec<String> = row.get("labels").unwrap_or_default();
+        let properties: Value = row.get("properties").unwrap_or(json!({}));
+        let mut line = json!({ "labels": labels, "properties": properties }).to_string();
+        line.push('\n');
+        file.write_all(line.as_bytes()).await
+            .map_err(|e| format!("Failed to write export file {}: {}", path.display(), e))?;
+        exported += 1;
+    }
+    file.sync_all().await
+        .map_err(|e| format!("Failed to sync export file {}: {}", path.display(), e))?;
+    Ok(exported)
+}
+
+async fn set_topology(graph: &Graph) -> Result<(), String> {
+    let topology_query = query(r#"
+        ALTER DATABASE neo4j SET TOPOLOGY 1 PRIMARIES 2 SECONDARIES
+    "#);
+
+    match graph.execute(topology_query).await {
+        Ok(_) => {
+            info!("Topology set to 1 primary and 2 secondaries");
+            Ok(())
+        },
+        Err(e) => {
+            let error_msg = format!("Failed to set topology: {}", e);
+            error!("{}", error_msg);
+            Err(error_msg)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_range_scope_matches_only_timestamps() {
+        let request = ResetRequest::from_json(&json!({ "scope": { "from": "2024-01-01", "to": "2024-02-01" } })).unwrap();
+        assert!(matches!(&request.scope, ResetScope::TimeRange { from, to } if from == "2024-01-01" && to == "2024-02-01"));
+        let clause = scope_match(&request.scope);
+        assert_eq!(clause, "MATCH (n:Timestamp) WHERE n.value >= $from AND n.value <= $to");
+        assert!(!clause.contains("UUID"));
+    }
+
+    #[test]
+    fn scope_must_be_exactly_one_kind() {
+        assert!(ResetRequest::from_json(&json!({})).is_err());
+        assert!(ResetRequest::from_json(&json!({ "scope": { "all": true, "from": "a", "to": "b" } })).is_err());
+        assert!(ResetRequest::from_json(&json!({ "scope": { "from": "a" } })).is_err());
+        assert!(ResetRequest::from_json(&json!({ "scope": { "labels": [] } })).is_err());
+        let request = ResetRequest::from_json(&json!({ "scope": { "labels": ["Color"] }, "batch_size": 0 })).unwrap();
+        assert_eq!(request.batch_size, DEFAULT_BATCH_SIZE);
+    }
+}
@@ -0,0 +1,96 @@
+use log::{info, error};
+use neo4rs::{Graph, query};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+
+// Constraints and indexes the queries in query.rs, device.rs and quarantine.rs rely on.
+// Every statement is idempotent, so this runs on each startup.
+pub struct SchemaItem {
+    pub name: &'static str,
+    pub kind: &'static str,
+    pub statement: &'static str,
+}
+
+pub const SCHEMA: &[SchemaItem] = &[
+    // Value nodes are MERGEd on their value, so there is exactly one node per value
+    SchemaItem { name: "uuid_id_unique", kind: "constraint",
+        statement: "CREATE CONSTRAINT uuid_id_unique IF NOT EXISTS FOR (n:UUID) REQUIRE n.id IS UNIQUE" },
+    SchemaItem { name: "color_value_unique", kind: "constraint",
+        statement: "CREATE CONSTRAINT color_value_unique IF NOT EXISTS FOR (n:Color) REQUIRE n.value IS UNIQUE" },
+    SchemaItem { name: "timestamp_value_unique", kind: "constraint",
+        statement: "CREATE CONSTRAINT timestamp_value_unique IF NOT EXISTS FOR (n:Timestamp) REQUIRE n.value IS UNIQUE" },
+    SchemaItem { name: "temperature_value_unique", kind: "constraint",
+        statement: "CREATE CONSTRAINT temperature_value_unique IF NOT EXISTS FOR (n:Temperature) REQUIRE n.value IS UNIQUE" },
+    SchemaItem { name: "humidity_value_unique", kind: "constraint",
+        statement: "CREATE CONSTRAINT humidity_value_unique IF NOT EXISTS FOR (n:Humidity) REQUIRE n.value IS UNIQUE" },
+    SchemaItem { name: "energy_cost_value_unique", kind: "constraint",
+        statement: "CREATE CONSTRAINT energy_cost_value_unique IF NOT EXISTS FOR (n:EnergyCost) REQUIRE n.value IS UNIQUE" },
+    SchemaItem { name: "energy_consume_value_unique", kind: "constraint",
+        statement: "CREATE CONSTRAINT energy_consume_value_unique IF NOT EXISTS FOR (n:EnergyConsume) REQUIRE n.value IS UNIQUE" },
+    SchemaItem { name: "device_id_unique", kind: "constraint",
+        statement: "CREATE CONSTRAINT device_id_unique IF NOT EXISTS FOR (n:Device) REQUIRE n.id IS UNIQUE" },
+    SchemaItem { name: "rejected_record_id_unique", kind: "constraint",
+        statement: "CREATE CONSTRAINT rejected_record_id_unique IF NOT EXISTS FOR (n:RejectedRecord) REQUIRE n.id IS UNIQUE" },
+    SchemaItem { name: "schema_version_id_unique", kind: "constraint",
+        statement: "CREATE CONSTRAINT schema_version_id_unique IF NOT EXISTS FOR (n:SchemaVersion) REQUIRE n.id IS UNIQUE" },
+    SchemaItem { name: "device_status_index", kind: "index",
+        statement: "CREATE RANGE INDEX device_status_index IF NOT EXISTS FOR (n:Device) ON (n.status)" },
+    SchemaItem { name: "rejected_record_received_at_index", kind: "index",
+        statement: "CREATE RANGE INDEX rejected_record_received_at_index IF NOT EXISTS FOR (n:RejectedRecord) ON (n.received_at)" },
+];
+
+// Creates all missing constraints and indexes. Stops at the first failure, e.g. when
+// existing duplicate values prevent a uniqueness constraint.
+pub async fn ensure_schema(graph: &Graph) -> Result<(), String> {
+    for item in SCHEMA {
+        graph.run(query(item.statement)).await.map_err(|e| {
+            error!("Failed to create {} {}: {}", item.kind, item.name, e);
+            format!("Failed to create {} {}: {}", item.kind, item.name, e)
+        })?;
+    }
+    info!("Schema bootstrap done: {} constraints and indexes in place", SCHEMA.len());
+    Ok(())
+}
+
+// Reports every expected constraint and index with whether it exists and its population
+// state. Uniqueness constraints take the state of the index that backs them.
+pub async fn schema_status(graph: &Graph) -> Result<Value, String> {
+    let mut present = HashMap::<String, String>::new();
+    let mut owned = HashMap::<String, String>::new();
+
+    let mut indexes = graph.execute(query("SHOW INDEXES YIELD name, state, owningConstraint RETURN name, state, owningConstraint")).await
+        .map_err(|e| format!("Failed to read indexes: {}", e))?;
+    while let Ok(Some(row)) = indexes.next().await {
+        let state: String = row.get("state").unwrap_or_default();
+        if let Ok(constraint) = row.get::<String>("owningConstraint") {
+            owned.insert(constraint, state.clone());
+        }
+        if let Ok(name) = row.get::<String>("name") {
+            present.insert(name, state);
+        }
+    }
+
+    let mut constraints = graph.execute(query("SHOW CONSTRAINTS YIELD name RETURN name")).await
+        .map_err(|e| format!("Failed to read constraints: {}", e))?;
+    while let Ok(Some(row)) = constraints.next().await {
+        if let Ok(name) = row.get::<String>("name") {
+            // A constraint without a backing index is not enforced
+            let state = owned.get(&name).cloned().unwrap_or_else(|| "MISSING INDEX".to_string());
+            present.insert(name, state);
+        }
+    }
+
+    let items: Vec<Value> = SCHEMA.iter().map(|item| json!({
+        "name": item.name,
+        "kind": item.kind,
+        "present": present.contains_key(item.name),
+        "state": present.get(item.name)
+    })).collect();
+    let missing = items.iter().filter(|i| i["present"] == false).count();
+
+    Ok(json!({
+        "expected": SCHEMA.len(),
+        "missing": missing,
+        "items": items
+    }))
+}
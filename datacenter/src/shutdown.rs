@@ -0,0 +1,62 @@
+use log::info;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use crate::config;
+
+// Process-wide shutdown signal. Triggered by the `exit` command, SIGTERM or SIGINT; every
+// long-running task watches the token and winds down on its own.
+static TOKEN: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+
+// In-flight client connections, so shutdown can wait for them to drain
+static TRACKER: LazyLock<TaskTracker> = LazyLock::new(TaskTracker::new);
+
+pub fn token() -> &'static CancellationToken {
+    &TOKEN
+}
+
+pub fn tracker() -> &'static TaskTracker {
+    &TRACKER
+}
+
+pub fn trigger(reason: &str) {
+    if !TOKEN.is_cancelled() {
+        info!("Shutdown requested: {}", reason);
+        TOKEN.cancel();
+    }
+}
+
+// How long connections, the WAL flush and the MQTT goodbye may take before we give up
+pub fn deadline() -> Duration {
+    Duration::from_secs(config::get().shutdown.timeout_secs)
+}
+
+// Turns SIGTERM (sent by `docker stop`) and SIGINT into a shutdown request
+pub fn listen_for_signals() {
+    tokio::spawn(async {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut sigterm = match signal(SignalKind::terminate()) {
+                Ok(sigterm) => sigterm,
+                Err(e) => {
+                    log::error!("Failed to install SIGTERM handler: {}", e);
+                    let _ = tokio::signal::ctrl_c().await;
+                    trigger("SIGINT");
+                    return;
+                }
+            };
+            tokio::select! {
+                _ = sigterm.recv() => trigger("SIGTERM"),
+                _ = tokio::signal::ctrl_c() => trigger("SIGINT"),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+            trigger("SIGINT");
+        }
+    });
+}
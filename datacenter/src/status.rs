@@ -0,0 +1,105 @@
+use serde_json::{Value, json};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
+
+use crate::db;
+use crate::live;
+use crate::wal;
+
+// Runtime state collected for the `status` command. Counters are updated from the
+// transports and the ingest path, the report is assembled on demand.
+static STARTED: LazyLock<(Instant, chrono::DateTime<chrono::Utc>)> = LazyLock::new(|| (Instant::now(), chrono::Utc::now()));
+static MQTT: Mutex<MqttState> = Mutex::new(MqttState { state: "starting", client_id: None });
+static LAST_ERROR: Mutex<Option<(String, String)>> = Mutex::new(None);
+
+pub static TCP_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+pub static BATCHES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+pub static RECORDS_RECEIVED: AtomicU64 = AtomicU64::new(0);
+pub static RECORDS_WRITTEN: AtomicU64 = AtomicU64::new(0);
+pub static RECORDS_QUARANTINED: AtomicU64 = AtomicU64::new(0);
+pub static RECORDS_REJECTED: AtomicU64 = AtomicU64::new(0);
+pub static DUPLICATES: AtomicU64 = AtomicU64::new(0);
+
+struct MqttState {
+    state: &'static str,
+    client_id: Option<String>,
+}
+
+pub fn mark_started() {
+    LazyLock::force(&STARTED);
+}
+
+pub fn add(counter: &AtomicU64, n: usize) {
+    counter.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+pub fn set_mqtt_state(state: &'static str, client_id: Option<&str>) {
+    let mut mqtt = MQTT.lock().unwrap_or_else(|e| e.into_inner());
+    mqtt.state = state;
+    if let Some(id) = client_id {
+        mqtt.client_id = Some(id.to_string());
+    }
+}
+
+pub fn record_error(message: &str) {
+    let mut last = LAST_ERROR.lock().unwrap_or_else(|e| e.into_inner());
+    *last = Some((chrono::Utc::now().to_rfc3339(), message.to_string()));
+}
+
+// Counts an open TCP connection for as long as the guard lives
+pub struct TcpClientGuard;
+
+impl TcpClientGuard {
+    pub fn new() -> Self {
+        TCP_CLIENTS.fetch_add(1, Ordering::Relaxed);
+        TcpClientGuard
+    }
+}
+
+impl Drop for TcpClientGuard {
+    fn drop(&mut self) {
+        TCP_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub async fn report() -> Value {
+    let (started, started_at) = *STARTED;
+    let mqtt = {
+        let mqtt = MQTT.lock().unwrap_or_else(|e| e.into_inner());
+        json!({ "state": mqtt.state, "client_id": mqtt.client_id })
+    };
+    let last_error = LAST_ERROR.lock().unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map(|(at, message)| json!({ "at": at, "message": message }));
+    let wal = match wal::WAL.get() {
+        Some(wal) => wal.backlog(),
+        None => Value::Null,
+    };
+
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "started_at": started_at.to_rfc3339(),
+        "uptime_secs": started.elapsed().as_secs(),
+        "tcp": {
+            "connected_clients": TCP_CLIENTS.load(Ordering::Relaxed)
+        },
+        "mqtt": mqtt,
+        "live": {
+            "subscribers": live::subscribers()
+        },
+        "neo4j": db::node_health().await,
+        "ingest": {
+            "batches_received": BATCHES_RECEIVED.load(Ordering::Relaxed),
+            "records_received": RECORDS_RECEIVED.load(Ordering::Relaxed),
+            "records_written": RECORDS_WRITTEN.load(Ordering::Relaxed),
+            "records_quarantined": RECORDS_QUARANTINED.load(Ordering::Relaxed),
+            "records_rejected_unknown_device": RECORDS_REJECTED.load(Ordering::Relaxed),
+            "duplicates": DUPLICATES.load(Ordering::Relaxed)
+        },
+        "queues": {
+            "wal": wal
+        },
+        "last_error": last_error
+    })
+}
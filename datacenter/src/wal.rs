@@ -0,0 +1,322 @@
+use log::{info, error, warn};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, Notify, OnceCell};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+
+use crate::config;
+use crate::db::{get_db, reachable};
+use crate::dedupe;
+use crate::ingest::ingest_records;
+use crate::migrations;
+use crate::shutdown;
+use crate::status;
+
+// Write-ahead log for ingested batches. Every batch is appended and fsynced before the
+// client gets its ack; a background task flushes the log to Neo4j in order and removes
+// entries once they are written. Pending entries survive restarts.
+//
+// A batch that keeps failing while Neo4j is up would block every batch behind it, so after
+// wal.max_attempts such failures it moves to a dead-letter file next to the log.
+pub static WAL: OnceCell<Wal> = OnceCell::const_new();
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+pub struct Wal {
+    path: PathBuf,
+    dead_letter_path: PathBuf,
+    // Guards appends and compaction of the log file
+    lock: Mutex<()>,
+    next_seq: AtomicU64,
+    pending_batches: AtomicUsize,
+    pending_records: AtomicUsize,
+    dead_letters: AtomicUsize,
+    // Failed flush attempts per sequence number while Neo4j was reachable
+    attempts: Mutex<HashMap<u64, u32>>,
+    notify: Notify,
+}
+
+fn entry_records(entry: &Value) -> usize {
+    entry.get("batch").and_then(Value::as_array).map_or(0, Vec::len)
+}
+
+// Entries written before the keys were stored only have their records' keys
+fn dedupe_keys(entry: &Value) -> Vec<String> {
+    match entry.get("dedupe_keys").and_then(Value::as_array) {
+        Some(keys) => keys.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+        None => entry.get("batch").and_then(Value::as_array)
+            .map(|records| records.iter().map(dedupe::record_key).collect())
+            .unwrap_or_default(),
+    }
+}
+
+async fn read_entries(path: &Path) -> Result<Vec<Value>, String> {
+    match fs::read_to_string(path).await {
+        Ok(content) => {
+            let mut entries = Vec::new();
+            for line in content.lines().filter(|l| !l.trim().is_empty()) {
+                match serde_json::from_str::<Value>(line) {
+                    Ok(entry) => entries.push(entry),
+                    // A torn write at the end of the file after a crash; the client never got an ack
+                    Err(e) => warn!("Skipping unreadable WAL entry in {}: {}", path.display(), e),
+                }
+            }
+            Ok(entries)
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("Failed to read WAL {}: {}", path.display(), e)),
+    }
+}
+
+impl Wal {
+    async fn open(path: PathBuf) -> Result<Wal, String> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).await
+                .map_err(|e| format!("Failed to create WAL directory {}: {}", dir.display(), e))?;
+        }
+        let entries = read_entries(&path).await?;
+        let next_seq = entries.iter()
+            .filter_map(|e| e.get("seq").and_then(Value::as_u64))
+            .max()
+            .map_or(1, |seq| seq + 1);
+        let records = entries.iter().map(entry_records).sum();
+
+        if !entries.is_empty() {
+            info!("WAL {} has {} pending batches ({} records) from a previous run", path.display(), entries.len(), records);
+        }
+        Ok(Wal {
+            dead_letter_path: path.with_file_name("dead_letter.wal"),
+            path,
+            lock: Mutex::new(()),
+            next_seq: AtomicU64::new(next_seq),
+            pending_batches: AtomicUsize::new(entries.len()),
+            pending_records: AtomicUsize::new(records),
+            dead_letters: AtomicUsize::new(0),
+            attempts: Mutex::new(HashMap::new()),
+            notify: Notify::new(),
+        })
+    }
+
+    // Appends a batch and returns its sequence number once it is durable on disk.
+    // `dedupe_keys` are the keys the batch was marked with, released if it is dead-lettered.
+    pub async fn append(&self, records: &[Value], source: &str, dedupe_keys: &[String]) -> Result<u64, String> {
+        let _guard = self.lock.lock().await;
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        let entry = json!({ "seq": seq, "source": source, "batch": records, "dedupe_keys": dedupe_keys });
+        let mut line = serde_json::to_vec(&entry).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path).await
+            .map_err(|e| format!("Failed to open WAL {}: {}", self.path.display(), e))?;
+        file.write_all(&line).await
+            .map_err(|e| format!("Failed to append to WAL {}: {}", self.path.display(), e))?;
+        file.sync_data().await
+            .map_err(|e| format!("Failed to sync WAL {}: {}", self.path.display(), e))?;
+
+        self.pending_batches.fetch_add(1, Ordering::SeqCst);
+        self.pending_records.fetch_add(records.len(), Ordering::SeqCst);
+        self.notify.notify_one();
+        Ok(seq)
+    }
+
+    pub fn backlog(&self) -> Value {
+        json!({
+            "pending_batches": self.pending_batches.load(Ordering::SeqCst),
+            "pending_records": self.pending_records.load(Ordering::SeqCst),
+            "dead_lettered_batches": self.dead_letters.load(Ordering::SeqCst),
+            "path": self.path.display().to_string(),
+            "dead_letter_path": self.dead_letter_path.display().to_string()
+        })
+    }
+
+    // Drops flushed entries from the log file, keeping anything appended meanwhile
+    async fn remove_flushed(&self, flushed_up_to: u64) -> Result<(), String> {
+        let _guard = self.lock.lock().await;
+        let remaining: Vec<Value> = read_entries(&self.path).await?
+            .into_iter()
+            .filter(|e| e.get("seq").and_then(Value::as_u64).is_some_and(|seq| seq > flushed_up_to))
+            .collect();
+
+        let mut content = String::new();
+        for entry in &remaining {
+            content.push_str(&entry.to_string());
+            content.push('\n');
+        }
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path).await
+            .map_err(|e| format!("Failed to create {}: {}", tmp_path.display(), e))?;
+        file.write_all(content.as_bytes()).await
+            .map_err(|e| format!("Failed to write {}: {}", tmp_path.display(), e))?;
+        file.sync_data().await
+            .map_err(|e| format!("Failed to sync {}: {}", tmp_path.display(), e))?;
+        fs::rename(&tmp_path, &self.path).await
+            .map_err(|e| format!("Failed to replace WAL {}: {}", self.path.display(), e))?;
+
+        self.pending_batches.store(remaining.len(), Ordering::SeqCst);
+        self.pending_records.store(remaining.iter().map(entry_records).sum(), Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Appends an entry that will never be flushed to the dead-letter file, with the error
+    async fn dead_letter(&self, entry: &Value, attempts: u32, error: &str) -> Result<(), String> {
+        let record = json!({
+            "entry": entry,
+            "attempts": attempts,
+            "error": error,
+            "dead_lettered_at": chrono::Utc::now().to_rfc3339()
+        });
+        let mut line = serde_json::to_vec(&record).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.dead_letter_path).await
+            .map_err(|e| format!("Failed to open dead-letter file {}: {}", self.dead_letter_path.display(), e))?;
+        file.write_all(&line).await
+            .and(file.sync_data().await)
+            .map_err(|e| format!("Failed to write dead-letter file {}: {}", self.dead_letter_path.display(), e))?;
+        self.dead_letters.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Counts a failed attempt at `seq` and returns the attempts so far. Failures while
+    // Neo4j is down are not the batch's fault and do not count.
+    async fn record_failure(&self, seq: u64, graph: &neo4rs::Graph) -> Option<u32> {
+        if !reachable(graph).await {
+            return None;
+        }
+        let mut attempts = self.attempts.lock().await;
+        let count = attempts.entry(seq).or_insert(0);
+        *count += 1;
+        Some(*count)
+    }
+
+    // Writes all pending entries to Neo4j in order. Stops at the first failure so later
+    // batches never overtake earlier ones, unless the failing batch used up its attempts.
+    pub async fn flush(&self) -> Result<usize, String> {
+        let entries = {
+            let _guard = self.lock.lock().await;
+            read_entries(&self.path).await?
+        };
+        if entries.is_empty() {
+            return Ok(0);
+        }
+
+        let db = get_db().await.map_err(|e| format!("Database unavailable: {}", e))?;
+        let max_attempts = config::get().wal.max_attempts;
+        let mut flushed_up_to = None;
+        let mut flushed = 0;
+        let mut written = 0;
+        let mut failure = None;
+
+        for entry in &entries {
+            let seq = entry.get("seq").and_then(Value::as_u64).unwrap_or_default();
+            let source = entry.get("source").and_then(Value::as_str).unwrap_or("wal");
+            let records = entry.get("batch").and_then(Value::as_array).cloned().unwrap_or_default();
+
+            match ingest_records(records, source, Some(&format!("wal-{}", seq)), db).await {
+                Ok(report) => {
+                    self.attempts.lock().await.remove(&seq);
+                    flushed_up_to = Some(seq);
+                    flushed += 1;
+                    written += report.written;
+                },
+                Err(e) => match self.record_failure(seq, db).await {
+                    Some(attempts) if attempts >= max_attempts => {
+                        error!("WAL batch {} failed {} times, moving it to {}: {}", seq, attempts, self.dead_letter_path.display(), e);
+                        self.dead_letter(entry, attempts, &e).await?;
+                        self.attempts.lock().await.remove(&seq);
+                        // A retry from the gateway has to be taken again, not acked as a duplicate
+                        dedupe::forget(&dedupe_keys(entry));
+                        flushed_up_to = Some(seq);
+                    },
+                    attempts => {
+                        let attempts = attempts.map(|n| format!(" (attempt {} of {})", n, max_attempts)).unwrap_or_default();
+                        failure = Some(format!("Failed to flush WAL batch {}{}: {}", seq, attempts, e));
+                        break;
+                    },
+                },
+            }
+        }
+
+        if let Some(seq) = flushed_up_to {
+            self.remove_flushed(seq).await?;
+            info!("Flushed {} WAL batches, {} records written to Neo4j", flushed, written);
+        }
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(flushed),
+        }
+    }
+}
+
+pub async fn init() -> Result<&'static Wal, String> {
+    WAL.get_or_try_init(|| async {
+        Wal::open(config::get().wal.dir.join("ingest.wal")).await
+    }).await
+}
+
+// Background task: flushes whenever new data arrives, retrying with exponential backoff
+// while Neo4j is unavailable. On shutdown it makes one last flush attempt and returns.
+pub fn spawn_flusher(wal: &'static Wal) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let token = shutdown::token();
+        // Nothing is written before the schema is known to be compatible
+        if !migrations::is_compatible() {
+            tokio::select! {
+                _ = migrations::compatible() => {},
+                _ = token.cancelled() => {
+                    warn!("Schema never checked, {} stays on disk for the next start", wal.backlog());
+                    return;
+                },
+            }
+        }
+        let mut delay = Duration::from_secs(1);
+        loop {
+            match wal.flush().await {
+                Ok(_) if token.is_cancelled() => break,
+                Ok(0) => {
+                    delay = Duration::from_secs(1);
+                    tokio::select! {
+                        _ = wal.notify.notified() => {},
+                        _ = token.cancelled() => {},
+                    }
+                },
+                Ok(_) => {
+                    delay = Duration::from_secs(1);
+                },
+                Err(e) if token.is_cancelled() => {
+                    warn!("Final WAL flush failed, {} stays on disk for the next start: {}", wal.backlog(), e);
+                    break;
+                },
+                Err(e) => {
+                    error!("{}; retrying in {:?} ({})", e, delay, wal.backlog());
+                    status::record_error(&e);
+                    tokio::select! {
+                        _ = sleep(delay) => {},
+                        _ = token.cancelled() => {},
+                    }
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                },
+            }
+        }
+        info!("WAL flusher stopped");
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dead_lettered_entries_release_their_dedupe_keys() {
+        let entry = json!({ "seq": 1, "batch": [{ "uuid": "a" }], "dedupe_keys": ["batch:m1", "record:r1"] });
+        assert_eq!(dedupe_keys(&entry), vec!["batch:m1", "record:r1"]);
+
+        let record = json!({ "uuid": "a", "message_id": "r2" });
+        let older = json!({ "seq": 2, "batch": [record] });
+        assert_eq!(dedupe_keys(&older), vec!["record:r2"]);
+    }
+}